@@ -0,0 +1,39 @@
+//! Puts `memory.x` on the linker search path and links with the
+//! cortex-m-rt link script, as the firmware's build script does.
+//!
+//! It also writes `usb.rs` with the USB ids of the board, see
+//! `src/board.rs`: the keyboard's vendor id and the product id after its
+//! own, so the host tells the two apart.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[path = "../build/source.rs"]
+mod source;
+
+fn main() {
+    let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    File::create(out.join("memory.x"))
+        .unwrap()
+        .write_all(include_bytes!("memory.x"))
+        .unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rustc-link-arg=--nmagic");
+    println!("cargo:rustc-link-arg=-Tlink.x");
+
+    let board = source::board();
+    let usb = source::usb_ids(Path::new(".."), &board);
+    println!("cargo:rerun-if-changed=../build/source.rs");
+    println!("cargo:rerun-if-changed=../src/board/{board}.json");
+    let mut ids = format!("// generated by build.rs from src/board/{board}.json\n");
+    ids += &format!("const VENDOR_ID: u16 = {:#06x};\n", usb.vendor_id);
+    ids += &format!("const PRODUCT_ID: u16 = {:#06x};\n", usb.product_id + 1);
+    ids += &format!("const MANUFACTURER: &str = {:?};\n", usb.manufacturer);
+    let product = format!("{} Bootloader", usb.product);
+    ids += &format!("const PRODUCT: &str = {product:?};\n");
+    ids += &format!("const SERIAL_NUMBER: &str = {:?};\n", usb.serial_number);
+    fs::write(out.join("usb.rs"), ids).unwrap();
+}
@@ -0,0 +1,8 @@
+MEMORY
+{
+  /* NOTE 1 K = 1 KiBi = 1024 bytes */
+  /* sector 0 only, the firmware is linked with memory-custom-bootloader.x */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 16K
+  /* the last word is `image::REQUEST_ADDRESS` */
+  RAM : ORIGIN = 0x20000000, LENGTH = 64K - 4
+}
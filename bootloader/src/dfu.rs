@@ -0,0 +1,236 @@
+//! USB DFU 1.1 in DFU mode, download only. The image is collected in RAM
+//! and only handed out for programming once it passed its check, so a
+//! broken or interrupted download leaves the flash untouched.
+
+use tpkb50_protocol::image::{self, Header};
+use usb_device::{
+    class_prelude::*,
+    control::{Recipient, RequestType},
+};
+
+const CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+const SUBCLASS_DFU: u8 = 0x01;
+const PROTOCOL_DFU_MODE: u8 = 0x02;
+const DESC_DFU_FUNCTIONAL: u8 = 0x21;
+
+const DFU_DETACH: u8 = 0x00;
+const DFU_DNLOAD: u8 = 0x01;
+const DFU_GETSTATUS: u8 = 0x03;
+const DFU_CLRSTATUS: u8 = 0x04;
+const DFU_GETSTATE: u8 = 0x05;
+const DFU_ABORT: u8 = 0x06;
+
+/// bitCanDnload and bitManifestationTolerant.
+const ATTRIBUTES: u8 = 0x05;
+/// Time asked of the host for erasing and programming, ms.
+const MANIFEST_TIMEOUT: u32 = 4000;
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle = 2,
+    DnloadSync = 3,
+    DnloadIdle = 5,
+    ManifestSync = 6,
+    Manifest = 7,
+    Error = 10,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Status {
+    Ok = 0x00,
+    /// Not an image for this keyboard or its check failed.
+    File = 0x02,
+    /// Erasing or programming failed, or the flash reads back wrong.
+    Verify = 0x07,
+    /// Longer than the RAM buffer.
+    Address = 0x08,
+    StalledPacket = 0x0F,
+}
+
+pub struct Dfu {
+    interface: InterfaceNumber,
+    buffer: &'static mut [u8],
+    len: usize,
+    state: State,
+    status: Status,
+    /// Checked image waiting for `Dfu::image`.
+    manifest: Option<Header>,
+    /// New firmware was programmed, a reset or detach starts it.
+    done: bool,
+    reboot: bool,
+}
+
+impl Dfu {
+    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>, buffer: &'static mut [u8]) -> Dfu {
+        Dfu {
+            interface: alloc.interface(),
+            buffer,
+            len: 0,
+            state: State::Idle,
+            status: Status::Ok,
+            manifest: None,
+            done: false,
+            reboot: false,
+        }
+    }
+
+    /// A downloaded image that passed its check, to be programmed and
+    /// reported with `Dfu::programmed`.
+    pub fn image(&self) -> Option<(Header, &[u8])> {
+        let header = self.manifest?;
+        Some((header, &self.buffer[image::HEADER_LEN..self.len]))
+    }
+
+    pub fn programmed(&mut self, ok: bool) {
+        self.manifest = None;
+        if ok {
+            self.done = true;
+            self.state = State::ManifestSync;
+        } else {
+            self.fail(Status::Verify);
+        }
+    }
+
+    /// Whether the new firmware should be started now.
+    pub fn reboot_requested(&self) -> bool {
+        self.reboot
+    }
+
+    fn fail(&mut self, status: Status) {
+        self.state = State::Error;
+        self.status = status;
+    }
+
+    fn is_ours(&self, request: &control::Request) -> bool {
+        request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index == u8::from(self.interface) as u16
+    }
+
+    fn download(&mut self, data: &[u8]) -> bool {
+        match (self.state, data.is_empty()) {
+            (State::Idle | State::DnloadIdle, false) => {
+                if self.state == State::Idle {
+                    self.len = 0;
+                }
+                let end = self.len + data.len();
+                match self.buffer.get_mut(self.len..end) {
+                    Some(chunk) => {
+                        chunk.copy_from_slice(data);
+                        self.len = end;
+                        self.state = State::DnloadSync;
+                    }
+                    None => self.fail(Status::Address),
+                }
+                true
+            }
+            // the empty block ends the download
+            (State::DnloadIdle, true) => {
+                self.state = State::ManifestSync;
+                true
+            }
+            _ => {
+                self.fail(Status::StalledPacket);
+                false
+            }
+        }
+    }
+
+    /// Move on for a GETSTATUS, returning the poll timeout.
+    fn status(&mut self) -> u32 {
+        match self.state {
+            // the block is in RAM already
+            State::DnloadSync => self.state = State::DnloadIdle,
+            State::ManifestSync if self.done => self.state = State::Idle,
+            State::ManifestSync => match image::verify(&self.buffer[..self.len]) {
+                Some((header, _)) => {
+                    self.manifest = Some(header);
+                    self.state = State::Manifest;
+                    return MANIFEST_TIMEOUT;
+                }
+                None => self.fail(Status::File),
+            },
+            _ => {}
+        }
+        0
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for Dfu {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(
+            self.interface,
+            CLASS_APPLICATION_SPECIFIC,
+            SUBCLASS_DFU,
+            PROTOCOL_DFU_MODE,
+        )?;
+        let [size_low, size_high] = image::DFU_TRANSFER_SIZE.to_le_bytes();
+        let [version_low, version_high] = image::DFU_VERSION.to_le_bytes();
+        writer.write(
+            DESC_DFU_FUNCTIONAL,
+            &[
+                ATTRIBUTES,
+                0,
+                0,
+                size_low,
+                size_high,
+                version_low,
+                version_high,
+            ],
+        )
+    }
+
+    fn reset(&mut self) {
+        // `dfu-util -R` resets the bus once the download is done
+        if self.done {
+            self.reboot = true;
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        if !self.is_ours(xfer.request()) {
+            return;
+        }
+        match xfer.request().request {
+            DFU_GETSTATUS => {
+                let [timeout_0, timeout_1, timeout_2, _] = self.status().to_le_bytes();
+                let response = [
+                    self.status as u8,
+                    timeout_0,
+                    timeout_1,
+                    timeout_2,
+                    self.state as u8,
+                    0,
+                ];
+                xfer.accept_with(&response).ok()
+            }
+            DFU_GETSTATE => xfer.accept_with(&[self.state as u8]).ok(),
+            _ => xfer.reject().ok(),
+        };
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        if !self.is_ours(xfer.request()) {
+            return;
+        }
+        let accepted = match xfer.request().request {
+            DFU_DNLOAD => self.download(xfer.data()),
+            DFU_CLRSTATUS | DFU_ABORT => {
+                self.state = State::Idle;
+                self.status = Status::Ok;
+                self.len = 0;
+                true
+            }
+            DFU_DETACH => {
+                self.reboot = self.done;
+                true
+            }
+            _ => false,
+        };
+        if accepted {
+            xfer.accept().ok();
+        } else {
+            xfer.reject().ok();
+        }
+    }
+}
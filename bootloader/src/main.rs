@@ -0,0 +1,141 @@
+//! Bootloader for the `custom-bootloader` build of the firmware. It starts
+//! the firmware when its header matches and the firmware didn't ask to
+//! stay here, otherwise it takes a new image over USB DFU.
+
+#![deny(warnings)]
+#![no_main]
+#![no_std]
+
+mod dfu;
+
+use core::ptr;
+
+use cortex_m::{asm, peripheral::SCB};
+use cortex_m_rt::entry;
+use hal::{
+    flash::FlashExt,
+    gpio::alt::otg_fs::{Dm::PA11, Dp::PA12},
+    otg_fs::{UsbBus, UsbBusType, USB},
+    pac::{self, FLASH},
+    prelude::*,
+};
+use panic_halt as _;
+use stm32f4xx_hal as hal;
+use tpkb50_protocol::image::{
+    Header, APP_ADDRESS, APP_SECTORS, HEADER_ADDRESS, HEADER_LEN, MAX_APP_LEN, MAX_IMAGE_LEN,
+    REQUEST_ADDRESS, REQUEST_MAGIC,
+};
+use usb_device::{class_prelude::UsbBusAllocator, prelude::*};
+
+use crate::dfu::Dfu;
+
+include!(concat!(env!("OUT_DIR"), "/usb.rs"));
+
+const FLASH_START: u32 = 0x0800_0000;
+/// Most of the RAM, which limits the size of an image.
+const BUFFER_LEN: usize = MAX_IMAGE_LEN;
+
+const _: () = assert!(BUFFER_LEN >= HEADER_LEN + MAX_APP_LEN);
+
+static mut BUFFER: [u8; BUFFER_LEN] = [0; BUFFER_LEN];
+static mut EP_MEMORY: [u32; 320] = [0; 320];
+static mut USB_BUS: Option<UsbBusAllocator<UsbBusType>> = None;
+
+#[entry]
+fn main() -> ! {
+    let dp = pac::Peripherals::take().unwrap();
+
+    // SAFETY: the word is left out of RAM by `memory.x`.
+    let requested = unsafe {
+        let request = REQUEST_ADDRESS as *mut u32;
+        let requested = ptr::read_volatile(request) == REQUEST_MAGIC;
+        ptr::write_volatile(request, 0);
+        requested
+    };
+    if !requested && app(&dp.FLASH).is_some() {
+        // SAFETY: nothing is set up yet, the firmware starts as after a
+        // reset, only with its own vector table.
+        unsafe {
+            (*SCB::PTR).vtor.write(APP_ADDRESS);
+            asm::bootload(APP_ADDRESS as *const u32)
+        }
+    }
+
+    let rcc = dp.RCC.constrain();
+    let clocks = rcc
+        .cfgr
+        .use_hse(25.MHz())
+        .sysclk(48.MHz())
+        .require_pll48clk()
+        .freeze();
+    let gpioa = dp.GPIOA.split();
+    let usb = USB {
+        usb_global: dp.OTG_FS_GLOBAL,
+        usb_device: dp.OTG_FS_DEVICE,
+        usb_pwrclk: dp.OTG_FS_PWRCLK,
+        pin_dm: PA11(gpioa.pa11.into_alternate()),
+        pin_dp: PA12(gpioa.pa12.into_alternate()),
+        hclk: clocks.hclk(),
+    };
+
+    // SAFETY: `main` runs once and is the only user of the statics.
+    let (buffer, usb_bus) = unsafe {
+        let ep_memory = &mut *ptr::addr_of_mut!(EP_MEMORY);
+        let usb_bus = &mut *ptr::addr_of_mut!(USB_BUS);
+        (
+            &mut *ptr::addr_of_mut!(BUFFER),
+            usb_bus.insert(UsbBus::new(usb, ep_memory)),
+        )
+    };
+    let mut dfu = Dfu::new(usb_bus, buffer);
+    let mut device = UsbDeviceBuilder::new(usb_bus, UsbVidPid(VENDOR_ID, PRODUCT_ID))
+        .manufacturer(MANUFACTURER)
+        .product(PRODUCT)
+        .serial_number(SERIAL_NUMBER)
+        .max_packet_size_0(64)
+        .build();
+
+    let mut flash = dp.FLASH;
+    loop {
+        device.poll(&mut [&mut dfu]);
+        if let Some((header, firmware)) = dfu.image() {
+            let ok = program(&mut flash, header, firmware);
+            dfu.programmed(ok);
+        }
+        if dfu.reboot_requested() {
+            // let the status stage of the last request go out
+            asm::delay(clocks.sysclk().raw() / 100);
+            SCB::sys_reset();
+        }
+    }
+}
+
+fn offset(address: u32) -> usize {
+    (address - FLASH_START) as usize
+}
+
+/// Header of the firmware in flash, if the firmware matches it.
+fn app(flash: &FLASH) -> Option<Header> {
+    let memory = flash.read();
+    let header = Header::decode(&memory[offset(HEADER_ADDRESS)..])?;
+    let start = offset(APP_ADDRESS);
+    let firmware = &memory[start..start + header.len as usize];
+    header.matches(firmware).then_some(header)
+}
+
+/// Replace the firmware, the header last so an interrupted write is never
+/// started. Whether the flash holds it afterwards.
+fn program(flash: &mut FLASH, header: Header, firmware: &[u8]) -> bool {
+    let mut unlocked = flash.unlocked();
+    let written = APP_SECTORS
+        .iter()
+        .all(|&sector| unlocked.erase(sector).is_ok())
+        && unlocked
+            .program(offset(APP_ADDRESS), firmware.iter())
+            .is_ok()
+        && unlocked
+            .program(offset(HEADER_ADDRESS), header.encode().iter())
+            .is_ok();
+    drop(unlocked);
+    written && app(flash) == Some(header)
+}
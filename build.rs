@@ -9,19 +9,38 @@
 //! new memory settings.
 //!
 //! The build script also sets the linker flags to tell it which link script to use.
+//!
+//! Last, it picks the board, see `src/board.rs`, and turns its keymap into
+//! the `LAYERS` table of `layout.rs`. That is `keymap.json` for the `pcb/`
+//! board and `keymap-NAME.json` for one picked with a `board-NAME` feature.
+//! The matrix size and USB ids of the board's `NAME.json` go to `board.rs`,
+//! the key names of `src/keys.json` to `keys.rs` of `keycodes.rs`.
 
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use serde_json::Value;
+
+#[path = "build/source.rs"]
+mod source;
+
+use source::DEFAULT_BOARD;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let memory: &[u8] = if env::var_os("CARGO_FEATURE_CUSTOM_BOOTLOADER").is_some() {
+        include_bytes!("memory-custom-bootloader.x")
+    } else {
+        include_bytes!("memory.x")
+    };
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
@@ -30,6 +49,7 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-custom-bootloader.x");
 
     // Specify linker arguments.
 
@@ -40,4 +60,131 @@ fn main() {
 
     // Set the linker script to the one provided by cortex-m-rt.
     println!("cargo:rustc-link-arg=-Tlink.x");
+
+    let board = source::board();
+    for board_file in [
+        format!("src/board/{board}.rs"),
+        format!("src/board/{board}.json"),
+    ] {
+        if !Path::new(&board_file).exists() {
+            eprintln!("no {board_file} for the board-{board} feature, see src/board.rs");
+            process::exit(1);
+        }
+    }
+    let keymap = if board == DEFAULT_BOARD {
+        "keymap.json".to_string()
+    } else {
+        format!("keymap-{board}.json")
+    };
+    println!("cargo:rustc-env=TPKB50_BOARD={board}");
+    fs::write(out.join("board.rs"), board_consts(&board)).unwrap();
+    fs::write(out.join("keys.rs"), keycodes()).unwrap();
+    println!("cargo:rerun-if-changed={keymap}");
+    println!("cargo:rerun-if-changed=build/source.rs");
+    for source in source::sources(Path::new("")) {
+        println!("cargo:rerun-if-changed={}", source.display());
+    }
+    match layers(Path::new(&keymap), &board) {
+        Ok(layers) => fs::write(out.join("layers.rs"), layers).unwrap(),
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{keymap}: {error}");
+            }
+            process::exit(1);
+        }
+    }
+}
+
+/// `ROWS`, `COLUMNS` and `USB` of `board.rs` from the board's `NAME.json`.
+fn board_consts(board: &str) -> String {
+    let (rows, columns) = source::matrix_size(Path::new(""), board);
+    let usb = source::usb_ids(Path::new(""), board);
+    let mut ids = format!("// generated by build.rs from src/board/{board}.json\n");
+    ids += &format!("pub const ROWS: usize = {rows};\n");
+    ids += &format!("pub const COLUMNS: usize = {columns};\n");
+    ids += "pub const USB: UsbIds = UsbIds {\n";
+    ids += &format!("    vendor_id: {:#06x},\n", usb.vendor_id);
+    ids += &format!("    product_id: {:#06x},\n", usb.product_id);
+    ids += &format!("    manufacturer: {:?},\n", usb.manufacturer);
+    ids += &format!("    product: {:?},\n", usb.product);
+    ids += &format!("    serial_number: {:?},\n", usb.serial_number);
+    ids += "};\n";
+    ids
+}
+
+/// `KEYCODES` of `keycodes.rs` from `src/keys.json`.
+fn keycodes() -> String {
+    let mut table = String::from("// generated by build.rs from src/keys.json\n");
+    table += "pub const KEYCODES: &[(KeyCode, u8)] = &[\n";
+    for (name, code) in source::keycodes(Path::new("")) {
+        table += &format!("    (KeyCode::{name}, {code:#04x}),\n");
+    }
+    table += "];\n";
+    table
+}
+
+/// `LAYERS` from a keymap file, or what is wrong with it.
+///
+/// The file is laid out as a QMK `keymap.json`: `layers` holds a list of
+/// keys for each layer, row by row. A key is named as in `src/keys.json`,
+/// either a `KeyCode` or one of the `Action` consts of `layout.rs`, like
+/// `TRNS`. The table ends with a check that `layout.rs` has all of those.
+fn layers(path: &Path, board: &str) -> Result<String, Vec<String>> {
+    let json = fs::read_to_string(path).map_err(|e| vec![e.to_string()])?;
+    let keymap: Value = serde_json::from_str(&json).map_err(|e| vec![e.to_string()])?;
+    let layers = keymap["layers"]
+        .as_array()
+        .ok_or_else(|| vec!["no `layers` list".to_string()])?;
+    let (rows, columns) = source::matrix_size(Path::new(""), board);
+    let names = source::key_names(Path::new(""));
+
+    let mut errors = Vec::new();
+    if layers.is_empty() || layers.len() > 32 {
+        errors.push(format!("{} layers, 1 to 32 are possible", layers.len()));
+    }
+    let mut table = format!(
+        "// generated by build.rs from {}\npub const LAYERS: [Layout; {}] = [\n",
+        path.display(),
+        layers.len()
+    );
+    for (layer, keys) in layers.iter().enumerate() {
+        let keys = match keys.as_array() {
+            Some(keys) if keys.len() == rows * columns => keys,
+            _ => {
+                errors.push(format!(
+                    "layer {layer}: expected a list of {rows}x{columns} keys"
+                ));
+                continue;
+            }
+        };
+        table.push_str("    layout![\n");
+        for (row, keys) in keys.chunks(columns).enumerate() {
+            table.push_str("       ");
+            for (column, key) in keys.iter().enumerate() {
+                match key.as_str() {
+                    Some(name) if names.iter().any(|known| known == name) => {
+                        table.push(' ');
+                        table.push_str(name);
+                    }
+                    _ => errors.push(format!(
+                        "layer {layer}, row {row}, column {column}: unknown key {key}"
+                    )),
+                }
+            }
+            table.push('\n');
+        }
+        table.push_str("    ],\n");
+    }
+    table.push_str("];\n");
+    let actions = source::actions(Path::new(""));
+    table += &format!("\nconst _: [Action; {}] = [\n", actions.len());
+    for action in actions {
+        table += &format!("    {action},\n");
+    }
+    table.push_str("];\n");
+    if errors.is_empty() {
+        Ok(table)
+    } else {
+        Err(errors)
+    }
 }
@@ -0,0 +1,139 @@
+//! What build scripts read from the firmware's data files: the names a key
+//! can have in a keymap file from `src/keys.json`, the matrix size and USB
+//! ids of the boards from `src/board/NAME.json`, and the board to build for.
+//! Included with `#[path]` by `build.rs`, `tool/build.rs` and
+//! `bootloader/build.rs`, `root` being the firmware crate's directory. Each
+//! of them uses only some of it.
+
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use serde_json::Value;
+
+pub const DEFAULT_BOARD: &str = "tpkb50";
+
+/// Files the results depend on, for `cargo:rerun-if-changed`.
+pub fn sources(root: &Path) -> Vec<PathBuf> {
+    vec![root.join("src/keys.json"), root.join("src/board")]
+}
+
+fn read(path: &Path) -> Value {
+    let json = fs::read_to_string(path).unwrap();
+    serde_json::from_str(&json).unwrap_or_else(|e| panic!("{}: {e}", path.display()))
+}
+
+/// `0x` hex number of a data file.
+fn hex(value: &Value, path: &Path) -> u32 {
+    let hex = value.as_str().and_then(|hex| hex.strip_prefix("0x"));
+    hex.and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .unwrap_or_else(|| panic!("bad number {value} in {}", path.display()))
+}
+
+/// The `KeyCode` variants, by name, with their HID usage.
+pub fn keycodes(root: &Path) -> Vec<(String, u8)> {
+    let path = root.join("src/keys.json");
+    let keys = read(&path);
+    let keycodes = keys["keycodes"].as_object();
+    let keycodes = keycodes.unwrap_or_else(|| panic!("no keycodes in {}", path.display()));
+    keycodes
+        .iter()
+        .map(|(name, code)| (name.clone(), hex(code, &path) as u8))
+        .collect()
+}
+
+/// The `Action` consts of `layout.rs` a key can be.
+pub fn actions(root: &Path) -> Vec<String> {
+    let path = root.join("src/keys.json");
+    let keys = read(&path);
+    let actions = keys["actions"].as_array();
+    let actions = actions.unwrap_or_else(|| panic!("no actions in {}", path.display()));
+    actions
+        .iter()
+        .map(|name| name.as_str().unwrap().to_string())
+        .collect()
+}
+
+/// Names a key can have: the `KeyCode` variants and the `Action` consts.
+pub fn key_names(root: &Path) -> Vec<String> {
+    let keycodes = keycodes(root).into_iter().map(|(name, _)| name);
+    keycodes.chain(actions(root)).collect()
+}
+
+/// Rows and columns of the board `name`.
+pub fn matrix_size(root: &Path, name: &str) -> (usize, usize) {
+    let path = root.join(format!("src/board/{name}.json"));
+    let size = &read(&path)["matrix_size"];
+    let count = |value: &Value| {
+        let count = value.as_u64().map(|count| count as usize);
+        count.unwrap_or_else(|| panic!("no matrix_size in {}", path.display()))
+    };
+    (count(&size["rows"]), count(&size["cols"]))
+}
+
+/// Name, rows and columns of each board in `src/board/`, by name.
+pub fn boards(root: &Path) -> Vec<(String, usize, usize)> {
+    let mut boards: Vec<_> = fs::read_dir(root.join("src/board"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            let (rows, columns) = matrix_size(root, &name);
+            (name, rows, columns)
+        })
+        .collect();
+    boards.sort();
+    boards
+}
+
+/// Name of the board of the `board-NAME` feature, `DEFAULT_BOARD` without.
+pub fn board() -> String {
+    let boards: Vec<String> = env::vars()
+        .filter_map(|(var, _)| {
+            let board = var.strip_prefix("CARGO_FEATURE_BOARD_")?;
+            Some(board.to_lowercase())
+        })
+        .collect();
+    match boards.as_slice() {
+        [] => DEFAULT_BOARD.to_string(),
+        [board] => board.clone(),
+        _ => {
+            eprintln!("more than one board feature: {}", boards.join(", "));
+            process::exit(1);
+        }
+    }
+}
+
+/// What the USB device of a board calls itself.
+pub struct UsbIds {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: String,
+    pub product: String,
+    pub serial_number: String,
+}
+
+/// USB ids in `src/board/NAME.json` of the board `name`.
+pub fn usb_ids(root: &Path, name: &str) -> UsbIds {
+    let path = root.join(format!("src/board/{name}.json"));
+    let info = read(&path);
+    let text = |value: &Value, field: &str| {
+        let text = value.as_str();
+        text.unwrap_or_else(|| panic!("no {field} in {}", path.display()))
+            .to_string()
+    };
+    UsbIds {
+        vendor_id: hex(&info["usb"]["vid"], &path) as u16,
+        product_id: hex(&info["usb"]["pid"], &path) as u16,
+        manufacturer: text(&info["manufacturer"], "manufacturer"),
+        product: text(&info["keyboard_name"], "keyboard_name"),
+        serial_number: text(&info["usb"]["serial_number"], "usb.serial_number"),
+    }
+}
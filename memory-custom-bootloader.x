@@ -0,0 +1,13 @@
+/* memory.x for the `custom-bootloader` feature */
+MEMORY
+{
+  /* NOTE 1 K = 1 KiBi = 1024 bytes */
+  /* sector 0 holds the bootloader, sectors 1 and 2 the settings, the */
+  /* firmware gets sectors 3 and 4 up to what the bootloader holds in */
+  /* RAM, see `tpkb50_protocol::image::MAX_APP_LEN` */
+  FLASH : ORIGIN = 0x0800C000, LENGTH = 56K - 16
+  /* sector 5 holds the keymap, see `keymap::KEYMAP_OFFSET` */
+  KEYMAP : ORIGIN = 0x08020000, LENGTH = 128K
+  /* the last word is `image::REQUEST_ADDRESS` */
+  RAM : ORIGIN = 0x20000000, LENGTH = 64K - 4
+}
@@ -1,10 +1,13 @@
 MEMORY
 {
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
-  /* TODO Adjust these memory regions to match your device memory layout */
-  /* These values correspond to the LM3S6965, one of the few devices QEMU can emulate */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 256K
-  RAM : ORIGIN = 0x20000000, LENGTH = 64K
+  /* STM32F401, 256K flash in sectors of 4x16K, 64K and 128K */
+  /* sectors 1 and 2 hold the settings, see `_stext` below */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 128K
+  /* sector 5 holds the keymap, see `keymap::KEYMAP_OFFSET` */
+  KEYMAP : ORIGIN = 0x08020000, LENGTH = 128K
+  /* the last word is `image::REQUEST_ADDRESS`, see `bootloader` */
+  RAM : ORIGIN = 0x20000000, LENGTH = 64K - 4
 }
 
 /* This is where the call stack will be allocated. */
@@ -18,7 +21,8 @@ MEMORY
    section */
 /* This is required only on microcontrollers that store some configuration right
    after the vector table */
-/* _stext = ORIGIN(FLASH) + 0x400; */
+/* Sector 0 only holds the vector table, code starts at sector 3 */
+_stext = ORIGIN(FLASH) + 0xC000;
 
 /* Example of putting non-initialized variables into custom RAM locations. */
 /* This assumes you have defined a region RAM2 above, and in the Rust
@@ -0,0 +1,145 @@
+//! Firmware images for the custom bootloader in `bootloader/`, built by
+//! `tpkb50-tool pack` from a firmware binary linked for `APP_ADDRESS`.
+//!
+//! Image: header, then the firmware.
+//!
+//! | offset | size | field                           |
+//! |--------|------|---------------------------------|
+//! | 0      | 4    | `MAGIC`                         |
+//! | 4      | 4    | firmware length, little endian  |
+//! | 8      | 4    | CRC-32 of the firmware, LE      |
+//! | 12     | 4    | reserved, 0                     |
+//!
+//! The bootloader writes the firmware to `APP_ADDRESS` and the header to
+//! `HEADER_ADDRESS` last, and only starts firmware its header matches.
+
+pub const MAGIC: [u8; 4] = *b"TPKF";
+pub const HEADER_LEN: usize = 16;
+
+/// Sectors 3 and 4, after the bootloader in sector 0 and the settings.
+pub const APP_ADDRESS: u32 = 0x0800_C000;
+pub const APP_SECTORS: [u8; 2] = [3, 4];
+/// End of sector 4, the keymap follows.
+pub const APP_END: u32 = 0x0802_0000;
+pub const HEADER_ADDRESS: u32 = APP_END - HEADER_LEN as u32;
+/// Longest image, what the bootloader holds in RAM. The sectors would
+/// have room for 80K.
+pub const MAX_IMAGE_LEN: usize = 56 * 1024;
+pub const MAX_APP_LEN: usize = MAX_IMAGE_LEN - HEADER_LEN;
+
+const _: () = assert!(MAX_APP_LEN <= (HEADER_ADDRESS - APP_ADDRESS) as usize);
+
+/// The last word of RAM, left out of both linker scripts. The firmware
+/// puts `REQUEST_MAGIC` there before a reset to stay in the bootloader.
+pub const REQUEST_ADDRESS: u32 = 0x2000_FFFC;
+pub const REQUEST_MAGIC: u32 = 0xB007_10AD;
+
+/// Control transfer size of the bootloader, it fits the control buffer of
+/// usb-device. The firmware's DFU runtime interface tells it to hosts.
+pub const DFU_TRANSFER_SIZE: u16 = 128;
+/// Plain DFU 1.1, no DfuSe extensions.
+pub const DFU_VERSION: u16 = 0x0110;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Header {
+    pub len: u32,
+    pub crc: u32,
+}
+
+impl Header {
+    /// Header of `firmware`, `None` if it doesn't fit into the sectors.
+    pub fn new(firmware: &[u8]) -> Option<Header> {
+        if firmware.is_empty() || firmware.len() > MAX_APP_LEN {
+            return None;
+        }
+        Some(Header {
+            len: firmware.len() as u32,
+            crc: crc32(firmware),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4..8].copy_from_slice(&self.len.to_le_bytes());
+        header[8..12].copy_from_slice(&self.crc.to_le_bytes());
+        header
+    }
+
+    /// `None` without `MAGIC` or with a length that can't be.
+    pub fn decode(header: &[u8]) -> Option<Header> {
+        let header = header.get(..HEADER_LEN)?;
+        let word =
+            |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+        let (len, crc) = (word(4), word(8));
+        if header[..4] != MAGIC || len == 0 || len as usize > MAX_APP_LEN {
+            return None;
+        }
+        Some(Header { len, crc })
+    }
+
+    /// Whether `firmware` is what this header was made for.
+    pub fn matches(&self, firmware: &[u8]) -> bool {
+        firmware.len() == self.len as usize && crc32(firmware) == self.crc
+    }
+}
+
+/// Split `image` into header and firmware, `None` if the firmware isn't
+/// what the header says.
+pub fn verify(image: &[u8]) -> Option<(Header, &[u8])> {
+    let header = Header::decode(image)?;
+    let firmware = &image[HEADER_LEN..];
+    header.matches(firmware).then_some((header, firmware))
+}
+
+/// CRC-32 as used by zlib and Ethernet, table driven so checking the
+/// firmware at every start stays quick.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc = crc >> 8 ^ CRC_TABLE[((crc ^ *byte as u32) & 0xFF) as usize];
+    }
+    !crc
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let firmware = [0x5A; 100];
+        let header = Header::new(&firmware).unwrap();
+        assert_eq!(Header::decode(&header.encode()), Some(header));
+        assert!(header.matches(&firmware));
+        assert!(!header.matches(&firmware[1..]));
+        assert!(Header::new(&[0; MAX_APP_LEN + 1]).is_none());
+        assert!(Header::new(&[]).is_none());
+    }
+}
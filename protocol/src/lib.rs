@@ -0,0 +1,326 @@
+//! Configuration protocol of the tpkb50, shared by the firmware and the
+//! `tpkb50-tool` host CLI.
+//!
+//! It runs over the raw HID interface VIA uses (usage page `USAGE_PAGE`,
+//! usage `USAGE`), one 32 byte report per request and response. Reports
+//! starting with `PREFIX` are for this protocol, VIA never sends that id:
+//! its command ids end at 0x15, and 0xFF is its answer to an unknown one.
+//!
+//! Request:
+//!
+//! | byte | field                   |
+//! |------|-------------------------|
+//! | 0    | `PREFIX`                |
+//! | 1    | `VERSION`               |
+//! | 2    | command                 |
+//! | 3    | sequence, echoed back   |
+//! | 4..  | arguments               |
+//!
+//! Response, the same but with a [`Status`] at byte 4 and the result after.
+//!
+//! | command                   | arguments               | result                          |
+//! |---------------------------|-------------------------|---------------------------------|
+//! | `0x01` get info           |                         | layers, rows, columns, firmware version (3 bytes) |
+//! | `0x02` get key            | layer, row, column      | action record (4 bytes)         |
+//! | `0x03` set key            | layer, row, column, action record | |
+//! | `0x04` read TrackPoint RAM  | location              | value                           |
+//! | `0x05` write TrackPoint RAM | location, value       |                                 |
+//! | `0x06` get setting        | key                     | length, value                   |
+//! | `0x07` reboot             |                         |                                 |
+//! | `0x08` enter bootloader   |                         |                                 |
+//!
+//! Action records are the 4 bytes of `tpkb50::keymap::encode`, settings
+//! keys those of `tpkb50::settings::key`. A changed key is saved to flash
+//! by the keyboard on its own.
+//!
+//! [`image`] has the format of firmware updates for the custom bootloader.
+
+#![no_std]
+
+pub mod image;
+
+pub const REPORT_LEN: usize = 32;
+pub type Report = [u8; REPORT_LEN];
+
+pub const USAGE_PAGE: u16 = 0xFF60;
+pub const USAGE: u16 = 0x61;
+pub const PREFIX: u8 = 0xF1;
+/// Bumped on any incompatible change.
+pub const VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 4;
+/// Result bytes in a response after the status.
+pub const RESULT_LEN: usize = REPORT_LEN - HEADER_LEN - 1;
+/// Longest setting value a response carries.
+pub const SETTING_LEN: usize = RESULT_LEN - 1;
+
+const GET_INFO: u8 = 0x01;
+const GET_KEY: u8 = 0x02;
+const SET_KEY: u8 = 0x03;
+const READ_TRACKPOINT_RAM: u8 = 0x04;
+const WRITE_TRACKPOINT_RAM: u8 = 0x05;
+const GET_SETTING: u8 = 0x06;
+const REBOOT: u8 = 0x07;
+const BOOTLOADER: u8 = 0x08;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Status {
+    Ok = 0,
+    UnknownCommand = 1,
+    InvalidArgument = 2,
+    Unsupported = 3,
+    Failed = 4,
+    VersionMismatch = 5,
+}
+
+impl Status {
+    fn from_u8(status: u8) -> Status {
+        match status {
+            0 => Status::Ok,
+            1 => Status::UnknownCommand,
+            2 => Status::InvalidArgument,
+            3 => Status::Unsupported,
+            5 => Status::VersionMismatch,
+            _ => Status::Failed,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct KeyPosition {
+    pub layer: u8,
+    pub row: u8,
+    pub column: u8,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Request {
+    GetInfo,
+    GetKey(KeyPosition),
+    SetKey(KeyPosition, [u8; 4]),
+    ReadTrackPointRam(u8),
+    WriteTrackPointRam(u8, u8),
+    GetSetting(u8),
+    Reboot,
+    Bootloader,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Info {
+    pub layers: u8,
+    pub rows: u8,
+    pub columns: u8,
+    /// Major, minor, patch.
+    pub firmware: [u8; 3],
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Setting {
+    len: u8,
+    data: [u8; SETTING_LEN],
+}
+
+impl Setting {
+    /// `None` if `value` is longer than `SETTING_LEN`.
+    pub fn new(value: &[u8]) -> Option<Setting> {
+        let mut data = [0; SETTING_LEN];
+        data.get_mut(..value.len())?.copy_from_slice(value);
+        Some(Setting {
+            len: value.len() as u8,
+            data,
+        })
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Response {
+    Info(Info),
+    Key([u8; 4]),
+    TrackPointRam(u8),
+    Setting(Setting),
+    /// Request without a result carried out.
+    Done,
+}
+
+impl Request {
+    fn command(&self) -> u8 {
+        match self {
+            Request::GetInfo => GET_INFO,
+            Request::GetKey(_) => GET_KEY,
+            Request::SetKey(..) => SET_KEY,
+            Request::ReadTrackPointRam(_) => READ_TRACKPOINT_RAM,
+            Request::WriteTrackPointRam(..) => WRITE_TRACKPOINT_RAM,
+            Request::GetSetting(_) => GET_SETTING,
+            Request::Reboot => REBOOT,
+            Request::Bootloader => BOOTLOADER,
+        }
+    }
+
+    pub fn encode(&self, sequence: u8) -> Report {
+        let mut report = [0; REPORT_LEN];
+        report[..HEADER_LEN].copy_from_slice(&[PREFIX, VERSION, self.command(), sequence]);
+        let args = &mut report[HEADER_LEN..];
+        match *self {
+            Request::GetKey(position) => args[..3].copy_from_slice(&position.to_bytes()),
+            Request::SetKey(position, record) => {
+                args[..3].copy_from_slice(&position.to_bytes());
+                args[3..7].copy_from_slice(&record);
+            }
+            Request::ReadTrackPointRam(location) | Request::GetSetting(location) => {
+                args[0] = location
+            }
+            Request::WriteTrackPointRam(location, value) => {
+                args[0] = location;
+                args[1] = value;
+            }
+            Request::GetInfo | Request::Reboot | Request::Bootloader => {}
+        }
+        report
+    }
+
+    pub fn decode(report: &Report) -> Result<Request, Status> {
+        if report[0] != PREFIX {
+            return Err(Status::UnknownCommand);
+        }
+        if report[1] != VERSION {
+            return Err(Status::VersionMismatch);
+        }
+        let args = &report[HEADER_LEN..];
+        let position = KeyPosition {
+            layer: args[0],
+            row: args[1],
+            column: args[2],
+        };
+        let request = match report[2] {
+            GET_INFO => Request::GetInfo,
+            GET_KEY => Request::GetKey(position),
+            SET_KEY => Request::SetKey(position, [args[3], args[4], args[5], args[6]]),
+            READ_TRACKPOINT_RAM => Request::ReadTrackPointRam(args[0]),
+            WRITE_TRACKPOINT_RAM => Request::WriteTrackPointRam(args[0], args[1]),
+            GET_SETTING => Request::GetSetting(args[0]),
+            REBOOT => Request::Reboot,
+            BOOTLOADER => Request::Bootloader,
+            _ => return Err(Status::UnknownCommand),
+        };
+        Ok(request)
+    }
+}
+
+impl KeyPosition {
+    fn to_bytes(self) -> [u8; 3] {
+        [self.layer, self.row, self.column]
+    }
+}
+
+impl Response {
+    /// Response to the request in `request`, which is echoed.
+    pub fn encode(request: &Report, result: Result<Response, Status>) -> Report {
+        let mut report = [0; REPORT_LEN];
+        report[..HEADER_LEN].copy_from_slice(&[PREFIX, VERSION, request[2], request[3]]);
+        let (status, data) = report[HEADER_LEN..].split_first_mut().unwrap();
+        let response = match result {
+            Ok(response) => response,
+            Err(error) => {
+                *status = error as u8;
+                return report;
+            }
+        };
+        *status = Status::Ok as u8;
+        match response {
+            Response::Info(info) => {
+                data[..3].copy_from_slice(&[info.layers, info.rows, info.columns]);
+                data[3..6].copy_from_slice(&info.firmware);
+            }
+            Response::Key(record) => data[..4].copy_from_slice(&record),
+            Response::TrackPointRam(value) => data[0] = value,
+            Response::Setting(setting) => {
+                data[0] = setting.len;
+                data[1..].copy_from_slice(&setting.data);
+            }
+            Response::Done => {}
+        }
+        report
+    }
+
+    /// Sequence number and result of a response.
+    pub fn decode(report: &Report) -> Result<(u8, Result<Response, Status>), Status> {
+        if report[0] != PREFIX {
+            return Err(Status::UnknownCommand);
+        }
+        if report[1] != VERSION {
+            return Err(Status::VersionMismatch);
+        }
+        let sequence = report[3];
+        let status = Status::from_u8(report[4]);
+        if status != Status::Ok {
+            return Ok((sequence, Err(status)));
+        }
+        let data = &report[HEADER_LEN + 1..];
+        let response = match report[2] {
+            GET_INFO => Response::Info(Info {
+                layers: data[0],
+                rows: data[1],
+                columns: data[2],
+                firmware: [data[3], data[4], data[5]],
+            }),
+            GET_KEY => Response::Key([data[0], data[1], data[2], data[3]]),
+            READ_TRACKPOINT_RAM => Response::TrackPointRam(data[0]),
+            GET_SETTING => {
+                let value = data[1..].get(..data[0] as usize).ok_or(Status::Failed)?;
+                Response::Setting(Setting::new(value).ok_or(Status::Failed)?)
+            }
+            SET_KEY | WRITE_TRACKPOINT_RAM | REBOOT | BOOTLOADER => Response::Done,
+            _ => return Err(Status::UnknownCommand),
+        };
+        Ok((sequence, Ok(response)))
+    }
+}
+
+/// What the keyboard does for each request, implemented by the firmware
+/// and by fakes for trying the tool without hardware.
+pub trait Device {
+    fn info(&self) -> Info;
+    fn get_key(&self, position: KeyPosition) -> Result<[u8; 4], Status>;
+    fn set_key(&mut self, position: KeyPosition, record: [u8; 4]) -> Result<(), Status>;
+    fn read_trackpoint_ram(&mut self, location: u8) -> Result<u8, Status>;
+    fn write_trackpoint_ram(&mut self, location: u8, value: u8) -> Result<(), Status>;
+    /// Copy the value of setting `key` to `buf`, returning its length.
+    fn get_setting(&self, key: u8, buf: &mut [u8]) -> Result<usize, Status>;
+    /// Reboot once the response is sent.
+    fn reboot(&mut self) -> Result<(), Status>;
+    /// Enter the bootloader once the response is sent.
+    fn bootloader(&mut self) -> Result<(), Status>;
+}
+
+/// Carry out the request in `report` on `device`, returning the response.
+pub fn serve<D: Device>(device: &mut D, report: &Report) -> Report {
+    let result = Request::decode(report).and_then(|request| match request {
+        Request::GetInfo => Ok(Response::Info(device.info())),
+        Request::GetKey(position) => device.get_key(position).map(Response::Key),
+        Request::SetKey(position, record) => device.set_key(position, record).map(done),
+        Request::ReadTrackPointRam(location) => device
+            .read_trackpoint_ram(location)
+            .map(Response::TrackPointRam),
+        Request::WriteTrackPointRam(location, value) => {
+            device.write_trackpoint_ram(location, value).map(done)
+        }
+        Request::GetSetting(key) => {
+            let mut buf = [0; SETTING_LEN];
+            let len = device.get_setting(key, &mut buf)?;
+            Setting::new(buf.get(..len).ok_or(Status::Failed)?)
+                .map(Response::Setting)
+                .ok_or(Status::Failed)
+        }
+        Request::Reboot => device.reboot().map(done),
+        Request::Bootloader => device.bootloader().map(done),
+    });
+    Response::encode(report, result)
+}
+
+fn done(_: ()) -> Response {
+    Response::Done
+}
@@ -1,4 +1,4 @@
-use crate::keycodes::{KeyCode, MouseCode};
+use crate::keycodes::{KeyCode, Modifiers, MouseCode};
 use crate::layout::LayerNumber;
 
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -6,11 +6,27 @@ pub enum Action {
     Nop,
     Transparent, // Fall-through to the next layer underneath
     Key(KeyCode),
-    ShiftKey(KeyCode),
+    ModifiedKey(Modifiers, KeyCode), // Key sent together with the modifiers
     LayerTapKey(LayerNumber, KeyCode),
+    ModTap(Modifiers, KeyCode), // Modifiers when held, key when tapped
+    OneShotMod(Modifiers),      // Modifiers for the next key, double tap locks
     LayerMomentary(LayerNumber),
     LayerToggle(LayerNumber),
+    LayerTo(LayerNumber),             // Only this layer above the default layer
+    DefaultLayer(LayerNumber),        // Base layer, e.g. QWERTY or Colemak
+    OneShotLayer(LayerNumber),        // Layer for the next key press only
+    LayerMod(LayerNumber, Modifiers), // Momentary layer with modifiers held
+    LayerLock,                        // Keep the top layer on, press again to release
     Mouse(MouseCode),
+    Leader,       // Start a key sequence, see `layout::LEADER_SEQUENCES`
+    Macro(usize), // Play `layout::MACROS[index]`
+    DynamicMacroRecord,
+    DynamicMacroStop,
+    DynamicMacroPlay,
+    CapsWord,   // Shift letters until the end of the word, host Caps Lock untouched
+    Repeat,     // The last key sent, with its modifiers
+    AltRepeat,  // Alternate of the last key, see `layout::ALT_REPEAT`
+    Bootloader, // Restart into the bootloader for flashing over USB, see `bootloader`
 }
 
 // Allow auto-conversion of KeyCodes to Action for nicer layout formatting
@@ -25,14 +41,51 @@ macro_rules! layout {
     };
 }
 
+// Shorthands adding a single modifier, chainable as in `Delete.lctrl().lalt()`
+macro_rules! modifier_fns {
+    ( $( $name:ident => $mods:ident ),* ) => {
+        $(
+            pub const fn $name(self) -> Action {
+                self.with(Modifiers::$mods)
+            }
+        )*
+    };
+}
+
 impl KeyCode {
     pub const fn to_action(self) -> Action {
         Action::Key(self)
     }
+
+    /// `self` sent together with `mods`.
+    pub const fn with(self, mods: Modifiers) -> Action {
+        Action::ModifiedKey(mods, self)
+    }
+
+    modifier_fns!(
+        lctrl => LCTRL, lshift => LSHIFT, lalt => LALT, lmeta => LMETA,
+        rctrl => RCTRL, rshift => RSHIFT, ralt => RALT, rmeta => RMETA
+    );
 }
 
 impl Action {
     pub const fn to_action(self) -> Action {
         self
     }
+
+    /// Add `mods` to a `Key` or `ModifiedKey`, other actions are kept as is.
+    ///
+    /// e.g. `S.lmeta().lshift()` or `Delete.with(Modifiers::LCTRL.union(Modifiers::LALT))`.
+    pub const fn with(self, mods: Modifiers) -> Action {
+        match self {
+            Action::Key(code) => Action::ModifiedKey(mods, code),
+            Action::ModifiedKey(held, code) => Action::ModifiedKey(held.union(mods), code),
+            action => action,
+        }
+    }
+
+    modifier_fns!(
+        lctrl => LCTRL, lshift => LSHIFT, lalt => LALT, lmeta => LMETA,
+        rctrl => RCTRL, rshift => RSHIFT, ralt => RALT, rmeta => RMETA
+    );
 }
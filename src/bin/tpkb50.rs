@@ -8,32 +8,31 @@ use panic_halt as _;
 #[rtic::app(device = hal::pac, peripherals = true)]
 mod app {
     // use cortex_m_semihosting::hprintln;
-    type UsbDevice = usb_device::device::UsbDevice<'static, UsbBusType>;
-    type HidDev = HIDClass<'static, UsbBusType>;
+    use cortex_m::peripheral::SCB;
     use hal::{
-        gpio::{
-            alt::otg_fs::{Dm::PA11, Dp::PA12},
-            PinState::Low,
-        },
+        flash::FlashExt,
         otg_fs::{UsbBusType, USB},
         prelude::*,
         timer::Event,
     };
     use stm32f4xx_hal as hal;
     use tpkb50::{
+        board, bootloader,
+        config::{Config, Pending},
+        flash::InternalFlash,
         keyboard::Keyboard,
-        keycodes::{KeyCode, MouseCode},
-        keymatrix::KeyMatrix,
-        trackpoint::{
-            TrackPoint, RST as TP_RST, SCL as TP_SCL, SDA as TP_SDA,
-            SFACTOR_HIGH as TP_SFACTOR_HIGH,
-        },
-    };
-    use usb_device::{bus::UsbBusAllocator, prelude::*};
-    use usbd_hid::{
-        descriptor::{generator_prelude::SerializedDescriptor, KeyboardReport, MouseReport},
-        hid_class::HIDClass,
+        keycodes::MouseCode,
+        keymap::{self, KEYMAP_OFFSET},
+        keymatrix::{KeyMatrix, DEBOUNCE},
+        layout::TAP_HOLD,
+        settings::{key, Settings},
+        trackpoint::{TrackPoint, SFACTOR_HIGH as TP_SFACTOR_HIGH},
+        usb::Usb,
+        via,
     };
+    use tpkb50_protocol as protocol;
+    use usb_device::bus::UsbBusAllocator;
+    use usbd_hid::descriptor::MouseReport;
     const RSV_MSB1: u8 = MouseCode::BTN1 as u8;
     const RSV_MSB2: u8 = MouseCode::BTN2 as u8;
     const RSV_MSB3: u8 = MouseCode::BTN3 as u8;
@@ -41,21 +40,66 @@ mod app {
     const RSV_WHDN: u8 = MouseCode::BTN5 as u8;
     const RSV_WHLT: u8 = MouseCode::BTN6 as u8;
     const RSV_WHRT: u8 = MouseCode::BTN7 as u8;
-    const RSV_TAB: u8 = KeyCode::Tab as u8;
-    const RSV_SPACE: u8 = KeyCode::Space as u8;
+    // keymap changes from VIA are saved once no more came for this long, ms
+    const SAVE_DELAY: u16 = 2000;
+    // time for the response to a reboot request to reach the host, ms
+    const REBOOT_DELAY: u16 = 50;
+
+    /// Act on what a key, configuration request or console command asked for.
+    fn schedule(pending: Pending, save_in: &mut u16, restart: &mut (u16, Pending)) {
+        match pending {
+            // applied by the caller, it has the settings at hand
+            Pending::None | Pending::ApplySettings => {}
+            Pending::SaveKeymap => *save_in = SAVE_DELAY,
+            Pending::Reboot | Pending::Bootloader => *restart = (REBOOT_DELAY, pending),
+        }
+    }
+
+    /// Use the stored settings, or the compiled-in defaults for those not set.
+    fn apply_settings(
+        settings: Option<&Settings<InternalFlash>>,
+        keyboard: &mut Keyboard,
+        trackpoint: &mut TrackPoint,
+        matrix: &mut KeyMatrix,
+    ) {
+        let term = settings.and_then(|s| s.get_u16(key::TAPPING_TERM));
+        keyboard.set_tapping_term(term.unwrap_or(TAP_HOLD.tapping_term));
+        let sensitivity = settings.and_then(|s| s.get_u8(key::TP_SENSITIVITY));
+        trackpoint.set_sensitivity_factor(sensitivity.unwrap_or(TP_SFACTOR_HIGH));
+        let debounce = settings.and_then(|s| s.get_u8(key::DEBOUNCE));
+        matrix.set_debounce(debounce.unwrap_or(DEBOUNCE));
+    }
+
+    fn save_keymap(
+        keyboard: &Keyboard,
+        settings: &mut Option<Settings<InternalFlash>>,
+        flash: &mut Option<InternalFlash>,
+    ) {
+        // the erase stalls everything for a second or two
+        let image = keymap::store(keyboard.keymap(), keyboard.macros());
+        let flash = match settings {
+            Some(settings) => Some(settings.flash()),
+            None => flash.as_mut(),
+        };
+        if let Some(flash) = flash {
+            // on failure the CRC makes the next boot fall back to `LAYERS`
+            flash.store_keymap(&image).ok();
+        }
+    }
 
     #[local]
     struct Local {
         keyboard: Keyboard,
         matrix: KeyMatrix,
         trackpoint: TrackPoint,
+        settings: Option<Settings<InternalFlash>>,
+        /// The flash when `settings` failed to mount, for the keymap.
+        flash: Option<InternalFlash>,
     }
 
     #[shared]
     struct Shared {
-        usb_dev: UsbDevice,
-        hid_kb: HidDev,
-        hid_ms: HidDev,
+        usb: Usb,
     }
 
     #[init(local = [
@@ -70,148 +114,172 @@ mod app {
             .sysclk(48.MHz())
             .require_pll48clk()
             .freeze();
-        let gpioa = ctx.device.GPIOA.split();
-        let gpiob = ctx.device.GPIOB.split();
-        let gpioc = ctx.device.GPIOC.split();
+        let pins = board::pins(
+            ctx.device.GPIOA.split(),
+            ctx.device.GPIOB.split(),
+            ctx.device.GPIOC.split(),
+        );
 
         let usb = USB {
             usb_global: ctx.device.OTG_FS_GLOBAL,
             usb_device: ctx.device.OTG_FS_DEVICE,
             usb_pwrclk: ctx.device.OTG_FS_PWRCLK,
-            pin_dm: PA11(gpioa.pa11.into_alternate()),
-            pin_dp: PA12(gpioa.pa12.into_alternate()),
+            pin_dm: pins.usb_dm,
+            pin_dp: pins.usb_dp,
             hclk: clocks.hclk(),
         };
 
-        let p_rst: TP_RST = gpiob.pb7.into_push_pull_output_in_state(Low).erase();
-        let p_clk: TP_SCL = gpiob.pb8.into_open_drain_output().erase();
-        let p_data: TP_SDA = gpiob.pb9.into_open_drain_output().erase();
         let delay = ctx.core.SYST.delay(&clocks);
 
-        let mut trackpoint = TrackPoint::new(p_clk, p_data, p_rst, delay);
+        let mut keyboard = Keyboard::new();
+        // keep the compiled-in layout unless a valid keymap was stored
+        if let Some((keymap, macros)) = keymap::load(&ctx.device.FLASH.read()[KEYMAP_OFFSET..]) {
+            keyboard.set_keymap(keymap);
+            *keyboard.macros_mut() = macros;
+        }
+        let (settings, flash) = match Settings::new(InternalFlash::new(ctx.device.FLASH)) {
+            Ok(settings) => (Some(settings), None),
+            // keymap changes are still saved without the settings
+            Err((flash, _)) => (None, Some(flash)),
+        };
+
+        let mut trackpoint = TrackPoint::new(pins.tp_clk, pins.tp_data, pins.tp_rst, delay);
         trackpoint.reset();
-        trackpoint.set_sensitivity_factor(TP_SFACTOR_HIGH);
         // default remote mode, stream not work well as expected with tim exti.
         // trackpoint.set_stream_mode();
 
         *ctx.local.USB_BUS = Some(UsbBusType::new(usb, ctx.local.EP_MEMORY));
         let usb_bus = ctx.local.USB_BUS.as_ref().unwrap();
 
-        let hid_kb = HIDClass::new(usb_bus, KeyboardReport::desc(), 10);
-        let hid_ms = HIDClass::new(usb_bus, MouseReport::desc(), 10);
-
-        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x2023, 0x0610))
-            .manufacturer("Custom")
-            .product("Trackpoint Keyboard")
-            .serial_number("20221010")
-            .device_class(0)
-            .build();
+        let usb = Usb::new(usb_bus);
 
         let mut timer = ctx.device.TIM3.counter_hz(&clocks);
         timer.start(1.kHz()).unwrap();
         timer.listen(Event::Update);
 
-        let rows = [
-            gpiob.pb6.into_pull_down_input().erase(),
-            gpiob.pb5.into_pull_down_input().erase(),
-            gpiob.pb4.into_pull_down_input().erase(),
-            gpiob.pb3.into_pull_down_input().erase(),
-        ];
-        let cols = [
-            gpiob.pb10.into_push_pull_output().erase(),
-            gpioc.pc14.into_push_pull_output().erase(),
-            gpiob.pb1.into_push_pull_output().erase(),
-            gpiob.pb0.into_push_pull_output().erase(),
-            gpioa.pa7.into_push_pull_output().erase(),
-            gpioa.pa6.into_push_pull_output().erase(),
-            gpioa.pa5.into_push_pull_output().erase(),
-            gpioa.pa4.into_push_pull_output().erase(),
-            gpioa.pa3.into_push_pull_output().erase(),
-            gpioa.pa2.into_push_pull_output().erase(),
-            gpioa.pa1.into_push_pull_output().erase(),
-            gpioa.pa0.into_push_pull_output().erase(),
-            gpioc.pc15.into_push_pull_output().erase(),
-        ];
-        let matrix = cortex_m::interrupt::free(move |_cs| KeyMatrix::new(rows, cols));
+        let (outputs, inputs) = (pins.outputs, pins.inputs);
+        let mut matrix = cortex_m::interrupt::free(move |_cs| KeyMatrix::new(outputs, inputs));
+
+        // stored settings override the compiled-in defaults
+        apply_settings(
+            settings.as_ref(),
+            &mut keyboard,
+            &mut trackpoint,
+            &mut matrix,
+        );
 
         (
-            Shared {
-                usb_dev,
-                hid_kb,
-                hid_ms,
-            },
+            Shared { usb },
             Local {
                 matrix,
-                keyboard: Keyboard::new(),
+                keyboard,
                 trackpoint,
+                settings,
+                flash,
             },
             init::Monotonics(),
         )
     }
 
-    #[task(binds = OTG_FS, priority = 3, shared = [usb_dev, hid_kb, hid_ms])]
-    fn usb_tx(ctx: usb_tx::Context) {
-        (ctx.shared.usb_dev, ctx.shared.hid_kb, ctx.shared.hid_ms).lock(
-            |usb_dev, hid_kb, hid_ms| {
-                if usb_dev.poll(&mut [hid_kb, hid_ms]) {}
-            },
-        );
+    #[task(binds = OTG_FS, priority = 3, shared = [usb])]
+    fn usb_tx(mut ctx: usb_tx::Context) {
+        ctx.shared.usb.lock(|usb| usb.poll());
     }
 
-    #[task(binds = OTG_FS_WKUP, priority = 3, shared = [usb_dev, hid_kb, hid_ms])]
-    fn usb_rx(ctx: usb_rx::Context) {
-        (ctx.shared.usb_dev, ctx.shared.hid_kb, ctx.shared.hid_ms)
-            .lock(|usb_dev, hid_kb, hid_ms| if usb_dev.poll(&mut [hid_kb, hid_ms]) {});
+    #[task(binds = OTG_FS_WKUP, priority = 3, shared = [usb])]
+    fn usb_rx(mut ctx: usb_rx::Context) {
+        ctx.shared.usb.lock(|usb| usb.poll());
     }
 
-    #[task(binds = TIM3, priority = 1, shared = [hid_kb, hid_ms], local=[
-        matrix, keyboard, trackpoint,
-        ms_btn: u8 = 0, ms_wheel: i8 = 0, ms_pan: i8 = 0
+    #[task(binds = TIM3, priority = 1, shared = [usb], local=[
+        matrix, keyboard, trackpoint, settings, flash,
+        ms_btn: u8 = 0, ms_wheel: i8 = 0, ms_pan: i8 = 0, save_in: u16 = 0,
+        restart: (u16, Pending) = (0, Pending::None)
     ])]
-    fn tick(ctx: tick::Context) {
-        (ctx.shared.hid_kb, ctx.shared.hid_ms).lock(|hid_kb, hid_ms| {
-            if let Some(kb_report) = ctx
-                .local
-                .keyboard
-                .gen_report(&ctx.local.matrix.current_state())
-            {
-                hid_kb.push_input(&kb_report).ok();
-                match kb_report.reserved {
-                    // for mouse wheel key
-                    RSV_WHUP => *ctx.local.ms_wheel = 1,
-                    RSV_WHDN => *ctx.local.ms_wheel = -1,
-                    RSV_WHLT => *ctx.local.ms_pan = -1,
-                    RSV_WHRT => *ctx.local.ms_pan = 1,
-                    btn @ (RSV_MSB1 | RSV_MSB2 | RSV_MSB3) => *ctx.local.ms_btn = btn,
-                    _tap_key @ (RSV_TAB | RSV_SPACE) => {
-                        // empirical time
-                        cortex_m::asm::delay(500_000);
-
-                        // send empty report, prevent repeat key
-                        hid_kb
-                            .push_input(&KeyboardReport {
-                                modifier: 0,
-                                reserved: 0,
-                                leds: 0,
-                                keycodes: [0; 6],
-                            })
-                            .ok();
-                    }
-                    _ => {
-                        (*ctx.local.ms_btn, *ctx.local.ms_wheel, *ctx.local.ms_pan) = (0, 0, 0);
-                    }
-                };
+    fn tick(mut ctx: tick::Context) {
+        let keyboard = ctx.local.keyboard;
+        keyboard.tick(&ctx.local.matrix.current_state());
+        let save_in = ctx.local.save_in;
+        if *save_in > 0 {
+            *save_in -= 1;
+            if *save_in == 0 {
+                save_keymap(keyboard, ctx.local.settings, ctx.local.flash);
+            }
+        }
+        let restart = ctx.local.restart;
+        if keyboard.take_bootloader_request() {
+            schedule(Pending::Bootloader, save_in, restart);
+        }
+        if restart.0 > 0 {
+            restart.0 -= 1;
+            if restart.0 == 0 {
+                // don't lose a keymap change still waiting to be saved
+                if *save_in > 0 {
+                    save_keymap(keyboard, ctx.local.settings, ctx.local.flash);
+                }
+                if restart.1 == Pending::Bootloader {
+                    bootloader::enter();
+                }
+                SCB::sys_reset();
+            }
+        }
+        let tp_data = ctx.local.trackpoint.query_data_report();
+        ctx.shared.usb.lock(|usb| {
+            let mut command = [0; via::REPORT_LEN];
+            if usb.pull_raw(&mut command) {
+                // above every VIA command id, see `config`
+                if command[0] == protocol::PREFIX {
+                    let mut config = Config {
+                        keyboard,
+                        trackpoint: ctx.local.trackpoint,
+                        settings: ctx.local.settings.as_ref(),
+                        pending: Pending::None,
+                    };
+                    config.handle(&mut command);
+                    schedule(config.pending, save_in, restart);
+                } else if via::handle(keyboard, &mut command) {
+                    *save_in = SAVE_DELAY;
+                }
+                usb.push_raw(&command);
+            }
+            // one report per host poll, the rest waits in the keyboard queue
+            if let Some(kb_report) = keyboard.report() {
+                if usb.push_keyboard(&kb_report) {
+                    keyboard.report_sent();
+                    match kb_report.reserved {
+                        // for mouse wheel key
+                        RSV_WHUP => *ctx.local.ms_wheel = 1,
+                        RSV_WHDN => *ctx.local.ms_wheel = -1,
+                        RSV_WHLT => *ctx.local.ms_pan = -1,
+                        RSV_WHRT => *ctx.local.ms_pan = 1,
+                        btn @ (RSV_MSB1 | RSV_MSB2 | RSV_MSB3) => *ctx.local.ms_btn = btn,
+                        _ => {
+                            (*ctx.local.ms_btn, *ctx.local.ms_wheel, *ctx.local.ms_pan) = (0, 0, 0);
+                        }
+                    };
+                }
+            }
+            usb.push_mouse(&MouseReport {
+                x: tp_data.x,
+                y: -tp_data.y,
+                buttons: tp_data.state & 7 | *ctx.local.ms_btn,
+                wheel: *ctx.local.ms_wheel,
+                pan: *ctx.local.ms_pan,
+            });
+            let pending = usb.console(
+                keyboard,
+                ctx.local.trackpoint,
+                &tp_data,
+                ctx.local.settings.as_mut(),
+            );
+            if pending == Pending::ApplySettings {
+                let (trackpoint, matrix) = (&mut *ctx.local.trackpoint, &mut *ctx.local.matrix);
+                apply_settings(ctx.local.settings.as_ref(), keyboard, trackpoint, matrix);
+            }
+            schedule(pending, save_in, restart);
+            if usb.take_detach_request() {
+                schedule(Pending::Bootloader, save_in, restart);
             }
-            let tp_data = &ctx.local.trackpoint.query_data_report();
-            hid_ms
-                .push_input(&MouseReport {
-                    x: tp_data.x,
-                    y: -tp_data.y,
-                    buttons: tp_data.state & 7 | *ctx.local.ms_btn,
-                    wheel: *ctx.local.ms_wheel,
-                    pan: *ctx.local.ms_pan,
-                })
-                .ok();
         })
     }
 }
@@ -0,0 +1,61 @@
+//! What differs between the keyboards the firmware runs on: matrix size and
+//! pins, diode direction, TrackPoint pins and USB ids.
+//!
+//! Each board is a file in `src/board/`, `tpkb50.rs` being the `pcb/`
+//! design. build.rs picks another one, and its keymap, for a `board-NAME`
+//! feature. A board file defines `DIODES` and `pins`, with what is imported
+//! here. Next to it `NAME.json` has the matrix size and USB ids, laid out as
+//! a QMK `info.json`, which build.rs turns into `ROWS`, `COLUMNS` and `USB`.
+//! `tpkb50-tool` and the bootloader in `bootloader/` read it too.
+//!
+//! For a board `src/board/NAME.rs` with its keymap in `keymap-NAME.json`,
+//! add `board-NAME = []` to the `[features]` of `Cargo.toml`. Cargo hands
+//! the feature to build.rs with `_` for `-`, so the file names use `_`:
+//! the feature `board-my-board` builds `src/board/my_board.rs`.
+
+#![deny(unsafe_code)]
+
+use hal::gpio::{
+    alt::otg_fs::{Dm, Dp},
+    gpioa, gpiob, gpioc, EPin, Input, Output, PinState,
+};
+use stm32f4xx_hal as hal;
+
+use crate::trackpoint::{RST, SCL, SDA};
+
+include!(concat!("board/", env!("TPKB50_BOARD"), ".rs"));
+include!(concat!(env!("OUT_DIR"), "/board.rs"));
+
+/// Which way the diodes let current through, from the driven pins to the
+/// read ones.
+pub enum Diodes {
+    ColumnToRow,
+    RowToColumn,
+}
+
+pub struct UsbIds {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: &'static str,
+    pub product: &'static str,
+    pub serial_number: &'static str,
+}
+
+/// Matrix pins driven high one after the other, the columns for
+/// `Diodes::ColumnToRow`.
+pub const OUTPUTS: usize = match DIODES {
+    Diodes::ColumnToRow => COLUMNS,
+    Diodes::RowToColumn => ROWS,
+};
+/// Matrix pins read, with pull-downs.
+pub const INPUTS: usize = ROWS + COLUMNS - OUTPUTS;
+
+pub struct Pins {
+    pub outputs: [EPin<Output>; OUTPUTS],
+    pub inputs: [EPin<Input>; INPUTS],
+    pub tp_clk: SCL,
+    pub tp_data: SDA,
+    pub tp_rst: RST,
+    pub usb_dm: Dm,
+    pub usb_dp: Dp,
+}
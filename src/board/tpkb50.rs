@@ -0,0 +1,40 @@
+// The board in `pcb/`, included by `board.rs`. Its matrix size and USB ids
+// are in `tpkb50.json`.
+
+pub const DIODES: Diodes = Diodes::ColumnToRow;
+
+pub fn pins(gpioa: gpioa::Parts, gpiob: gpiob::Parts, gpioc: gpioc::Parts) -> Pins {
+    Pins {
+        // columns
+        outputs: [
+            gpiob.pb10.into_push_pull_output().erase(),
+            gpioc.pc14.into_push_pull_output().erase(),
+            gpiob.pb1.into_push_pull_output().erase(),
+            gpiob.pb0.into_push_pull_output().erase(),
+            gpioa.pa7.into_push_pull_output().erase(),
+            gpioa.pa6.into_push_pull_output().erase(),
+            gpioa.pa5.into_push_pull_output().erase(),
+            gpioa.pa4.into_push_pull_output().erase(),
+            gpioa.pa3.into_push_pull_output().erase(),
+            gpioa.pa2.into_push_pull_output().erase(),
+            gpioa.pa1.into_push_pull_output().erase(),
+            gpioa.pa0.into_push_pull_output().erase(),
+            gpioc.pc15.into_push_pull_output().erase(),
+        ],
+        // rows
+        inputs: [
+            gpiob.pb6.into_pull_down_input().erase(),
+            gpiob.pb5.into_pull_down_input().erase(),
+            gpiob.pb4.into_pull_down_input().erase(),
+            gpiob.pb3.into_pull_down_input().erase(),
+        ],
+        tp_clk: gpiob.pb8.into_open_drain_output().erase(),
+        tp_data: gpiob.pb9.into_open_drain_output().erase(),
+        tp_rst: gpiob
+            .pb7
+            .into_push_pull_output_in_state(PinState::Low)
+            .erase(),
+        usb_dm: Dm::PA11(gpioa.pa11.into_alternate()),
+        usb_dp: Dp::PA12(gpioa.pa12.into_alternate()),
+    }
+}
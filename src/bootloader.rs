@@ -0,0 +1,45 @@
+//! Restart into the bootloader, so new firmware can be flashed over USB
+//! with `dfu-util` instead of a debug probe.
+//!
+//! That is the STM32F401 ROM bootloader, or with the `custom-bootloader`
+//! feature the one in `bootloader/`. Either way `enter` leaves a magic
+//! value at `REQUEST_ADDRESS` that survives the reset, the last word of
+//! RAM, left out of both linker scripts. For the ROM, the check run before
+//! `main` jumps there while the chip is still in its reset state, as the
+//! ROM expects. The custom bootloader checks it on its own.
+
+use core::ptr;
+
+use cortex_m::peripheral::SCB;
+use tpkb50_protocol::image::{REQUEST_ADDRESS, REQUEST_MAGIC as MAGIC};
+
+/// Reset into the bootloader.
+pub fn enter() -> ! {
+    // SAFETY: a word of RAM no Rust object lives in, see `memory.x`.
+    unsafe { ptr::write_volatile(REQUEST_ADDRESS as *mut u32, MAGIC) };
+    SCB::sys_reset()
+}
+
+#[cfg(not(feature = "custom-bootloader"))]
+mod rom {
+    use core::ptr;
+
+    use cortex_m_rt::pre_init;
+
+    use super::{MAGIC, REQUEST_ADDRESS};
+
+    /// System memory with the ROM bootloader, starting with its vector table.
+    const SYSTEM_MEMORY: u32 = 0x1FFF_0000;
+
+    #[pre_init]
+    unsafe fn jump_if_requested() {
+        // a raw address, no static is touched before the runtime set up RAM
+        let request = REQUEST_ADDRESS as *mut u32;
+        // after power-up this is whatever the RAM came up with
+        if ptr::read_volatile(request) == MAGIC {
+            // a reset out of the bootloader starts the firmware again
+            ptr::write_volatile(request, 0);
+            cortex_m::asm::bootload(SYSTEM_MEMORY as *const u32)
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! The keyboard side of the tpkb50 configuration protocol, see the
+//! `tpkb50-protocol` crate. It shares the raw HID interface with VIA,
+//! reports starting with `protocol::PREFIX` are handled here.
+//!
+//! The interface is shared as the OTG_FS endpoints are all taken, see
+//! [`crate::usb`]. VIA looks for the interface by usage page, a second one
+//! would be no use to it anyway.
+
+#![deny(unsafe_code)]
+
+use tpkb50_protocol::{self as protocol, Device, Info, KeyPosition, Status};
+
+use crate::{
+    keyboard::Keyboard,
+    keymap,
+    keymatrix::{key_index, COLUMNS, ROWS},
+    layout::LAYERS,
+    settings::{Flash, Settings},
+    trackpoint::TrackPoint,
+    via,
+};
+
+// a VIA command must never be taken for a request, nor an answer to one
+// for VIA's "unhandled"
+const _: () = assert!(protocol::PREFIX > via::LAST_COMMAND && protocol::PREFIX != via::UNHANDLED);
+
+/// What a request asks of the firmware once its response is sent.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Pending {
+    None,
+    SaveKeymap,
+    Reboot,
+    Bootloader,
+    /// Stored settings changed, apply them now.
+    ApplySettings,
+}
+
+pub struct Config<'a, F: Flash> {
+    pub keyboard: &'a mut Keyboard,
+    pub trackpoint: &'a mut TrackPoint,
+    pub settings: Option<&'a Settings<F>>,
+    pub pending: Pending,
+}
+
+impl<'a, F: Flash> Config<'a, F> {
+    /// Answer the request in `report` in place.
+    pub fn handle(&mut self, report: &mut protocol::Report) {
+        *report = protocol::serve(self, report);
+    }
+}
+
+impl<'a, F: Flash> Device for Config<'a, F> {
+    fn info(&self) -> Info {
+        let version = |v: &str| v.parse().unwrap_or(0);
+        Info {
+            layers: LAYERS.len() as u8,
+            rows: ROWS as u8,
+            columns: COLUMNS as u8,
+            firmware: [
+                version(env!("CARGO_PKG_VERSION_MAJOR")),
+                version(env!("CARGO_PKG_VERSION_MINOR")),
+                version(env!("CARGO_PKG_VERSION_PATCH")),
+            ],
+        }
+    }
+
+    fn get_key(&self, position: KeyPosition) -> Result<[u8; 4], Status> {
+        let (layer, key) = key_position(position)?;
+        Ok(keymap::encode(self.keyboard.keymap()[layer][key]))
+    }
+
+    fn set_key(&mut self, position: KeyPosition, record: [u8; 4]) -> Result<(), Status> {
+        let (layer, key) = key_position(position)?;
+        let action = keymap::decode(record).ok_or(Status::InvalidArgument)?;
+        self.keyboard.keymap_mut()[layer][key] = action;
+        self.pending = Pending::SaveKeymap;
+        Ok(())
+    }
+
+    fn read_trackpoint_ram(&mut self, location: u8) -> Result<u8, Status> {
+        Ok(self.trackpoint.read_from_ram_location(location))
+    }
+
+    fn write_trackpoint_ram(&mut self, location: u8, value: u8) -> Result<(), Status> {
+        self.trackpoint.write_to_ram_location(location, value);
+        Ok(())
+    }
+
+    fn get_setting(&self, key: u8, buf: &mut [u8]) -> Result<usize, Status> {
+        let settings = self.settings.ok_or(Status::Failed)?;
+        // a value too long for `buf` is as good as missing to the host
+        settings.get(key, buf).ok_or(Status::InvalidArgument)
+    }
+
+    fn reboot(&mut self) -> Result<(), Status> {
+        self.pending = Pending::Reboot;
+        Ok(())
+    }
+
+    fn bootloader(&mut self) -> Result<(), Status> {
+        self.pending = Pending::Bootloader;
+        Ok(())
+    }
+}
+
+fn key_position(position: KeyPosition) -> Result<(usize, usize), Status> {
+    let layer = position.layer as usize;
+    let (row, column) = (position.row as usize, position.column as usize);
+    if layer < LAYERS.len() && row < ROWS && column < COLUMNS {
+        Ok((layer, key_index(row, column)))
+    } else {
+        Err(Status::InvalidArgument)
+    }
+}
@@ -0,0 +1,306 @@
+//! Line based diagnostics console on the USB serial port of `console`
+//! builds, see [`crate::usb`]. Type `help` for the commands.
+
+#![deny(unsafe_code)]
+
+use core::fmt::{self, Write};
+
+use bit_field::{BitArray, BitField};
+
+use crate::{
+    config::Pending,
+    keyboard::Keyboard,
+    keymatrix::{key_index, KeyState, COLUMNS, KEYBYTES, ROWS},
+    layout::LAYERS,
+    settings::{Flash, Settings, MAX_VALUE_LEN},
+    trackpoint::{DataReport, TrackPoint},
+};
+
+const LINE_LEN: usize = 80;
+/// Output waiting for the host, more is dropped.
+const OUTPUT_LEN: usize = 1024;
+const PROMPT: &str = "> ";
+
+const HELP: &str = "\
+matrix              keys down
+layers              active layers
+tp                  TrackPoint packet counts and last movement
+get KEY             stored setting
+set KEY BYTE...     store a setting and apply it
+remove KEY          remove a stored setting, back to the default
+log keys|tp|off     live log of key events or TrackPoint packets
+reboot              restart the keyboard
+bootloader          restart into the bootloader for dfu-util
+KEY 0: TrackPoint sensitivity, 1: tapping term ms (u16 LE), 2: debounce ms
+";
+
+/// What the console looks at, borrowed for each call.
+pub struct Target<'a, F: Flash> {
+    pub keyboard: &'a Keyboard,
+    pub trackpoint: &'a TrackPoint,
+    /// The latest TrackPoint data report.
+    pub tp_data: &'a DataReport,
+    pub settings: Option<&'a mut Settings<F>>,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Log {
+    Off,
+    Keys,
+    TrackPoint,
+}
+
+pub struct Console {
+    line: [u8; LINE_LEN],
+    line_len: usize,
+    /// Last input byte was a CR, a LF after it ends no line.
+    after_cr: bool,
+    output: [u8; OUTPUT_LEN],
+    output_len: usize,
+    log: Log,
+    /// Matrix state of the previous `watch`.
+    matrix: KeyState,
+    /// Last TrackPoint report that moved or changed buttons, and its time.
+    tp_last: (u8, i8, i8, u32),
+    /// Restart asked for, carried out by the caller.
+    pending: Pending,
+}
+
+impl Console {
+    pub const fn new() -> Console {
+        Console {
+            line: [0; LINE_LEN],
+            line_len: 0,
+            after_cr: false,
+            output: [0; OUTPUT_LEN],
+            output_len: 0,
+            log: Log::Off,
+            matrix: [0; KEYBYTES],
+            tp_last: (0, 0, 0, 0),
+            pending: Pending::None,
+        }
+    }
+
+    /// Echo `data` from the host and run each line it completes.
+    pub fn input<F: Flash>(&mut self, data: &[u8], target: &mut Target<F>) {
+        for &byte in data {
+            let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+            match byte {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    self.print("\n");
+                    let mut line = [0; LINE_LEN];
+                    let len = self.line_len;
+                    line[..len].copy_from_slice(&self.line[..len]);
+                    self.line_len = 0;
+                    // only ASCII is ever put into the line
+                    let line = core::str::from_utf8(&line[..len]).unwrap_or("");
+                    self.run(line, target);
+                    self.print(PROMPT);
+                }
+                // backspace, delete
+                0x08 | 0x7F if self.line_len > 0 => {
+                    self.line_len -= 1;
+                    self.print("\x08 \x08");
+                }
+                b' '..=b'~' if self.line_len < LINE_LEN => {
+                    self.line[self.line_len] = byte;
+                    self.line_len += 1;
+                    self.push(&[byte]);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Follow the keyboard and TrackPoint for the log, call every tick.
+    pub fn watch<F: Flash>(&mut self, target: &Target<F>) {
+        let now = target.keyboard.uptime();
+        let state = *target.keyboard.matrix_state();
+        if self.log == Log::Keys && state != self.matrix {
+            for (row, column) in (0..ROWS).flat_map(|row| (0..COLUMNS).map(move |c| (row, c))) {
+                let key = key_index(row, column);
+                let down = state.get_bit(key);
+                if down != self.matrix.get_bit(key) {
+                    let event = if down { "down" } else { "up" };
+                    self.printf(format_args!("{now} key {row},{column} {event}\n"));
+                }
+            }
+        }
+        self.matrix = state;
+
+        let data = target.tp_data;
+        if data.x != 0 || data.y != 0 || data.state & 7 != self.tp_last.0 & 7 {
+            self.tp_last = (data.state, data.x, data.y, now);
+            if self.log == Log::TrackPoint {
+                let (state, x, y) = (data.state, data.x, data.y);
+                self.printf(format_args!("{now} tp {state:02x} {x} {y}\n"));
+            }
+        }
+    }
+
+    /// What the last commands ask of the firmware, once their output is
+    /// sent.
+    pub fn take_pending(&mut self) -> Pending {
+        if self.output_len > 0 {
+            return Pending::None;
+        }
+        core::mem::replace(&mut self.pending, Pending::None)
+    }
+
+    /// Output waiting for the host.
+    pub fn output(&self) -> &[u8] {
+        &self.output[..self.output_len]
+    }
+
+    /// Drop the first `len` bytes of the output, sent to the host.
+    pub fn consume(&mut self, len: usize) {
+        self.output.copy_within(len..self.output_len, 0);
+        self.output_len -= len;
+    }
+
+    fn run<F: Flash>(&mut self, line: &str, target: &mut Target<F>) {
+        let mut words = line.split_ascii_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return,
+        };
+        match (command, words.next()) {
+            ("help", None) => self.print(HELP),
+            ("matrix", None) => {
+                let state = target.keyboard.matrix_state();
+                for row in 0..ROWS {
+                    self.printf(format_args!("{row} "));
+                    for column in 0..COLUMNS {
+                        let down = state.get_bit(key_index(row, column));
+                        self.print(if down { "#" } else { "." });
+                    }
+                    self.print("\n");
+                }
+            }
+            ("layers", None) => {
+                let layers = target.keyboard.active_layers();
+                for layer in (0..LAYERS.len()).filter(|i| layers.get_bit(*i)) {
+                    self.printf(format_args!("{layer} "));
+                }
+                self.print("\n");
+            }
+            ("tp", None) => {
+                let tp = target.trackpoint;
+                let (state, x, y, time) = self.tp_last;
+                self.printf(format_args!(
+                    "packets {}, errors {}\nlast {state:02x} {x} {y} at {time}\n",
+                    tp.packets, tp.errors
+                ));
+            }
+            ("get", Some(key)) => {
+                let (Some(key), Some(settings)) = (number(key), target.settings.as_ref()) else {
+                    return self.print("invalid key or no settings\n");
+                };
+                let mut value = [0; MAX_VALUE_LEN];
+                match settings.get(key, &mut value) {
+                    Some(len) => {
+                        for byte in &value[..len] {
+                            self.printf(format_args!("{byte:02x} "));
+                        }
+                        self.print("\n");
+                    }
+                    None => self.print("not set\n"),
+                }
+            }
+            ("set", Some(key)) => {
+                let mut value = [0; MAX_VALUE_LEN];
+                let mut len = 0;
+                for word in words {
+                    match (number(word), value.get_mut(len)) {
+                        (Some(byte), Some(slot)) => *slot = byte,
+                        _ => return self.print("invalid value\n"),
+                    }
+                    len += 1;
+                }
+                let (Some(key), Some(settings)) = (number(key), target.settings.as_mut()) else {
+                    return self.print("invalid key or no settings\n");
+                };
+                let result = settings.set(key, &value[..len]);
+                self.apply(result);
+            }
+            ("remove", Some(key)) => {
+                let (Some(key), Some(settings)) = (number(key), target.settings.as_mut()) else {
+                    return self.print("invalid key or no settings\n");
+                };
+                let result = settings.remove(key);
+                self.apply(result);
+            }
+            ("log", Some(what)) => {
+                self.log = match what {
+                    "keys" => Log::Keys,
+                    "tp" => Log::TrackPoint,
+                    "off" => Log::Off,
+                    _ => return self.print("log keys, tp or off\n"),
+                };
+            }
+            ("reboot", None) => self.pending = Pending::Reboot,
+            ("bootloader", None) => self.pending = Pending::Bootloader,
+            _ => self.print("unknown command, try help\n"),
+        }
+    }
+
+    fn result<E: fmt::Debug>(&mut self, result: Result<(), E>) {
+        match result {
+            Ok(()) => self.print("ok\n"),
+            Err(error) => self.printf(format_args!("failed: {error:?}\n")),
+        }
+    }
+
+    /// Report the result of a settings change, applied if it was stored.
+    fn apply<E: fmt::Debug>(&mut self, result: Result<(), E>) {
+        if result.is_ok() {
+            self.pending = Pending::ApplySettings;
+        }
+        self.result(result);
+    }
+
+    fn print(&mut self, text: &str) {
+        self.write_str(text).ok();
+    }
+
+    fn printf(&mut self, args: fmt::Arguments) {
+        self.write_fmt(args).ok();
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let end = self.output_len + bytes.len();
+        // output the host is too slow for is dropped, the keys go first
+        if let Some(free) = self.output.get_mut(self.output_len..end) {
+            free.copy_from_slice(bytes);
+            self.output_len = end;
+        }
+    }
+}
+
+impl Default for Console {
+    fn default() -> Console {
+        Console::new()
+    }
+}
+
+impl Write for Console {
+    /// Queue `text` with terminal line endings.
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for (i, part) in text.split('\n').enumerate() {
+            if i > 0 {
+                self.push(b"\r\n");
+            }
+            self.push(part.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+/// A byte in decimal or 0x hex.
+fn number(word: &str) -> Option<u8> {
+    match word.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => word.parse().ok(),
+    }
+}
@@ -0,0 +1,111 @@
+//! USB DFU runtime interface, so `dfu-util -e` and other DFU hosts can
+//! switch the keyboard into its bootloader. It needs no endpoints, only
+//! requests on the control pipe. Its functional descriptor is the one of
+//! the bootloader the build resets into, see [`crate::bootloader`].
+
+#![deny(unsafe_code)]
+
+use usb_device::{
+    class_prelude::*,
+    control::{Recipient, RequestType},
+};
+
+const CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+const SUBCLASS_DFU: u8 = 0x01;
+const PROTOCOL_RUNTIME: u8 = 0x01;
+const DESC_DFU_FUNCTIONAL: u8 = 0x21;
+
+const DFU_DETACH: u8 = 0x00;
+const DFU_GETSTATUS: u8 = 0x03;
+const DFU_GETSTATE: u8 = 0x05;
+
+/// bitCanDnload, bitCanUpload and bitWillDetach: the keyboard resets into
+/// the bootloader itself instead of waiting for a bus reset.
+const ATTRIBUTES: u8 = 0x0B;
+const DETACH_TIMEOUT: u16 = 1000;
+/// Control transfer size of the ROM bootloader.
+#[cfg(not(feature = "custom-bootloader"))]
+const TRANSFER_SIZE: u16 = 2048;
+/// DFU 1.1 with ST's DfuSe extensions, as the ROM bootloader.
+#[cfg(not(feature = "custom-bootloader"))]
+const DFU_VERSION: u16 = 0x011A;
+// what the bootloader in `bootloader/` tells hosts in DFU mode
+#[cfg(feature = "custom-bootloader")]
+use tpkb50_protocol::image::{DFU_TRANSFER_SIZE as TRANSFER_SIZE, DFU_VERSION};
+const STATE_APP_IDLE: u8 = 0;
+
+pub struct DfuRuntime {
+    interface: InterfaceNumber,
+    detach: bool,
+}
+
+impl DfuRuntime {
+    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>) -> DfuRuntime {
+        DfuRuntime {
+            interface: alloc.interface(),
+            detach: false,
+        }
+    }
+
+    /// Whether the host asked to detach since the last call.
+    pub fn take_detach_request(&mut self) -> bool {
+        core::mem::replace(&mut self.detach, false)
+    }
+
+    fn is_ours(&self, request: &control::Request) -> bool {
+        request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index == u8::from(self.interface) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for DfuRuntime {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(
+            self.interface,
+            CLASS_APPLICATION_SPECIFIC,
+            SUBCLASS_DFU,
+            PROTOCOL_RUNTIME,
+        )?;
+        let [timeout_low, timeout_high] = DETACH_TIMEOUT.to_le_bytes();
+        let [size_low, size_high] = TRANSFER_SIZE.to_le_bytes();
+        let [version_low, version_high] = DFU_VERSION.to_le_bytes();
+        writer.write(
+            DESC_DFU_FUNCTIONAL,
+            &[
+                ATTRIBUTES,
+                timeout_low,
+                timeout_high,
+                size_low,
+                size_high,
+                version_low,
+                version_high,
+            ],
+        )
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        if !self.is_ours(xfer.request()) {
+            return;
+        }
+        match xfer.request().request {
+            // status OK, no poll timeout, appIDLE, no string
+            DFU_GETSTATUS => xfer.accept_with(&[0, 0, 0, 0, STATE_APP_IDLE, 0]).ok(),
+            DFU_GETSTATE => xfer.accept_with(&[STATE_APP_IDLE]).ok(),
+            _ => xfer.reject().ok(),
+        };
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        if !self.is_ours(xfer.request()) {
+            return;
+        }
+        match xfer.request().request {
+            DFU_DETACH => {
+                self.detach = true;
+                xfer.accept().ok()
+            }
+            _ => xfer.reject().ok(),
+        };
+    }
+}
@@ -0,0 +1,64 @@
+//! Internal flash of the STM32F401 as the backing of [`Settings`], which
+//! also writes the keymap sector.
+//!
+//! [`Settings`]: crate::settings::Settings
+
+#![deny(unsafe_code)]
+
+use crate::{
+    keymap::{KEYMAP_OFFSET, KEYMAP_SECTOR},
+    settings::{Error, Flash},
+};
+use hal::{flash::FlashExt, pac::FLASH};
+use stm32f4xx_hal as hal;
+
+/// Sectors 1 and 2, kept free of code by `_stext` in `memory.x`.
+const SECTORS: [u8; 2] = [1, 2];
+const OFFSETS: [usize; 2] = [0x4000, 0x8000];
+const SECTOR_SIZE: usize = 0x4000;
+
+pub struct InternalFlash {
+    flash: FLASH,
+}
+
+impl InternalFlash {
+    pub fn new(flash: FLASH) -> Self {
+        Self { flash }
+    }
+
+    /// Replace the image in the keymap sector, see [`crate::keymap`].
+    ///
+    /// Erasing the 128K sector stalls the CPU for a second or two.
+    pub fn store_keymap(&mut self, image: &[u8]) -> Result<(), Error> {
+        let mut flash = self.flash.unlocked();
+        flash.erase(KEYMAP_SECTOR).map_err(|_| Error::Flash)?;
+        flash
+            .program(KEYMAP_OFFSET, image.iter())
+            .map_err(|_| Error::Flash)
+    }
+}
+
+impl Flash for InternalFlash {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn read(&self, sector: usize, offset: usize, buf: &mut [u8]) {
+        let start = OFFSETS[sector] + offset;
+        buf.copy_from_slice(&self.flash.read()[start..start + buf.len()]);
+    }
+
+    fn write(&mut self, sector: usize, offset: usize, data: &[u8]) -> Result<(), Error> {
+        self.flash
+            .unlocked()
+            .program(OFFSETS[sector] + offset, data.iter())
+            .map_err(|_| Error::Flash)
+    }
+
+    fn erase(&mut self, sector: usize) -> Result<(), Error> {
+        self.flash
+            .unlocked()
+            .erase(SECTORS[sector])
+            .map_err(|_| Error::Flash)
+    }
+}
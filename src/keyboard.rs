@@ -1,35 +1,306 @@
 //! Get a lot help from https://github.com/ah-/anne-key.
 //! That keyboard framework looks great, just like tmk.
-//! Removed bt, led, add LayerTapKey, ShiftKey, Mouse 7 btns.
+//! Removed bt, led, add LayerTapKey, ModTap, ModifiedKey, Mouse 7 btns.
 
 #![deny(warnings)]
 #![deny(unsafe_code)]
 
 use crate::{
     action::Action,
-    keycodes::KeyCode,
+    keycodes::{KeyCode, Modifiers},
+    keymap::Keymap,
     keymatrix::{KeyState, COLUMNS, KEYBYTES, ROWS},
-    layout::LAYERS,
+    layout::{
+        LayerNumber, ALT_REPEAT, AUTO_SHIFT, CAPS_WORD_CONTINUE, CAPS_WORD_TIMEOUT, COMBOS,
+        CONDITIONAL_LAYERS, KEY_OVERRIDES, LAYERS, LEADER_SEQUENCES, LEADER_TIMEOUT,
+        ONE_SHOT_TIMEOUT, TAP_HOLD,
+    },
+    macros::{MacroBuffer, MacroEvent, MacroPlayer},
 };
 use bit_field::{BitArray, BitField};
 use usbd_hid::descriptor::KeyboardReport;
 
+const KEYS: usize = COLUMNS * ROWS;
+
+/// Key events kept back while a tap/hold key is undecided.
+const TAP_HOLD_BUFFER_LEN: usize = 8;
+
+/// Most keys in a combo, also the presses kept back while one may form.
+const COMBO_BUFFER_LEN: usize = 8;
+
+/// Longest leader sequence.
+const LEADER_LEN: usize = 8;
+
+/// Reports a dynamic macro can record.
+const DYNAMIC_MACRO_LEN: usize = 128;
+
+/// Most reports one key event can queue: each event it lets through, itself
+/// and those kept back for combos and tap/hold, sends up to 2 deciding a
+/// tap/hold key, 2 for the auto-shift tap it ends and 3 for a release with
+/// a retro tap.
+const EVENT_REPORTS: usize = 7 * (1 + COMBO_BUFFER_LEN + TAP_HOLD_BUFFER_LEN);
+
+/// Reports waiting for the USB endpoint, one goes out per host poll.
+const REPORT_QUEUE_LEN: usize = 128;
+
+const _: () = assert!(REPORT_QUEUE_LEN >= EVENT_REPORTS);
+
+/// How `LayerTapKey` and `ModTap` decide between tap and hold.
+///
+/// All times are in milliseconds, i.e. calls to [`Keyboard::tick`].
+#[derive(Copy, Clone)]
+pub struct TapHoldConfig {
+    /// Held longer than this, the key becomes a hold.
+    pub tapping_term: u16,
+    /// `tapping_term` overrides by key index, for slow fingers.
+    pub tapping_terms: &'static [(usize, u16)],
+    /// Pressing the key again within this time after a tap sends the tap
+    /// key for as long as it is held (key repeat). 0 disables.
+    pub quick_tap_term: u16,
+    /// Pressing the key within this time after a normal key press is a
+    /// tap without waiting, so fast typing never misfires. 0 disables.
+    pub require_prior_idle: u16,
+    /// Releasing a hold without pressing any other key sends the tap.
+    pub retro_tapping: bool,
+    /// Another key pressed and released within the hold makes it a hold
+    /// before `tapping_term`.
+    pub permissive_hold: bool,
+}
+
+impl TapHoldConfig {
+    fn tapping_term(&self, key: usize) -> u32 {
+        self.tapping_terms
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map_or(self.tapping_term, |(_, term)| *term) as u32
+    }
+}
+
+/// Letters and digits held longer than `timeout` ms are sent shifted.
+#[derive(Copy, Clone)]
+pub struct AutoShiftConfig {
+    pub enabled: bool,
+    pub timeout: u16,
+    /// Key indices never auto-shifted.
+    pub excluded: &'static [usize],
+}
+
+impl AutoShiftConfig {
+    fn applies(&self, key: usize, code: KeyCode) -> bool {
+        let shiftable = (code >= KeyCode::A && code <= KeyCode::Z)
+            || (code >= KeyCode::N1 && code <= KeyCode::N0);
+        self.enabled && shiftable && !self.excluded.contains(&key)
+    }
+}
+
+/// Turns on `then` whenever all layers of `if_all` are active, e.g. an
+/// adjust layer reached by holding both thumb layer keys.
+pub struct ConditionalLayer {
+    pub if_all: &'static [LayerNumber],
+    pub then: LayerNumber,
+}
+
+impl ConditionalLayer {
+    fn apply(&self, layers: u32) -> u32 {
+        let mask = self
+            .if_all
+            .iter()
+            .fold(0u32, |mask, layer| mask | 1 << *layer as usize);
+        if layers & mask == mask {
+            layers | 1 << self.then as usize
+        } else {
+            layers
+        }
+    }
+}
+
+/// Keys pressed together within `timeout` that act as `action` instead,
+/// e.g. J+K for Escape.
+pub struct Combo {
+    /// Key indices, see [`keymatrix::key_index`]. The first one carries
+    /// the action while the combo is held.
+    pub keys: &'static [usize],
+    pub action: Action,
+    /// Longest time in ms from the first press to the last.
+    pub timeout: u16,
+    /// Keys must be pressed in the order listed.
+    pub in_order: bool,
+}
+
+impl Combo {
+    /// Whether `events` could still grow into this combo.
+    fn is_candidate(&self, events: &[KeyEvent]) -> bool {
+        events.len() <= self.keys.len()
+            && events.iter().enumerate().all(|(i, event)| {
+                if self.in_order {
+                    self.keys[i] == event.key
+                } else {
+                    self.keys.contains(&event.key)
+                }
+            })
+    }
+}
+
+// Held combo members are a u8 bit-field per combo, combos a u32 one
+const _: () = {
+    assert!(COMBOS.len() <= 32);
+    let mut i = 0;
+    while i < COMBOS.len() {
+        assert!(COMBOS[i].keys.len() <= COMBO_BUFFER_LEN);
+        i += 1;
+    }
+};
+
+/// While `mods` are held, `key` sends `replacement` (a `Key` or
+/// `ModifiedKey`) with `mods` taken off, e.g. Shift+BSpace for Delete.
+///
+/// Each modifier in `mods` matches either side, `LSHIFT` also means `RSHIFT`.
+pub struct KeyOverride {
+    pub mods: Modifiers,
+    pub key: KeyCode,
+    pub replacement: Action,
+}
+
+impl KeyOverride {
+    fn matches(&self, held: Modifiers, code: u8) -> bool {
+        let sideless = |mods: Modifiers| (mods.bits() | mods.bits() >> 4) & 0x0F;
+        self.key as u8 == code && sideless(held) & sideless(self.mods) == sideless(self.mods)
+    }
+}
+
+/// Keys tapped after `Leader` that trigger `action`, e.g. G, C.
+pub struct LeaderSequence {
+    pub keys: &'static [KeyCode],
+    pub action: Action,
+}
+
+const _: () = {
+    let mut i = 0;
+    while i < LEADER_SEQUENCES.len() {
+        assert!(LEADER_SEQUENCES[i].keys.len() <= LEADER_LEN);
+        i += 1;
+    }
+};
+
+#[derive(Copy, Clone)]
+struct KeyEvent {
+    key: usize,
+    pressed: bool,
+    time: u32,
+    /// Action replacing the layout one, for combos.
+    action: Option<Action>,
+}
+
+enum Decision {
+    Tap,
+    Hold,
+}
+
 pub struct Keyboard {
+    /// Layouts in use, `LAYERS` unless a stored keymap was loaded.
+    keymap: Keymap,
     layers: Layers,
     previous_state: KeyState,
-    // layer_tap_key holding counter
-    holding_counter: u8,
+    /// Pressed keys as seen by the actions, lags behind `previous_state`
+    /// while a tap/hold key is undecided.
+    state: KeyState,
+    /// Action of each pressed key as looked up at press time, so its
+    /// release applies to the same action even if the layers changed.
+    actions: [Action; KEYS],
+    /// Milliseconds since start, counted by `tick`.
+    now: u32,
+    combos: Combos,
+    tap_hold: TapHold,
+    /// Modifiers of the last pressed `ModifiedKey` and its key index, only
+    /// sent until any other key is pressed so they never leak onto it.
+    weak_mods: Option<(usize, Modifiers)>,
+    one_shot: OneShotMods,
+    leader: Leader,
+    /// Caps Word is on since the last key at this time, letters are
+    /// shifted until a word break or `CAPS_WORD_TIMEOUT`.
+    caps_word: Option<u32>,
+    auto_shift: AutoShift,
+    /// A `Bootloader` key was pressed.
+    bootloader: bool,
+    /// Key last added to a report and the modifiers sent with it.
+    last_key: Option<(Modifiers, KeyCode)>,
+    macros: MacroPlayer,
+    dynamic_macro: DynamicMacro,
+    reports: ReportQueue,
 }
 
 impl Keyboard {
     pub const fn new() -> Keyboard {
         Keyboard {
+            keymap: LAYERS,
             layers: Layers::new(),
             previous_state: [0; KEYBYTES],
-            holding_counter: 0,
+            state: [0; KEYBYTES],
+            actions: [Action::Nop; KEYS],
+            now: 0,
+            combos: Combos::new(),
+            tap_hold: TapHold::new(TAP_HOLD),
+            weak_mods: None,
+            one_shot: OneShotMods::new(),
+            leader: Leader::new(LEADER_SEQUENCES),
+            caps_word: None,
+            auto_shift: AutoShift::new(AUTO_SHIFT),
+            bootloader: false,
+            last_key: None,
+            macros: MacroPlayer::new(),
+            dynamic_macro: DynamicMacro::new(),
+            reports: ReportQueue::new(),
         }
     }
 
+    /// Use `keymap` in place of the compiled-in `LAYERS`.
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        self.keymap = keymap;
+    }
+
+    pub fn keymap(&self) -> &Keymap {
+        &self.keymap
+    }
+
+    pub fn keymap_mut(&mut self) -> &mut Keymap {
+        &mut self.keymap
+    }
+
+    /// Macros in the format of [`crate::macros`].
+    pub fn macros(&self) -> &MacroBuffer {
+        &self.macros.buffer
+    }
+
+    pub fn macros_mut(&mut self) -> &mut MacroBuffer {
+        &mut self.macros.buffer
+    }
+
+    /// Keys down as far as [`Keyboard::tick`] has taken them in, changes
+    /// wait while the report queue is nearly full.
+    pub fn matrix_state(&self) -> &KeyState {
+        &self.previous_state
+    }
+
+    /// Bit-field of the active layers, indexed by position in `LAYERS`.
+    pub fn active_layers(&self) -> u32 {
+        self.layers.current
+    }
+
+    /// Whether a `Bootloader` key was pressed since the last call.
+    pub fn take_bootloader_request(&mut self) -> bool {
+        core::mem::replace(&mut self.bootloader, false)
+    }
+
+    /// Milliseconds since start.
+    pub fn uptime(&self) -> u32 {
+        self.now
+    }
+
+    /// Override `TAP_HOLD.tapping_term`, e.g. from the stored settings.
+    pub fn set_tapping_term(&mut self, ms: u16) {
+        self.tap_hold.config.tapping_term = ms;
+    }
+
     /// Get the action for `key`.
 
     /// The top non-Transparent action at index `key` amongst the
@@ -38,7 +309,7 @@ impl Keyboard {
         let mut action = Action::Transparent;
         for i in (0..LAYERS.len()).rev() {
             if self.layers.current.get_bit(i) {
-                action = LAYERS[i][key];
+                action = self.keymap[i][key];
             }
             if action != Action::Transparent {
                 break;
@@ -47,50 +318,783 @@ impl Keyboard {
         action
     }
 
-    pub fn gen_report(&mut self, state: &KeyState) -> Option<KeyboardReport> {
-        if &self.previous_state != state {
-            let mut hid = HidProcessor::default();
+    /// The action pressed `key` performs, with a decided tap/hold key
+    /// resolved to its tap. A hold is left as is for the processors.
+    fn key_action(&self, key: usize) -> Action {
+        match self.actions[key] {
+            Action::LayerTapKey(_, kc) | Action::ModTap(_, kc)
+                if self.tap_hold.tapping.get_bit(key) =>
+            {
+                kc.to_action()
+            }
+            action => action,
+        }
+    }
+
+    /// Scan step, must be called every millisecond with the matrix state.
+    pub fn tick(&mut self, state: &KeyState) {
+        self.now = self.now.wrapping_add(1);
+        if &self.previous_state != state {
+            for key in 0..KEYS {
+                let pressed = state.get_bit(key);
+                if self.previous_state.get_bit(key) != pressed {
+                    // left for a later tick until the host catches up, so
+                    // no change gets lost in a full queue
+                    if self.reports.free() < EVENT_REPORTS {
+                        break;
+                    }
+                    self.combo_event(KeyEvent {
+                        key,
+                        pressed,
+                        time: self.now,
+                        action: None,
+                    });
+                    self.previous_state.set_bit(key, pressed);
+                }
+            }
+        }
+        // timeouts replay kept back events too, they wait the same way
+        let room = self.reports.free() >= EVENT_REPORTS;
+        if room && self.combos.len > 0 {
+            self.check_combos(self.now);
+        }
+        if room {
+            self.check_tapping_term(self.now);
+        }
+        if let (true, Some((event, code))) = (room, self.auto_shift.held) {
+            if self.now.wrapping_sub(event.time) >= self.auto_shift.config.timeout as u32 {
+                self.auto_shift.held = None;
+                self.tap(code, Modifiers::LSHIFT);
+            }
+        }
+        self.one_shot.expire(self.now);
+        if let Some(last) = self.caps_word {
+            if self.now.wrapping_sub(last) >= CAPS_WORD_TIMEOUT as u32 {
+                self.caps_word = None;
+            }
+        }
+        if room
+            && self.leader.active
+            && self.now.wrapping_sub(self.leader.last) >= LEADER_TIMEOUT as u32
+        {
+            self.leader.active = false;
+            if let (Some(action), _) = self.leader.lookup() {
+                self.tap_action(action);
+            }
+        }
+        self.play_macro();
+        self.play_dynamic_macro();
+    }
+
+    /// Queue recorded reports while there is room besides that for a key
+    /// event, then the live state.
+    fn play_dynamic_macro(&mut self) {
+        if !self.dynamic_macro.is_playing() {
+            return;
+        }
+        while self.reports.free() > EVENT_REPORTS {
+            match self.dynamic_macro.next() {
+                Some(report) => {
+                    self.reports.push(report);
+                }
+                None => {
+                    self.send_report(None);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Queue macro reports while there is room for a tap.
+    fn play_macro(&mut self) {
+        while self.reports.free() >= 2 {
+            match self.macros.next(self.now, self.reports.len == 0) {
+                Some(MacroEvent::Held) => self.send_report(None),
+                Some(MacroEvent::Tap(action)) => self.tap_action(action),
+                None => break,
+            }
+        }
+    }
+
+    /// Next report for the host, call [`Keyboard::report_sent`] once the
+    /// endpoint has taken it.
+    pub fn report(&self) -> Option<KeyboardReport> {
+        self.reports.front().map(|report| report.to_hid())
+    }
+
+    pub fn report_sent(&mut self) {
+        self.reports.pop();
+    }
+
+    /// First stage for key events, detects combos before any tap/hold.
+    fn combo_event(&mut self, event: KeyEvent) {
+        if let (false, Some((i, pos))) = (event.pressed, self.combos.held_member(event.key)) {
+            // the combo action goes off with the first member released
+            self.combos.held[i].set_bit(pos, false);
+            if self.combos.pressed.get_bit(i) {
+                self.combos.pressed.set_bit(i, false);
+                self.handle_event(KeyEvent {
+                    key: COMBOS[i].keys[0],
+                    pressed: false,
+                    time: event.time,
+                    action: None,
+                });
+            }
+            return;
+        }
+        if event.pressed && COMBOS.iter().any(|combo| combo.keys.contains(&event.key)) {
+            let events = &self.combos.events[..self.combos.len];
+            if !COMBOS.iter().any(|combo| combo.is_candidate(events)) {
+                self.flush_combos();
+            }
+            self.combos.events[self.combos.len] = event;
+            self.combos.len += 1;
+            self.check_combos(event.time);
+            return;
+        }
+        self.flush_combos();
+        self.handle_event(event);
+    }
+
+    /// Fire a completed combo, keep waiting, or let the kept back presses
+    /// through as normal keys.
+    fn check_combos(&mut self, time: u32) {
+        let events = &self.combos.events[..self.combos.len];
+        let (first, last) = (events[0].time, events[events.len() - 1].time);
+        let mut complete = None;
+        let mut waiting = false;
+        for (i, combo) in COMBOS.iter().enumerate() {
+            if !combo.is_candidate(events) {
+                continue;
+            }
+            let timeout = combo.timeout as u32;
+            if combo.keys.len() == events.len() {
+                if last.wrapping_sub(first) < timeout {
+                    complete = Some(i);
+                }
+            } else if time.wrapping_sub(first) < timeout {
+                waiting = true;
+            }
+        }
+        match (complete, waiting) {
+            (_, true) => {}
+            (Some(i), false) => {
+                let combo = &COMBOS[i];
+                self.combos.len = 0;
+                self.combos.held[i] = u8::MAX >> (COMBO_BUFFER_LEN - combo.keys.len());
+                self.combos.pressed.set_bit(i, true);
+                self.handle_event(KeyEvent {
+                    key: combo.keys[0],
+                    pressed: true,
+                    time,
+                    action: Some(combo.action),
+                });
+            }
+            (None, false) => self.flush_combos(),
+        }
+    }
+
+    fn flush_combos(&mut self) {
+        let (events, len) = (self.combos.events, self.combos.len);
+        self.combos.len = 0;
+        for event in &events[..len] {
+            self.handle_event(*event);
+        }
+    }
+
+    fn check_tapping_term(&mut self, time: u32) {
+        if let Some((pending, _)) = self.tap_hold.pending {
+            let term = self.tap_hold.config.tapping_term(pending.key);
+            if time.wrapping_sub(pending.time) >= term {
+                self.decide(Decision::Hold);
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: KeyEvent) {
+        self.check_tapping_term(event.time);
+        match self.tap_hold.pending {
+            Some((pending, _)) if pending.key == event.key && !event.pressed => {
+                self.decide(Decision::Tap)
+            }
+            Some(_) => {
+                let nested_tap = !event.pressed && self.tap_hold.is_buffered(event.key);
+                if !self.tap_hold.buffer(event) {
+                    self.decide(Decision::Hold);
+                    self.handle_event(event);
+                } else if nested_tap && self.tap_hold.config.permissive_hold {
+                    self.decide(Decision::Hold);
+                }
+            }
+            None => self.process_event(event),
+        }
+    }
+
+    /// Settle the pending tap/hold key and replay the events held back.
+    fn decide(&mut self, decision: Decision) {
+        if let Some((pending, action)) = self.tap_hold.pending.take() {
+            match (decision, action) {
+                (Decision::Tap, Action::LayerTapKey(_, kc) | Action::ModTap(_, kc)) => {
+                    self.tap(kc, Modifiers::empty());
+                    self.tap_hold.last_tap = Some((pending.key, self.now));
+                }
+                (Decision::Hold, _) => {
+                    self.tap_hold.holding.set_bit(pending.key, true);
+                    self.tap_hold.retro = Some(pending.key);
+                    self.press(pending.key, action);
+                }
+                _ => {}
+            }
+            let (events, len) = (self.tap_hold.events, self.tap_hold.len);
+            self.tap_hold.len = 0;
+            for event in &events[..len] {
+                self.handle_event(*event);
+            }
+        }
+    }
+
+    fn process_event(&mut self, event: KeyEvent) {
+        // an auto-shift key let go or interrupted in time is a plain tap
+        if let Some((held, code)) = self.auto_shift.held {
+            if event.pressed || event.key == held.key {
+                self.auto_shift.held = None;
+                self.tap(code, Modifiers::empty());
+            }
+        }
+        if !event.pressed {
+            self.release(event.key);
+            return;
+        }
+        self.tap_hold.retro = None;
+        match event.action.unwrap_or_else(|| self.get_action(event.key)) {
+            action @ (Action::LayerTapKey(..) | Action::ModTap(..)) => {
+                if self.tap_hold.is_quick_tap(event) || self.tap_hold.is_typing(event.time) {
+                    self.tap_hold.tapping.set_bit(event.key, true);
+                    self.press(event.key, action);
+                } else {
+                    self.tap_hold.pending = Some((event, action));
+                }
+            }
+            Action::Key(code)
+                if self.auto_shift.config.applies(event.key, code)
+                    && self.held_mods().is_empty() =>
+            {
+                self.tap_hold.last_press = Some(event.time);
+                self.auto_shift.held = Some((event, code));
+            }
+            action => {
+                self.tap_hold.last_press = Some(event.time);
+                self.press(event.key, action);
+            }
+        }
+    }
+
+    fn press(&mut self, key: usize, action: Action) {
+        // held like the repeated key, so the host repeats it too
+        self.actions[key] = match action {
+            Action::Repeat => self.repeat_action(),
+            Action::AltRepeat => self.alt_repeat_action(),
+            action => action,
+        };
+        let action = self.key_action(key);
+        if self.lead(action) {
+            self.actions[key] = Action::Nop;
+            self.state.set_bit(key, true);
+            return;
+        }
+        self.state.set_bit(key, true);
+        self.weak_mods = match action {
+            Action::ModifiedKey(mods, _) => Some((key, mods)),
+            _ => None,
+        };
+        let caps = match action {
+            Action::Key(code) => self.caps_word_mods(code, Modifiers::empty()),
+            Action::ModifiedKey(mods, code) => self.caps_word_mods(code, mods),
+            _ => Modifiers::empty(),
+        };
+        self.add_weak_mods(key, caps);
+        match action {
+            Action::OneShotMod(mods) => self.one_shot.press(mods),
+            Action::Leader => self.leader.start(self.now),
+            Action::Macro(_)
+            | Action::DynamicMacroRecord
+            | Action::DynamicMacroStop
+            | Action::DynamicMacroPlay
+            | Action::CapsWord
+            | Action::Bootloader => self.trigger(action),
+            Action::Key(KeyCode::Escape) => self.one_shot.cancel(),
+            Action::Key(code) if code.is_modifier() => {}
+            // keys that send something take the one-shot modifiers along
+            Action::Key(_) | Action::ModifiedKey(..) | Action::Mouse(_) => {
+                let mods = self.one_shot.take();
+                self.add_weak_mods(key, mods);
+            }
+            _ => {}
+        }
+        self.layers.process(&action, true, true);
+        self.layers.finish();
+        self.send_report(None);
+    }
+
+    fn repeat_action(&self) -> Action {
+        match self.last_key {
+            Some((mods, code)) if mods.is_empty() => code.to_action(),
+            Some((mods, code)) => code.with(mods),
+            None => Action::Nop,
+        }
+    }
+
+    /// The other key of the `ALT_REPEAT` pair with the last key. Pairs
+    /// match modifiers on either side, the other key keeps the side sent.
+    fn alt_repeat_action(&self) -> Action {
+        let Some((mods, code)) = self.last_key else {
+            return Action::Nop;
+        };
+        let bits = mods.bits();
+        let sideless = Modifiers::from_bits_retain((bits | bits >> 4) & 0x0f);
+        let last = code.with(sideless);
+        let normalized = |action: Action| action.with(Modifiers::empty());
+        for (a, b) in ALT_REPEAT {
+            let other = if normalized(*a) == last {
+                *b
+            } else if normalized(*b) == last {
+                *a
+            } else {
+                continue;
+            };
+            return match other {
+                Action::ModifiedKey(pair, code) if pair == sideless => code.with(mods),
+                other => other,
+            };
+        }
+        Action::Nop
+    }
+
+    fn add_weak_mods(&mut self, key: usize, mods: Modifiers) {
+        if !mods.is_empty() {
+            let weak = self.weak_mods.map_or(mods, |(_, weak)| weak | mods);
+            self.weak_mods = Some((key, weak));
+        }
+    }
+
+    /// Shift for `code` while Caps Word is on, turning it off on a word
+    /// break. Letters are shifted, `CAPS_WORD_CONTINUE` keys are kept as is.
+    fn caps_word_mods(&mut self, code: KeyCode, mods: Modifiers) -> Modifiers {
+        if self.caps_word.is_none() || code.is_modifier() {
+            return Modifiers::empty();
+        }
+        let shift = Modifiers::LSHIFT | Modifiers::RSHIFT;
+        // anything with ctrl, alt or meta is a shortcut, not part of a word
+        if mods.difference(shift).is_empty() {
+            if code >= KeyCode::A && code <= KeyCode::Z {
+                self.caps_word = Some(self.now);
+                return Modifiers::LSHIFT;
+            }
+            if CAPS_WORD_CONTINUE.contains(&code) {
+                self.caps_word = Some(self.now);
+                return Modifiers::empty();
+            }
+        }
+        self.caps_word = None;
+        Modifiers::empty()
+    }
+
+    fn release(&mut self, key: usize) {
+        if !self.state.get_bit(key) {
+            return;
+        }
+        let action = self.key_action(key);
+        self.state.set_bit(key, false);
+        if matches!(self.weak_mods, Some((weak_key, _)) if weak_key == key) {
+            self.weak_mods = None;
+        }
+        if let Action::OneShotMod(mods) = action {
+            self.one_shot.release(mods, self.now);
+        }
+        self.layers.process(&action, false, true);
+        self.layers.finish();
+        self.send_report(None);
+
+        if self.tap_hold.tapping.get_bit(key) {
+            self.tap_hold.tapping.set_bit(key, false);
+            self.tap_hold.last_tap = Some((key, self.now));
+        } else if self.tap_hold.holding.get_bit(key) {
+            self.tap_hold.holding.set_bit(key, false);
+            if self.tap_hold.retro.take() == Some(key) && self.tap_hold.config.retro_tapping {
+                if let Action::LayerTapKey(_, kc) | Action::ModTap(_, kc) = action {
+                    self.tap(kc, Modifiers::empty());
+                }
+            }
+        }
+    }
+
+    /// Press and release `code` with `mods` on top of the held keys.
+    fn tap(&mut self, code: KeyCode, mods: Modifiers) {
+        if self.lead(code.to_action()) {
+            return;
+        }
+        // another key, like a press
+        self.weak_mods = None;
+        let mods = mods | self.one_shot.take() | self.caps_word_mods(code, mods);
+        let action = if mods.is_empty() {
+            code.to_action()
+        } else {
+            code.with(mods)
+        };
+        self.send_report(Some(action));
+        self.send_report(None);
+    }
+
+    /// Start the macro and mode actions, which only do something on press.
+    fn trigger(&mut self, action: Action) {
+        match action {
+            Action::Macro(index) => self.macros.play(index),
+            Action::DynamicMacroRecord => self.dynamic_macro.record(),
+            Action::DynamicMacroStop => self.dynamic_macro.stop(),
+            Action::DynamicMacroPlay => self.dynamic_macro.play(),
+            Action::CapsWord => {
+                self.caps_word = match self.caps_word {
+                    Some(_) => None,
+                    None => Some(self.now),
+                }
+            }
+            Action::Bootloader => self.bootloader = true,
+            _ => {}
+        }
+    }
+
+    /// Press and release any `action` on top of the held keys.
+    fn tap_action(&mut self, action: Action) {
+        if let Action::Macro(_)
+        | Action::DynamicMacroRecord
+        | Action::DynamicMacroStop
+        | Action::DynamicMacroPlay
+        | Action::CapsWord
+        | Action::Bootloader = action
+        {
+            self.trigger(action);
+            return;
+        }
+        self.layers.process(&action, true, true);
+        self.layers.finish();
+        self.send_report(Some(action));
+        self.layers.process(&action, false, true);
+        self.layers.finish();
+        self.send_report(None);
+    }
+
+    /// Feed a key to an active leader sequence, true if it took the key.
+    fn lead(&mut self, action: Action) -> bool {
+        let code = match action {
+            Action::Key(code) | Action::ModifiedKey(_, code) if !code.is_modifier() => code,
+            _ => return false,
+        };
+        if !self.leader.active {
+            return false;
+        }
+        self.leader.push(code, self.now);
+        if let (found, false) = self.leader.lookup() {
+            self.leader.active = false;
+            if let Some(action) = found {
+                self.tap_action(action);
+            }
+        }
+        true
+    }
+
+    /// Keys held down, by the matrix and the macro player.
+    fn held_keys(&self) -> HidProcessor {
+        let mut hid = HidProcessor::default();
+        for key in 0..KEYS {
+            if self.state.get_bit(key) {
+                hid.process(&self.key_action(key), true, false);
+            }
+        }
+        for code in self.macros.pressed {
+            hid.process(&code.to_action(), true, false);
+        }
+        hid
+    }
+
+    /// Modifiers a key pressed now goes out with, weak ones left out as
+    /// they never carry over to another key.
+    fn held_mods(&self) -> Modifiers {
+        let held = Modifiers::from_bits_retain(self.held_keys().report.modifier);
+        held | self.one_shot.mods() | self.one_shot.armed
+    }
+
+    fn send_report(&mut self, extra: Option<Action>) {
+        let mut hid = self.held_keys();
+        if let Some((_, mods)) = self.weak_mods {
+            hid.report.modifier |= mods.bits();
+        }
+        hid.report.modifier |= self.one_shot.mods().bits();
+        if let Some(action) = extra {
+            hid.process(&action, true, true);
+            if let Action::ModifiedKey(mods, _) = action {
+                hid.report.modifier |= mods.bits();
+            }
+        }
+        hid.finish();
+        let previous = self.reports.last;
+        for (i, code) in hid.report.keycodes.iter().enumerate() {
+            if *code != 0 && !previous.keycodes.contains(code) {
+                self.last_key = Some((
+                    Modifiers::from_bits_retain(hid.report.modifier),
+                    hid.codes[i],
+                ));
+            }
+        }
+        if self.reports.push(hid.report) {
+            self.dynamic_macro.push(hid.report);
+        }
+    }
+}
+
+impl KeyEvent {
+    const NONE: KeyEvent = KeyEvent {
+        key: 0,
+        pressed: false,
+        time: 0,
+        action: None,
+    };
+}
+
+/// State of the [`Combo`] detection.
+struct Combos {
+    /// Presses of combo keys that may still become a combo.
+    events: [KeyEvent; COMBO_BUFFER_LEN],
+    len: usize,
+    /// Members still held of each fired combo, by position in `keys`.
+    held: [u8; 32],
+    /// Fired combos whose action is still pressed.
+    pressed: u32,
+}
+
+impl Combos {
+    const fn new() -> Combos {
+        Combos {
+            events: [KeyEvent::NONE; COMBO_BUFFER_LEN],
+            len: 0,
+            held: [0; 32],
+            pressed: 0,
+        }
+    }
+
+    /// Fired combo `key` is held for, with its position in the combo keys.
+    fn held_member(&self, key: usize) -> Option<(usize, usize)> {
+        COMBOS.iter().enumerate().find_map(|(i, combo)| {
+            combo
+                .keys
+                .iter()
+                .position(|k| *k == key)
+                .filter(|pos| self.held[i].get_bit(*pos))
+                .map(|pos| (i, pos))
+        })
+    }
+}
+
+/// State of the tap/hold decision shared by `LayerTapKey` and `ModTap`.
+struct TapHold {
+    config: TapHoldConfig,
+    /// Undecided tap/hold key press with its action at press time.
+    pending: Option<(KeyEvent, Action)>,
+    /// Events after `pending`, replayed once it is decided.
+    events: [KeyEvent; TAP_HOLD_BUFFER_LEN],
+    len: usize,
+    /// Decided tap/hold keys sending their tap key.
+    tapping: KeyState,
+    /// Decided tap/hold keys acting as their hold.
+    holding: KeyState,
+    /// Last tapped tap/hold key and its release time, for quick tap.
+    last_tap: Option<(usize, u32)>,
+    /// Last press time of a normal key, for require prior idle.
+    last_press: Option<u32>,
+    /// Hold without any other key pressed since, for retro tapping.
+    retro: Option<usize>,
+}
+
+impl TapHold {
+    const fn new(config: TapHoldConfig) -> TapHold {
+        TapHold {
+            config,
+            pending: None,
+            events: [KeyEvent::NONE; TAP_HOLD_BUFFER_LEN],
+            len: 0,
+            tapping: [0; KEYBYTES],
+            holding: [0; KEYBYTES],
+            last_tap: None,
+            last_press: None,
+            retro: None,
+        }
+    }
+
+    /// Keep `event` back, false if the buffer is full.
+    fn buffer(&mut self, event: KeyEvent) -> bool {
+        if self.len == self.events.len() {
+            return false;
+        }
+        self.events[self.len] = event;
+        self.len += 1;
+        true
+    }
+
+    fn is_buffered(&self, key: usize) -> bool {
+        self.events[..self.len]
+            .iter()
+            .any(|event| event.key == key && event.pressed)
+    }
+
+    fn is_quick_tap(&self, event: KeyEvent) -> bool {
+        match self.last_tap {
+            Some((key, time)) => {
+                key == event.key
+                    && event.time.wrapping_sub(time) < self.config.quick_tap_term as u32
+            }
+            None => false,
+        }
+    }
+
+    fn is_typing(&self, time: u32) -> bool {
+        match self.last_press {
+            Some(last) => time.wrapping_sub(last) < self.config.require_prior_idle as u32,
+            None => false,
+        }
+    }
+}
+
+/// State of auto-shift, see [`AutoShiftConfig`].
+struct AutoShift {
+    config: AutoShiftConfig,
+    /// Key press held back until its release or the timeout.
+    held: Option<(KeyEvent, KeyCode)>,
+}
+
+impl AutoShift {
+    const fn new(config: AutoShiftConfig) -> AutoShift {
+        AutoShift { config, held: None }
+    }
+}
+
+/// State of the `OneShotMod` keys.
+struct OneShotMods {
+    /// Held down, sent as normal modifiers.
+    held: Modifiers,
+    /// Another key was pressed while `held`, so no one-shot on release.
+    used: bool,
+    /// Tapped, waiting for the next key until `ONE_SHOT_TIMEOUT`.
+    armed: Modifiers,
+    armed_at: u32,
+    /// Double tapped, on until tapped again or Escape.
+    locked: Modifiers,
+}
+
+impl OneShotMods {
+    const fn new() -> OneShotMods {
+        OneShotMods {
+            held: Modifiers::empty(),
+            used: false,
+            armed: Modifiers::empty(),
+            armed_at: 0,
+            locked: Modifiers::empty(),
+        }
+    }
+
+    /// Modifiers to send with every report.
+    fn mods(&self) -> Modifiers {
+        self.held | self.locked
+    }
+
+    fn press(&mut self, mods: Modifiers) {
+        if self.locked.contains(mods) {
+            self.locked.remove(mods);
+        } else if self.armed.contains(mods) {
+            self.armed.remove(mods);
+            self.locked.insert(mods);
+        } else {
+            self.held.insert(mods);
+            self.used = false;
+        }
+    }
+
+    fn release(&mut self, mods: Modifiers, time: u32) {
+        // not held if the press (un)locked
+        if self.held.contains(mods) {
+            self.held.remove(mods);
+            if !self.used {
+                self.armed.insert(mods);
+                self.armed_at = time;
+            }
+        }
+    }
+
+    /// Armed modifiers for the key being pressed.
+    fn take(&mut self) -> Modifiers {
+        self.used = true;
+        core::mem::take(&mut self.armed)
+    }
+
+    fn cancel(&mut self) {
+        self.armed = Modifiers::empty();
+        self.locked = Modifiers::empty();
+    }
+
+    fn expire(&mut self, time: u32) {
+        if !self.armed.is_empty() && time.wrapping_sub(self.armed_at) >= ONE_SHOT_TIMEOUT as u32 {
+            self.armed = Modifiers::empty();
+        }
+    }
+}
+
+/// State of the `Leader` key sequence capture.
+struct Leader {
+    sequences: &'static [LeaderSequence],
+    active: bool,
+    keys: [KeyCode; LEADER_LEN],
+    len: usize,
+    /// Time of the leader or last captured key, for `LEADER_TIMEOUT`.
+    last: u32,
+}
+
+impl Leader {
+    const fn new(sequences: &'static [LeaderSequence]) -> Leader {
+        Leader {
+            sequences,
+            active: false,
+            keys: [KeyCode::No; LEADER_LEN],
+            len: 0,
+            last: 0,
+        }
+    }
+
+    fn start(&mut self, time: u32) {
+        self.active = true;
+        self.len = 0;
+        self.last = time;
+    }
 
-            for key in 0..COLUMNS * ROWS {
-                let pressed = state.get_bit(key);
-                let changed = self.previous_state.get_bit(key) != pressed;
-
-                // Only handle currently pressed and changed keys to
-                // cut down on processing time.
-                if pressed || changed {
-                    let action = self.get_action(key);
-                    match action {
-                        Action::LayerTapKey(layer, kc) => {
-                            self.layers
-                                .process(&Action::LayerMomentary(layer), pressed, changed);
-                            match (pressed, changed) {
-                                (true, false) => {
-                                    self.holding_counter = self.holding_counter.saturating_add(1);
-                                }
-                                (false, true) => {
-                                    self.holding_counter = self.holding_counter.saturating_add(1);
-                                    if self.holding_counter == 1 {
-                                        hid.process(&kc.to_action(), true, changed);
-                                        hid.report.reserved = kc as u8;
-                                    }
-                                    self.holding_counter = 0;
-                                }
-                                _ => {}
-                            }
-                        }
-                        _ => {
-                            hid.process(&action, pressed, changed);
-                            self.layers.process(&action, pressed, changed);
-                        }
-                    }
-                }
-            }
+    fn push(&mut self, code: KeyCode, time: u32) {
+        if self.len < LEADER_LEN {
+            self.keys[self.len] = code;
+            self.len += 1;
+        }
+        self.last = time;
+    }
 
-            self.layers.finish();
-            self.previous_state = *state;
-            return Some(hid.report);
+    /// Action of the sequence matching the captured keys, and whether a
+    /// longer sequence could still match.
+    fn lookup(&self) -> (Option<Action>, bool) {
+        let keys = &self.keys[..self.len];
+        let mut found = None;
+        let mut longer = false;
+        for sequence in self.sequences.iter().filter(|s| s.keys.starts_with(keys)) {
+            if sequence.keys.len() == keys.len() {
+                found = Some(sequence.action);
+            } else {
+                longer = true;
+            }
         }
-        None
+        (found, longer)
     }
 }
 
@@ -102,41 +1106,285 @@ trait EventProcessor {
 /// Bit-field of the currently active layers, indexed by position in
 /// [`layout::LAYERS`].
 struct Layers {
-    current: u8,
-    /// Active layers after action processing is finished
-    next: u8,
+    current: u32,
+    /// Active layers after action processing is finished, without `default`
+    next: u32,
+    /// Base layer, always active underneath the others
+    default: usize,
+    /// Momentary layers kept on by `LayerLock`
+    locked: u32,
+    oneshot: OneShotLayer,
+}
+
+/// Progress of a `OneShotLayer` key.
+#[derive(Copy, Clone)]
+enum OneShotLayer {
+    Off,
+    /// Key still down, `true` once another key was pressed meanwhile,
+    /// which makes it a plain momentary layer.
+    Held(usize, bool),
+    /// Released unused, waiting for the next key press.
+    Armed(usize),
+    /// Next key pressed, the layer goes off with its release.
+    Active(usize),
 }
 
+// Layers are a u32 bit-field
+const _: () = assert!(LAYERS.len() <= 32);
+
 impl Layers {
     const fn new() -> Layers {
         Layers {
             current: 0b1,
-            next: 0b1,
+            next: 0,
+            default: 0,
+            locked: 0,
+            oneshot: OneShotLayer::Off,
+        }
+    }
+
+    fn release(&mut self, layer: usize) {
+        if !self.locked.get_bit(layer) {
+            self.next.set_bit(layer, false);
+        }
+    }
+
+    /// Lock the top momentary layer, or unlock it if it is locked already.
+    fn lock(&mut self) {
+        if self.next == 0 {
+            return;
+        }
+        let top = 31 - self.next.leading_zeros() as usize;
+        if self.locked.get_bit(top) {
+            self.locked.set_bit(top, false);
+            self.next.set_bit(top, false);
+        } else {
+            self.locked.set_bit(top, true);
         }
     }
 }
 
 impl EventProcessor for Layers {
     fn process(&mut self, action: &Action, pressed: bool, changed: bool) {
-        if changed {
-            match (*action, pressed) {
-                (Action::LayerMomentary(layer), _) => self.next.set_bit(layer as usize, pressed),
-                (Action::LayerToggle(layer), true) => {
-                    let current = self.next.get_bit(layer as usize);
-                    self.next.set_bit(layer as usize, !current)
+        if !changed {
+            return;
+        }
+        match (*action, pressed) {
+            (
+                Action::LayerMomentary(layer)
+                | Action::LayerTapKey(layer, _)
+                | Action::LayerMod(layer, _),
+                true,
+            ) => {
+                self.next.set_bit(layer as usize, true);
+            }
+            (
+                Action::LayerMomentary(layer)
+                | Action::LayerTapKey(layer, _)
+                | Action::LayerMod(layer, _),
+                false,
+            ) => self.release(layer as usize),
+            (Action::LayerToggle(layer), true) => {
+                let current = self.next.get_bit(layer as usize);
+                self.next.set_bit(layer as usize, !current);
+                self.locked.set_bit(layer as usize, false);
+            }
+            (Action::LayerTo(layer), true) => {
+                self.next = 1 << layer as usize;
+                self.locked = 0;
+                self.oneshot = OneShotLayer::Off;
+            }
+            (Action::DefaultLayer(layer), true) => self.default = layer as usize,
+            (Action::LayerLock, true) => self.lock(),
+            (Action::OneShotLayer(layer), true) => {
+                self.next.set_bit(layer as usize, true);
+                self.oneshot = OneShotLayer::Held(layer as usize, false);
+            }
+            (Action::OneShotLayer(_), false) => match self.oneshot {
+                OneShotLayer::Held(layer, false) => self.oneshot = OneShotLayer::Armed(layer),
+                OneShotLayer::Held(layer, true) => {
+                    self.release(layer);
+                    self.oneshot = OneShotLayer::Off;
                 }
-                _ => &mut self.next,
-            };
+                _ => {}
+            },
+            (
+                Action::LayerToggle(_)
+                | Action::LayerTo(_)
+                | Action::DefaultLayer(_)
+                | Action::LayerLock,
+                false,
+            ) => {}
+            (Action::OneShotMod(_), _) => {}
+            // any other key uses up a one-shot layer
+            (_, true) => match self.oneshot {
+                OneShotLayer::Held(layer, _) => self.oneshot = OneShotLayer::Held(layer, true),
+                OneShotLayer::Armed(layer) => self.oneshot = OneShotLayer::Active(layer),
+                _ => {}
+            },
+            (_, false) => {
+                if let OneShotLayer::Active(layer) = self.oneshot {
+                    self.release(layer);
+                    self.oneshot = OneShotLayer::Off;
+                }
+            }
         }
     }
 
     fn finish(&mut self) {
-        self.current = self.next;
+        self.current = CONDITIONAL_LAYERS
+            .iter()
+            .fold(self.next | 1 << self.default, |layers, rule| {
+                rule.apply(layers)
+            });
+    }
+}
+
+/// Keyboard report without the unused `leds` byte.
+#[derive(Copy, Clone, PartialEq)]
+struct Report {
+    modifier: u8,
+    reserved: u8,
+    keycodes: [u8; 6],
+}
+
+impl Report {
+    const fn new() -> Report {
+        Report {
+            modifier: 0,
+            reserved: 0,
+            keycodes: [0; 6],
+        }
+    }
+
+    fn to_hid(self) -> KeyboardReport {
+        KeyboardReport {
+            modifier: self.modifier,
+            reserved: self.reserved,
+            leds: 0,
+            keycodes: self.keycodes,
+        }
+    }
+}
+
+/// Ring buffer of reports, so several state changes within one host poll
+/// interval (e.g. a tap) all reach the host.
+struct ReportQueue {
+    reports: [Report; REPORT_QUEUE_LEN],
+    head: usize,
+    len: usize,
+    /// Last report pushed, unchanged states are not queued again.
+    last: Report,
+}
+
+impl ReportQueue {
+    const fn new() -> ReportQueue {
+        ReportQueue {
+            reports: [Report::new(); REPORT_QUEUE_LEN],
+            head: 0,
+            len: 0,
+            last: Report::new(),
+        }
+    }
+
+    /// Queue `report`, false if it is the same as the last one or the queue
+    /// is full. [`Keyboard::tick`] holds events back before that happens.
+    fn push(&mut self, report: Report) -> bool {
+        if report == self.last || self.len == REPORT_QUEUE_LEN {
+            return false;
+        }
+        self.last = report;
+        self.reports[(self.head + self.len) % REPORT_QUEUE_LEN] = report;
+        self.len += 1;
+        true
+    }
+
+    fn free(&self) -> usize {
+        REPORT_QUEUE_LEN - self.len
+    }
+
+    fn front(&self) -> Option<&Report> {
+        if self.len > 0 {
+            Some(&self.reports[self.head])
+        } else {
+            None
+        }
+    }
+
+    fn pop(&mut self) {
+        if self.len > 0 {
+            self.head = (self.head + 1) % REPORT_QUEUE_LEN;
+            self.len -= 1;
+        }
+    }
+}
+
+/// Reports recorded live with `DynamicMacroRecord`, replayed as they were.
+struct DynamicMacro {
+    reports: [Report; DYNAMIC_MACRO_LEN],
+    len: usize,
+    recording: bool,
+    /// Next report to replay.
+    playing: Option<usize>,
+}
+
+impl DynamicMacro {
+    const fn new() -> DynamicMacro {
+        DynamicMacro {
+            reports: [Report::new(); DYNAMIC_MACRO_LEN],
+            len: 0,
+            recording: false,
+            playing: None,
+        }
+    }
+
+    fn is_playing(&self) -> bool {
+        self.playing.is_some()
+    }
+
+    /// Start over with an empty recording.
+    fn record(&mut self) {
+        if !self.is_playing() {
+            self.len = 0;
+            self.recording = true;
+        }
+    }
+
+    fn stop(&mut self) {
+        self.recording = false;
+    }
+
+    fn play(&mut self) {
+        if !self.recording && self.len > 0 {
+            self.playing = Some(0);
+        }
+    }
+
+    /// Record `report` while recording, which stops once the buffer is full.
+    fn push(&mut self, report: Report) {
+        if self.recording {
+            self.reports[self.len] = report;
+            self.len += 1;
+            self.recording = self.len < DYNAMIC_MACRO_LEN;
+        }
+    }
+
+    fn next(&mut self) -> Option<Report> {
+        let i = self.playing?;
+        if i < self.len {
+            self.playing = Some(i + 1);
+            Some(self.reports[i])
+        } else {
+            self.playing = None;
+            None
+        }
     }
 }
 
 struct HidProcessor {
-    pub report: KeyboardReport,
+    pub report: Report,
+    /// `KeyCode`s of `report.keycodes`
+    pub codes: [KeyCode; 6],
     /// Number of normal keys to be sent in `report`
     i: usize,
 }
@@ -144,15 +1392,19 @@ struct HidProcessor {
 impl HidProcessor {
     pub const fn default() -> Self {
         Self {
-            report: KeyboardReport {
-                modifier: 0,
-                reserved: 0,
-                leds: 0,
-                keycodes: [0u8; 6],
-            },
+            report: Report::new(),
+            codes: [KeyCode::No; 6],
             i: 0,
         }
     }
+
+    fn push_key(&mut self, code: KeyCode) {
+        if code.is_normal_key() && self.i < self.report.keycodes.len() {
+            self.report.keycodes[self.i] = code as u8;
+            self.codes[self.i] = code;
+            self.i += 1;
+        }
+    }
 }
 
 impl EventProcessor for HidProcessor {
@@ -161,23 +1413,16 @@ impl EventProcessor for HidProcessor {
             match *action {
                 Action::Key(code) => {
                     if code.is_modifier() {
-                        self.report
-                            .modifier
-                            .set_bit(code as usize - KeyCode::LCtrl as usize, true);
-                    } else if code.is_normal_key() && self.i < self.report.keycodes.len() {
-                        self.report.keycodes[self.i] = code as u8;
-                        self.i += 1;
+                        self.report.modifier |= code.as_modifier().bits();
+                    } else {
+                        self.push_key(code);
                     }
                 }
-                // implement shift & key
-                Action::ShiftKey(code) => {
-                    if code.is_normal_key() && self.i < self.report.keycodes.len() {
-                        self.report
-                            .modifier
-                            .set_bit(KeyCode::LShift as usize - KeyCode::LCtrl as usize, true);
-                        self.report.keycodes[self.i] = code as u8;
-                        self.i += 1;
-                    }
+                // modifiers are added by `Keyboard` as weak mods
+                Action::ModifiedKey(_, code) => self.push_key(code),
+                // hold of a mod-tap, the tap is resolved by `Keyboard`
+                Action::ModTap(mods, _) | Action::LayerMod(_, mods) => {
+                    self.report.modifier |= mods.bits()
                 }
                 // implement wheel
                 Action::Mouse(code) => self.report.reserved = code as u8,
@@ -185,4 +1430,1459 @@ impl EventProcessor for HidProcessor {
             }
         }
     }
+
+    /// Apply [`KeyOverride`]s to the complete report. Only the held
+    /// modifiers that triggered one are taken off, and only for as long as
+    /// its key is down, the next report has them back.
+    fn finish(&mut self) {
+        let held = Modifiers::from_bits_retain(self.report.modifier);
+        let (mut removed, mut added) = (0, 0);
+        for (code, key_code) in self.report.keycodes[..self.i]
+            .iter_mut()
+            .zip(self.codes.iter_mut())
+        {
+            let Some(rule) = KEY_OVERRIDES.iter().find(|rule| rule.matches(held, *code)) else {
+                continue;
+            };
+            let (add, replacement) = match rule.replacement {
+                Action::Key(kc) => (Modifiers::empty(), kc),
+                Action::ModifiedKey(add, kc) => (add, kc),
+                _ => continue,
+            };
+            let mods = rule.mods.bits();
+            removed |= held.bits() & (mods | mods << 4 | mods >> 4);
+            added |= add.bits();
+            *code = replacement as u8;
+            *key_code = replacement;
+        }
+        self.report.modifier = (self.report.modifier & !removed) | added;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{mem, vec, vec::Vec};
+
+    use super::*;
+    use crate::keymatrix::key_index;
+
+    const A: usize = key_index(1, 1);
+    const B: usize = key_index(1, 2);
+    /// `LayerTapKey(LN1, Tab)`
+    const LT1: usize = key_index(3, 4);
+    /// `ModTap(LSHIFT, Space)`
+    const MT: usize = key_index(3, 8);
+
+    const CONFIG: TapHoldConfig = TapHoldConfig {
+        tapping_term: 200,
+        tapping_terms: &[],
+        quick_tap_term: 0,
+        require_prior_idle: 0,
+        retro_tapping: false,
+        permissive_hold: false,
+    };
+
+    /// Nothing but the keys above, A sends N1 and B turns on layer 2 on
+    /// layer 1.
+    fn keymap() -> Keymap {
+        let mut keymap = [[Action::Transparent; KEYS]; LAYERS.len()];
+        keymap[0] = [Action::Nop; KEYS];
+        keymap[0][A] = Action::Key(KeyCode::A);
+        keymap[0][B] = Action::Key(KeyCode::B);
+        keymap[0][LT1] = Action::LayerTapKey(LayerNumber::LN1, KeyCode::Tab);
+        keymap[0][MT] = Action::ModTap(Modifiers::LSHIFT, KeyCode::Space);
+        keymap[1][A] = Action::Key(KeyCode::N1);
+        keymap[1][B] = Action::LayerMomentary(LayerNumber::LN2);
+        keymap
+    }
+
+    /// Modifier byte and key codes of a report.
+    type Sent = (u8, Vec<u8>);
+
+    fn report(mods: Modifiers, codes: &[KeyCode]) -> Sent {
+        (mods.bits(), codes.iter().map(|code| *code as u8).collect())
+    }
+
+    fn keys(codes: &[KeyCode]) -> Sent {
+        report(Modifiers::empty(), codes)
+    }
+
+    /// A `Keyboard` fed matrix snapshots every ms, with a host taking one
+    /// report per ms.
+    struct Sim {
+        keyboard: Keyboard,
+        matrix: KeyState,
+        sent: Vec<Sent>,
+    }
+
+    impl Sim {
+        fn new(config: TapHoldConfig) -> Sim {
+            let mut keyboard = Keyboard::new();
+            keyboard.set_keymap(keymap());
+            keyboard.tap_hold.config = config;
+            Sim {
+                keyboard,
+                matrix: [0; KEYBYTES],
+                sent: Vec::new(),
+            }
+        }
+
+        /// Put `action` on `key` of `layer`.
+        fn bind(&mut self, layer: usize, key: usize, action: Action) {
+            self.keyboard.keymap_mut()[layer][key] = action;
+        }
+
+        fn press(&mut self, key: usize, ms: u32) {
+            self.matrix.set_bit(key, true);
+            self.run(ms);
+        }
+
+        fn release(&mut self, key: usize, ms: u32) {
+            self.matrix.set_bit(key, false);
+            self.run(ms);
+        }
+
+        /// Press and release `key`, 10 ms each.
+        fn tap(&mut self, key: usize) {
+            self.press(key, 10);
+            self.release(key, 10);
+        }
+
+        fn run(&mut self, ms: u32) {
+            for _ in 0..ms {
+                self.keyboard.tick(&self.matrix);
+                self.poll();
+            }
+        }
+
+        fn poll(&mut self) {
+            if let Some(report) = self.keyboard.report() {
+                let codes = report.keycodes.iter().filter(|code| **code != 0);
+                self.sent.push((report.modifier, codes.copied().collect()));
+                self.keyboard.report_sent();
+            }
+        }
+
+        /// Reports sent so far, then the next call starts over.
+        fn sent(&mut self) -> Vec<Sent> {
+            mem::take(&mut self.sent)
+        }
+    }
+
+    #[test]
+    fn tap() {
+        let mut sim = Sim::new(CONFIG);
+        sim.press(LT1, 50);
+        sim.release(LT1, 10);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::Tab]), keys(&[])]);
+    }
+
+    #[test]
+    fn hold_past_tapping_term() {
+        let mut sim = Sim::new(CONFIG);
+        sim.press(MT, 199);
+        assert_eq!(sim.sent(), vec![]);
+        sim.run(10);
+        sim.press(A, 10);
+        sim.release(A, 10);
+        sim.release(MT, 10);
+        let shift = Modifiers::LSHIFT;
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(shift, &[]),
+                report(shift, &[KeyCode::A]),
+                report(shift, &[]),
+                keys(&[]),
+            ]
+        );
+
+        // a layer held past the term, the key comes from that layer
+        sim.press(LT1, 250);
+        sim.press(A, 10);
+        sim.release(A, 10);
+        sim.release(LT1, 10);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::N1]), keys(&[])]);
+    }
+
+    #[test]
+    fn roll_is_a_tap() {
+        // A↓ LT1↓ A↑ LT1↑, A went down first so it's no hold
+        let config = TapHoldConfig {
+            permissive_hold: true,
+            ..CONFIG
+        };
+        let mut sim = Sim::new(config);
+        sim.press(A, 20);
+        sim.press(LT1, 20);
+        sim.release(A, 20);
+        sim.release(LT1, 20);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::A]),
+                keys(&[KeyCode::A, KeyCode::Tab]),
+                keys(&[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+        assert_eq!(sim.keyboard.active_layers(), 1);
+    }
+
+    #[test]
+    fn permissive_hold() {
+        // LT1↓ A↓ A↑ LT1↑ within the term
+        let mut sim = Sim::new(TapHoldConfig {
+            permissive_hold: true,
+            ..CONFIG
+        });
+        sim.press(LT1, 20);
+        sim.press(A, 20);
+        sim.release(A, 20);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::N1]), keys(&[])]);
+        sim.release(LT1, 20);
+        assert_eq!(sim.sent(), vec![]);
+
+        // without it the same keys are a tap followed by A
+        let mut sim = Sim::new(CONFIG);
+        sim.press(LT1, 20);
+        sim.press(A, 20);
+        sim.release(A, 20);
+        assert_eq!(sim.sent(), vec![]);
+        sim.release(LT1, 20);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::Tab]),
+                keys(&[]),
+                keys(&[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn quick_tap_term() {
+        let mut sim = Sim::new(TapHoldConfig {
+            quick_tap_term: 120,
+            ..CONFIG
+        });
+        sim.press(LT1, 20);
+        sim.release(LT1, 100);
+        // pressed again in time, Tab is held down at once
+        sim.press(LT1, 300);
+        sim.press(A, 10);
+        sim.release(A, 10);
+        sim.release(LT1, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::Tab]),
+                keys(&[]),
+                keys(&[KeyCode::Tab]),
+                keys(&[KeyCode::A, KeyCode::Tab]),
+                keys(&[KeyCode::Tab]),
+                keys(&[]),
+            ]
+        );
+
+        // too late, a hold again
+        sim.press(LT1, 20);
+        sim.release(LT1, 130);
+        sim.sent();
+        sim.press(LT1, 250);
+        sim.press(A, 10);
+        sim.release(A, 10);
+        sim.release(LT1, 10);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::N1]), keys(&[])]);
+    }
+
+    #[test]
+    fn retro_tapping() {
+        let config = TapHoldConfig {
+            retro_tapping: true,
+            ..CONFIG
+        };
+        let shift = Modifiers::LSHIFT;
+        let mut sim = Sim::new(config);
+        sim.press(MT, 300);
+        sim.release(MT, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(shift, &[]),
+                keys(&[]),
+                keys(&[KeyCode::Space]),
+                keys(&[]),
+            ]
+        );
+
+        // not after another key
+        sim.press(MT, 300);
+        sim.press(A, 10);
+        sim.release(A, 10);
+        sim.release(MT, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(shift, &[]),
+                report(shift, &[KeyCode::A]),
+                report(shift, &[]),
+                keys(&[]),
+            ]
+        );
+
+        // nor without it
+        let mut sim = Sim::new(CONFIG);
+        sim.press(MT, 300);
+        sim.release(MT, 10);
+        assert_eq!(sim.sent(), vec![report(shift, &[]), keys(&[])]);
+    }
+
+    #[test]
+    fn require_prior_idle() {
+        let mut sim = Sim::new(TapHoldConfig {
+            require_prior_idle: 150,
+            ..CONFIG
+        });
+        // typing, the key is a tap straight away
+        sim.press(A, 20);
+        sim.release(A, 20);
+        sim.press(LT1, 20);
+        sim.press(B, 20);
+        sim.release(B, 20);
+        sim.release(LT1, 20);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::A]),
+                keys(&[]),
+                keys(&[KeyCode::Tab]),
+                keys(&[KeyCode::B, KeyCode::Tab]),
+                keys(&[KeyCode::Tab]),
+                keys(&[]),
+            ]
+        );
+
+        // after a pause it can be a hold
+        sim.press(A, 20);
+        sim.release(A, 200);
+        sim.sent();
+        sim.press(LT1, 250);
+        sim.press(A, 20);
+        sim.release(A, 20);
+        sim.release(LT1, 20);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::N1]), keys(&[])]);
+    }
+
+    #[test]
+    fn weak_mods_stay_on_their_key() {
+        let mut sim = Sim::new(CONFIG);
+        let (exclaim, shift) = (key_index(0, 1), key_index(0, 2));
+        sim.bind(0, exclaim, KeyCode::N1.lshift());
+        sim.bind(0, shift, Action::Key(KeyCode::LShift));
+        let lshift = Modifiers::LSHIFT;
+
+        // the next key pressed while it is held goes without Shift
+        sim.press(exclaim, 10);
+        sim.press(A, 10);
+        sim.release(A, 10);
+        sim.release(exclaim, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(lshift, &[KeyCode::N1]),
+                keys(&[KeyCode::N1, KeyCode::A]),
+                keys(&[KeyCode::N1]),
+                keys(&[]),
+            ]
+        );
+
+        // tapped, Shift goes with its release
+        sim.press(exclaim, 10);
+        sim.release(exclaim, 10);
+        sim.press(A, 10);
+        sim.release(A, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(lshift, &[KeyCode::N1]),
+                keys(&[]),
+                keys(&[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+
+        // a Shift held as a key stays
+        sim.press(shift, 10);
+        sim.press(exclaim, 10);
+        sim.press(A, 10);
+        sim.release(exclaim, 10);
+        sim.release(A, 10);
+        sim.release(shift, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(lshift, &[]),
+                report(lshift, &[KeyCode::N1]),
+                report(lshift, &[KeyCode::N1, KeyCode::A]),
+                report(lshift, &[KeyCode::A]),
+                report(lshift, &[]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn layer_toggle_to_and_default() {
+        let mut sim = Sim::new(CONFIG);
+        let (toggle, to, to_base, default) = (
+            key_index(0, 1),
+            key_index(0, 2),
+            key_index(0, 3),
+            key_index(0, 4),
+        );
+        let c = key_index(1, 3);
+        sim.bind(0, toggle, Action::LayerToggle(LayerNumber::LN2));
+        sim.bind(0, to, Action::LayerTo(LayerNumber::LN1));
+        sim.bind(0, to_base, Action::LayerTo(LayerNumber::LN0));
+        sim.bind(0, default, Action::DefaultLayer(LayerNumber::LN2));
+        sim.bind(0, c, Action::Key(KeyCode::C));
+        sim.bind(2, A, Action::Key(KeyCode::N2));
+
+        sim.tap(toggle);
+        assert_eq!(sim.keyboard.active_layers(), 0b101);
+        sim.tap(A);
+        // LayerTo turns the toggled layer off
+        sim.tap(to);
+        assert_eq!(sim.keyboard.active_layers(), 0b11);
+        sim.tap(A);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::N2]),
+                keys(&[]),
+                keys(&[KeyCode::N1]),
+                keys(&[]),
+            ]
+        );
+
+        // the top layer wins, transparent keys fall through to layer 0
+        sim.tap(toggle);
+        assert_eq!(sim.keyboard.active_layers(), 0b1111);
+        sim.tap(A);
+        sim.tap(c);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::N2]),
+                keys(&[]),
+                keys(&[KeyCode::C]),
+                keys(&[]),
+            ]
+        );
+
+        // another default layer replaces layer 0 underneath
+        sim.tap(to_base);
+        assert_eq!(sim.keyboard.active_layers(), 0b1);
+        sim.tap(default);
+        assert_eq!(sim.keyboard.active_layers(), 0b101);
+        sim.tap(to);
+        assert_eq!(sim.keyboard.active_layers(), 0b1110);
+        sim.tap(A);
+        sim.tap(c);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::N2]), keys(&[])]);
+    }
+
+    #[test]
+    fn one_shot_layer() {
+        let mut sim = Sim::new(CONFIG);
+        let osl = key_index(0, 1);
+        sim.bind(0, osl, Action::OneShotLayer(LayerNumber::LN1));
+
+        // tapped, on for the next key only
+        sim.tap(osl);
+        assert_eq!(sim.keyboard.active_layers(), 0b11);
+        sim.press(A, 10);
+        assert_eq!(sim.keyboard.active_layers(), 0b11);
+        sim.release(A, 10);
+        assert_eq!(sim.keyboard.active_layers(), 0b1);
+        sim.tap(A);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::N1]),
+                keys(&[]),
+                keys(&[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+
+        // held while another key is pressed, a momentary layer
+        sim.press(osl, 10);
+        sim.tap(A);
+        sim.tap(A);
+        assert_eq!(sim.keyboard.active_layers(), 0b11);
+        sim.release(osl, 10);
+        assert_eq!(sim.keyboard.active_layers(), 0b1);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::N1]),
+                keys(&[]),
+                keys(&[KeyCode::N1]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn layer_mod_and_lock() {
+        let mut sim = Sim::new(CONFIG);
+        let (layer_mod, lock) = (key_index(0, 1), key_index(0, 2));
+        sim.bind(
+            0,
+            layer_mod,
+            Action::LayerMod(LayerNumber::LN1, Modifiers::LCTRL),
+        );
+        sim.bind(1, lock, Action::LayerLock);
+        let ctrl = Modifiers::LCTRL;
+
+        sim.press(layer_mod, 10);
+        assert_eq!(sim.keyboard.active_layers(), 0b11);
+        sim.tap(A);
+        // locked, the layer stays without its modifiers
+        sim.tap(lock);
+        sim.release(layer_mod, 10);
+        assert_eq!(sim.keyboard.active_layers(), 0b11);
+        sim.tap(A);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(ctrl, &[]),
+                report(ctrl, &[KeyCode::N1]),
+                report(ctrl, &[]),
+                keys(&[]),
+                keys(&[KeyCode::N1]),
+                keys(&[]),
+            ]
+        );
+
+        // pressed again it lets go of the layer
+        sim.tap(lock);
+        assert_eq!(sim.keyboard.active_layers(), 0b1);
+        sim.tap(A);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::A]), keys(&[])]);
+    }
+
+    #[test]
+    fn conditional_layer() {
+        let mut sim = Sim::new(CONFIG);
+        let (lower, raise) = (key_index(0, 1), key_index(0, 2));
+        sim.bind(0, lower, Action::LayerMomentary(LayerNumber::LN1));
+        sim.bind(0, raise, Action::LayerMomentary(LayerNumber::LN2));
+        sim.bind(3, A, Action::Key(KeyCode::N3));
+
+        // layer 3 is on while both layer 1 and 2 are, in either order
+        sim.press(lower, 10);
+        assert_eq!(sim.keyboard.active_layers(), 0b11);
+        sim.press(raise, 10);
+        assert_eq!(sim.keyboard.active_layers(), 0b1111);
+        sim.tap(A);
+        sim.release(lower, 10);
+        assert_eq!(sim.keyboard.active_layers(), 0b101);
+        sim.press(lower, 10);
+        assert_eq!(sim.keyboard.active_layers(), 0b1111);
+        sim.release(raise, 10);
+        assert_eq!(sim.keyboard.active_layers(), 0b11);
+        sim.tap(A);
+        sim.release(lower, 10);
+        assert_eq!(sim.keyboard.active_layers(), 0b1);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::N3]),
+                keys(&[]),
+                keys(&[KeyCode::N1]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    /// One-shot Shift and Ctrl next to Escape.
+    const OS_SHIFT: usize = key_index(0, 1);
+    const OS_CTRL: usize = key_index(0, 2);
+    const ESCAPE: usize = key_index(0, 3);
+
+    fn one_shot_sim() -> Sim {
+        let mut sim = Sim::new(CONFIG);
+        sim.bind(0, OS_SHIFT, Action::OneShotMod(Modifiers::LSHIFT));
+        sim.bind(0, OS_CTRL, Action::OneShotMod(Modifiers::LCTRL));
+        sim.bind(0, ESCAPE, Action::Key(KeyCode::Escape));
+        sim
+    }
+
+    #[test]
+    fn one_shot_mods() {
+        let mut sim = one_shot_sim();
+        let (shift, ctrl) = (Modifiers::LSHIFT, Modifiers::LCTRL);
+        // the next key only
+        sim.tap(OS_SHIFT);
+        sim.tap(A);
+        sim.tap(A);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(shift, &[]),
+                keys(&[]),
+                report(shift, &[KeyCode::A]),
+                keys(&[]),
+                keys(&[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+
+        // dropped after the timeout
+        sim.tap(OS_SHIFT);
+        sim.run(ONE_SHOT_TIMEOUT as u32 - 20);
+        sim.tap(A);
+        sim.tap(OS_SHIFT);
+        sim.run(ONE_SHOT_TIMEOUT as u32);
+        sim.tap(A);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(shift, &[]),
+                keys(&[]),
+                report(shift, &[KeyCode::A]),
+                keys(&[]),
+                report(shift, &[]),
+                keys(&[]),
+                keys(&[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+
+        // several stack up
+        sim.tap(OS_SHIFT);
+        sim.tap(OS_CTRL);
+        sim.tap(A);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(shift, &[]),
+                keys(&[]),
+                report(ctrl, &[]),
+                keys(&[]),
+                report(shift | ctrl, &[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn one_shot_lock_and_cancel() {
+        let mut sim = one_shot_sim();
+        let shift = Modifiers::LSHIFT;
+        // double tapped, locked until Escape
+        sim.tap(OS_SHIFT);
+        sim.tap(OS_SHIFT);
+        sim.tap(A);
+        sim.tap(A);
+        sim.tap(ESCAPE);
+        sim.tap(A);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(shift, &[]),
+                keys(&[]),
+                report(shift, &[]),
+                report(shift, &[KeyCode::A]),
+                report(shift, &[]),
+                report(shift, &[KeyCode::A]),
+                report(shift, &[]),
+                keys(&[KeyCode::Escape]),
+                keys(&[]),
+                keys(&[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+
+        // or until tapped again
+        sim.tap(OS_SHIFT);
+        sim.tap(OS_SHIFT);
+        sim.tap(OS_SHIFT);
+        sim.tap(A);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(shift, &[]),
+                keys(&[]),
+                report(shift, &[]),
+                keys(&[]),
+                keys(&[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+
+        // Escape cancels a tapped one too, without being shifted
+        sim.tap(OS_SHIFT);
+        sim.tap(ESCAPE);
+        sim.tap(A);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(shift, &[]),
+                keys(&[]),
+                keys(&[KeyCode::Escape]),
+                keys(&[]),
+                keys(&[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn one_shot_mods_with_layers() {
+        let mut sim = one_shot_sim();
+        let osl = key_index(0, 4);
+        sim.bind(0, osl, Action::OneShotLayer(LayerNumber::LN1));
+        let shift = Modifiers::LSHIFT;
+        // a tapped layer-tap key takes them for its tap
+        sim.tap(OS_SHIFT);
+        sim.tap(LT1);
+        // a held one passes them on to the key from its layer
+        sim.tap(OS_SHIFT);
+        sim.press(LT1, 250);
+        sim.tap(A);
+        sim.release(LT1, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(shift, &[]),
+                keys(&[]),
+                report(shift, &[KeyCode::Tab]),
+                keys(&[]),
+                report(shift, &[]),
+                keys(&[]),
+                report(shift, &[KeyCode::N1]),
+                keys(&[]),
+            ]
+        );
+
+        // a one-shot layer and modifier both wait for the same key
+        sim.tap(osl);
+        sim.tap(OS_SHIFT);
+        sim.tap(A);
+        sim.tap(A);
+        assert_eq!(sim.keyboard.active_layers(), 0b1);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(shift, &[]),
+                keys(&[]),
+                report(shift, &[KeyCode::N1]),
+                keys(&[]),
+                keys(&[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    /// The J + K combo of `COMBOS`, sending Escape.
+    const J: usize = key_index(1, 8);
+    const K: usize = key_index(1, 9);
+
+    fn combo_sim() -> Sim {
+        let mut sim = Sim::new(CONFIG);
+        sim.bind(0, J, Action::Key(KeyCode::J));
+        sim.bind(0, K, Action::Key(KeyCode::K));
+        sim
+    }
+
+    #[test]
+    fn combo() {
+        let mut sim = combo_sim();
+        // K goes down 39 ms after J, just in time
+        sim.press(J, 39);
+        assert_eq!(sim.sent(), vec![]);
+        sim.press(K, 10);
+        sim.release(J, 10);
+        sim.release(K, 10);
+        // in any order, the first release ends it
+        sim.press(K, 30);
+        sim.press(J, 10);
+        sim.release(J, 10);
+        sim.release(K, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::Escape]),
+                keys(&[]),
+                keys(&[KeyCode::Escape]),
+                keys(&[]),
+            ]
+        );
+
+        // other keys come and go while it is held
+        sim.press(J, 10);
+        sim.press(K, 10);
+        sim.press(A, 10);
+        sim.release(K, 10);
+        sim.press(K, 10);
+        sim.release(A, 10);
+        sim.release(K, 10);
+        sim.release(J, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::Escape]),
+                keys(&[KeyCode::A, KeyCode::Escape]),
+                keys(&[KeyCode::A]),
+                keys(&[KeyCode::A, KeyCode::K]),
+                keys(&[KeyCode::K]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn combo_timeout() {
+        let mut sim = combo_sim();
+        // too slow, J goes out once the term is over
+        sim.press(J, 40);
+        assert_eq!(sim.sent(), vec![]);
+        sim.run(1);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::J])]);
+        sim.press(K, 10);
+        sim.release(J, 10);
+        sim.release(K, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::J, KeyCode::K]),
+                keys(&[KeyCode::K]),
+                keys(&[])
+            ]
+        );
+
+        // released within the term, a plain tap
+        sim.press(J, 10);
+        sim.release(J, 10);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::J]), keys(&[])]);
+    }
+
+    #[test]
+    fn combo_interrupted() {
+        let mut sim = combo_sim();
+        // a key outside the combo lets J through at once
+        sim.press(J, 10);
+        sim.press(A, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![keys(&[KeyCode::J]), keys(&[KeyCode::A, KeyCode::J])]
+        );
+        // and K then starts over
+        sim.press(K, 39);
+        assert_eq!(sim.sent(), vec![]);
+        sim.release(A, 10);
+        sim.release(J, 10);
+        sim.release(K, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::A, KeyCode::J, KeyCode::K]),
+                keys(&[KeyCode::J, KeyCode::K]),
+                keys(&[KeyCode::K]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn key_override() {
+        let mut sim = Sim::new(CONFIG);
+        let (bspace, rshift, ctrl) = (key_index(0, 1), key_index(0, 2), key_index(0, 3));
+        sim.bind(0, bspace, Action::Key(KeyCode::BSpace));
+        sim.bind(0, rshift, Action::Key(KeyCode::RShift));
+        sim.bind(0, ctrl, Action::Key(KeyCode::LCtrl));
+        let (shift, lctrl) = (Modifiers::RSHIFT, Modifiers::LCTRL);
+
+        // Shift on either side gives Delete, and is back once it is up
+        sim.press(rshift, 10);
+        sim.tap(bspace);
+        sim.tap(A);
+        sim.release(rshift, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(shift, &[]),
+                keys(&[KeyCode::Delete]),
+                report(shift, &[]),
+                report(shift, &[KeyCode::A]),
+                report(shift, &[]),
+                keys(&[]),
+            ]
+        );
+
+        // other modifiers stay
+        sim.press(ctrl, 10);
+        sim.press(rshift, 10);
+        sim.tap(bspace);
+        sim.release(rshift, 10);
+        sim.tap(bspace);
+        sim.release(ctrl, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(lctrl, &[]),
+                report(lctrl | shift, &[]),
+                report(lctrl, &[KeyCode::Delete]),
+                report(lctrl | shift, &[]),
+                report(lctrl, &[]),
+                report(lctrl, &[KeyCode::BSpace]),
+                report(lctrl, &[]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn leader() {
+        let mut sim = Sim::new(CONFIG);
+        let lead = key_index(0, 1);
+        let (g, c, q) = (key_index(1, 3), key_index(1, 4), key_index(1, 5));
+        sim.bind(0, lead, Action::Leader);
+        sim.bind(0, g, Action::Key(KeyCode::G));
+        sim.bind(0, c, Action::Key(KeyCode::C));
+        sim.bind(0, q, Action::Key(KeyCode::Q));
+        sim.keyboard.leader.sequences = &[
+            LeaderSequence {
+                keys: &[KeyCode::G, KeyCode::C],
+                action: Action::Key(KeyCode::F1),
+            },
+            LeaderSequence {
+                keys: &[KeyCode::C],
+                action: Action::Key(KeyCode::F2),
+            },
+            LeaderSequence {
+                keys: &[KeyCode::C, KeyCode::C],
+                action: Action::Key(KeyCode::F3),
+            },
+        ];
+        let timeout = LEADER_TIMEOUT as u32;
+
+        // a complete sequence fires as soon as it can't get longer
+        sim.tap(lead);
+        sim.tap(g);
+        assert_eq!(sim.sent(), vec![]);
+        sim.tap(c);
+        sim.tap(c);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::F1]),
+                keys(&[]),
+                keys(&[KeyCode::C]),
+                keys(&[]),
+            ]
+        );
+
+        // or on the timeout if a longer one could still follow
+        sim.tap(lead);
+        sim.tap(c);
+        sim.run(timeout - 20);
+        assert_eq!(sim.sent(), vec![]);
+        sim.run(10);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::F2]), keys(&[])]);
+        sim.tap(lead);
+        sim.tap(c);
+        sim.tap(c);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::F3]), keys(&[])]);
+
+        // a prefix of a sequence alone gives nothing
+        sim.tap(lead);
+        sim.tap(g);
+        sim.run(timeout);
+        sim.tap(g);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::G]), keys(&[])]);
+
+        // nor do keys matching no sequence, the next ones type again
+        sim.tap(lead);
+        sim.tap(q);
+        sim.tap(q);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::Q]), keys(&[])]);
+    }
+
+    #[test]
+    fn macro_with_held_keys() {
+        let mut sim = Sim::new(CONFIG);
+        let duplicate = key_index(0, 1);
+        sim.bind(0, duplicate, Action::Macro(0));
+        let ctrl = Modifiers::LCTRL;
+
+        // `MACROS[0]` plays on top of the held A, which stays down
+        sim.press(A, 10);
+        sim.tap(duplicate);
+        sim.run(100);
+        sim.release(A, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::A]),
+                report(ctrl, &[KeyCode::A]),
+                report(ctrl, &[KeyCode::A, KeyCode::C]),
+                report(ctrl, &[KeyCode::A]),
+                keys(&[KeyCode::A]),
+                report(ctrl, &[KeyCode::A]),
+                report(ctrl, &[KeyCode::A, KeyCode::V]),
+                report(ctrl, &[KeyCode::A]),
+                report(ctrl, &[KeyCode::A, KeyCode::V]),
+                report(ctrl, &[KeyCode::A]),
+                keys(&[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    /// Record, stop and play keys of a dynamic macro.
+    fn dynamic_macro_sim() -> (Sim, [usize; 3]) {
+        let mut sim = Sim::new(CONFIG);
+        let keys = [key_index(0, 1), key_index(0, 2), key_index(0, 3)];
+        sim.bind(0, keys[0], Action::DynamicMacroRecord);
+        sim.bind(0, keys[1], Action::DynamicMacroStop);
+        sim.bind(0, keys[2], Action::DynamicMacroPlay);
+        (sim, keys)
+    }
+
+    #[test]
+    fn dynamic_macro() {
+        let (mut sim, [record, stop, play]) = dynamic_macro_sim();
+        sim.tap(record);
+        sim.tap(A);
+        sim.press(B, 10);
+        sim.press(A, 10);
+        sim.release(B, 10);
+        sim.release(A, 10);
+        sim.tap(stop);
+        let typed = vec![
+            keys(&[KeyCode::A]),
+            keys(&[]),
+            keys(&[KeyCode::B]),
+            keys(&[KeyCode::A, KeyCode::B]),
+            keys(&[KeyCode::A]),
+            keys(&[]),
+        ];
+        assert_eq!(sim.sent(), typed);
+        sim.tap(play);
+        assert_eq!(sim.sent(), typed);
+        sim.tap(play);
+        assert_eq!(sim.sent(), typed);
+    }
+
+    #[test]
+    fn dynamic_macro_capacity() {
+        let (mut sim, [record, stop, play]) = dynamic_macro_sim();
+        // recording stops once it is full
+        sim.tap(record);
+        for _ in 0..DYNAMIC_MACRO_LEN {
+            sim.tap(A);
+        }
+        sim.tap(stop);
+        assert_eq!(sim.sent().len(), 2 * DYNAMIC_MACRO_LEN);
+        sim.press(play, 10);
+        // the replay leaves room for key events
+        for _ in 0..DYNAMIC_MACRO_LEN {
+            sim.keyboard.tick(&sim.matrix);
+            assert!(sim.keyboard.reports.free() >= EVENT_REPORTS);
+            sim.poll();
+        }
+        sim.release(play, 10);
+        let taps = (0..DYNAMIC_MACRO_LEN).map(|i| match i % 2 {
+            0 => keys(&[KeyCode::A]),
+            _ => keys(&[]),
+        });
+        assert_eq!(sim.sent(), taps.collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn caps_word() {
+        let mut sim = Sim::new(CONFIG);
+        let (caps, minus, n2, space) = (
+            key_index(0, 1),
+            key_index(0, 2),
+            key_index(0, 3),
+            key_index(0, 4),
+        );
+        sim.bind(0, caps, Action::CapsWord);
+        sim.bind(0, minus, Action::Key(KeyCode::Minus));
+        sim.bind(0, n2, Action::Key(KeyCode::N2));
+        sim.bind(0, space, Action::Key(KeyCode::Space));
+        let shift = Modifiers::LSHIFT;
+
+        // letters are shifted, `CAPS_WORD_CONTINUE` keys kept as they are
+        sim.tap(caps);
+        sim.tap(A);
+        sim.tap(minus);
+        sim.tap(n2);
+        sim.tap(B);
+        // and anything else ends the word
+        sim.tap(space);
+        sim.tap(A);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(shift, &[KeyCode::A]),
+                keys(&[]),
+                keys(&[KeyCode::Minus]),
+                keys(&[]),
+                keys(&[KeyCode::N2]),
+                keys(&[]),
+                report(shift, &[KeyCode::B]),
+                keys(&[]),
+                keys(&[KeyCode::Space]),
+                keys(&[]),
+                keys(&[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+
+        // a shortcut ends it as well
+        sim.bind(0, space, KeyCode::A.lctrl());
+        sim.tap(caps);
+        sim.tap(space);
+        sim.tap(A);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(Modifiers::LCTRL, &[KeyCode::A]),
+                keys(&[]),
+                keys(&[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn caps_word_timeout() {
+        let mut sim = Sim::new(CONFIG);
+        let caps = key_index(0, 1);
+        sim.bind(0, caps, Action::CapsWord);
+        let timeout = CAPS_WORD_TIMEOUT as u32;
+        let shift = Modifiers::LSHIFT;
+
+        // every key starts the timeout over
+        sim.tap(caps);
+        sim.run(timeout - 20);
+        sim.tap(A);
+        sim.run(timeout - 20);
+        sim.tap(A);
+        sim.run(timeout);
+        sim.tap(A);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(shift, &[KeyCode::A]),
+                keys(&[]),
+                report(shift, &[KeyCode::A]),
+                keys(&[]),
+                keys(&[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+
+        // tapped again, it is off at once
+        sim.tap(caps);
+        sim.tap(caps);
+        sim.tap(A);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::A]), keys(&[])]);
+    }
+
+    const AUTO_SHIFT_CONFIG: AutoShiftConfig = AutoShiftConfig {
+        enabled: true,
+        timeout: 175,
+        excluded: &[B],
+    };
+
+    #[test]
+    fn auto_shift() {
+        let mut sim = Sim::new(CONFIG);
+        sim.keyboard.auto_shift.config = AUTO_SHIFT_CONFIG;
+        let shift = Modifiers::LSHIFT;
+        // a tap goes out on release, a long press once the timeout is over
+        sim.press(A, 100);
+        assert_eq!(sim.sent(), vec![]);
+        sim.release(A, 10);
+        sim.press(A, 175);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::A]), keys(&[])]);
+        sim.run(1);
+        sim.release(A, 10);
+        assert_eq!(sim.sent(), vec![report(shift, &[KeyCode::A]), keys(&[])]);
+
+        // the next key ends the wait, excluded keys go out at once
+        sim.press(A, 10);
+        sim.press(B, 200);
+        sim.release(A, 10);
+        sim.release(B, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::A]),
+                keys(&[]),
+                keys(&[KeyCode::B]),
+                keys(&[]),
+            ]
+        );
+
+        // off, nothing is held back
+        sim.keyboard.auto_shift.config.enabled = false;
+        sim.press(A, 200);
+        sim.release(A, 10);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::A]), keys(&[])]);
+    }
+
+    #[test]
+    fn auto_shift_held_mods() {
+        let mut sim = Sim::new(CONFIG);
+        sim.keyboard.auto_shift.config = AUTO_SHIFT_CONFIG;
+        let (ctrl, exclaim) = (key_index(0, 1), key_index(0, 2));
+        sim.bind(0, ctrl, Action::Key(KeyCode::RCtrl));
+        sim.bind(0, exclaim, KeyCode::N1.lshift());
+        let (rctrl, shift) = (Modifiers::RCTRL, Modifiers::LSHIFT);
+
+        // a held modifier makes it a shortcut, sent straight away
+        sim.press(ctrl, 10);
+        sim.press(A, 200);
+        sim.release(A, 10);
+        sim.release(ctrl, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(rctrl, &[]),
+                report(rctrl, &[KeyCode::A]),
+                report(rctrl, &[]),
+                keys(&[]),
+            ]
+        );
+
+        // the Shift of a key like ! only goes with that key
+        sim.press(exclaim, 10);
+        sim.press(A, 100);
+        sim.release(A, 10);
+        sim.press(A, 200);
+        sim.release(A, 10);
+        sim.release(exclaim, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(shift, &[KeyCode::N1]),
+                keys(&[KeyCode::N1, KeyCode::A]),
+                keys(&[KeyCode::N1]),
+                report(shift, &[KeyCode::N1, KeyCode::A]),
+                keys(&[KeyCode::N1]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeat() {
+        let mut sim = Sim::new(CONFIG);
+        let (repeat, ctrl, x) = (key_index(0, 1), key_index(0, 2), key_index(1, 3));
+        sim.bind(0, repeat, Action::Repeat);
+        sim.bind(0, ctrl, Action::Key(KeyCode::RCtrl));
+        sim.bind(0, x, Action::Key(KeyCode::X));
+        let rctrl = Modifiers::RCTRL;
+
+        // nothing to repeat yet
+        sim.tap(repeat);
+        assert_eq!(sim.sent(), vec![]);
+        // held down like the key repeated
+        sim.tap(A);
+        sim.press(repeat, 10);
+        sim.press(B, 10);
+        sim.release(repeat, 10);
+        sim.release(B, 10);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::A]),
+                keys(&[]),
+                keys(&[KeyCode::A]),
+                keys(&[KeyCode::A, KeyCode::B]),
+                keys(&[KeyCode::B]),
+                keys(&[]),
+            ]
+        );
+
+        // with the modifiers it was sent with
+        sim.press(ctrl, 10);
+        sim.tap(x);
+        sim.release(ctrl, 10);
+        sim.tap(repeat);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(rctrl, &[]),
+                report(rctrl, &[KeyCode::X]),
+                report(rctrl, &[]),
+                keys(&[]),
+                report(rctrl, &[KeyCode::X]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn alt_repeat() {
+        let mut sim = Sim::new(CONFIG);
+        let (alt_repeat, shift, nine, bracket) = (
+            key_index(0, 1),
+            key_index(0, 2),
+            key_index(1, 3),
+            key_index(1, 4),
+        );
+        sim.bind(0, alt_repeat, Action::AltRepeat);
+        sim.bind(0, shift, Action::Key(KeyCode::RShift));
+        sim.bind(0, nine, Action::Key(KeyCode::N9));
+        sim.bind(0, bracket, Action::Key(KeyCode::LBracket));
+        let rshift = Modifiers::RSHIFT;
+
+        // either key of a pair gives the other one
+        sim.tap(bracket);
+        sim.tap(alt_repeat);
+        sim.tap(alt_repeat);
+        // keys without a pair give nothing
+        sim.tap(A);
+        sim.tap(alt_repeat);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                keys(&[KeyCode::LBracket]),
+                keys(&[]),
+                keys(&[KeyCode::RBracket]),
+                keys(&[]),
+                keys(&[KeyCode::LBracket]),
+                keys(&[]),
+                keys(&[KeyCode::A]),
+                keys(&[]),
+            ]
+        );
+
+        // a right Shift ( matches the left Shift pair, ) keeps the side
+        sim.press(shift, 10);
+        sim.tap(nine);
+        sim.release(shift, 10);
+        sim.tap(alt_repeat);
+        assert_eq!(
+            sim.sent(),
+            vec![
+                report(rshift, &[]),
+                report(rshift, &[KeyCode::N9]),
+                report(rshift, &[]),
+                keys(&[]),
+                report(rshift, &[KeyCode::N0]),
+                keys(&[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn release_after_layer_key() {
+        let mut sim = Sim::new(CONFIG);
+        sim.press(LT1, 250);
+        sim.press(A, 10);
+        sim.press(B, 10);
+        // with layer 3 from `CONDITIONAL_LAYERS`
+        assert_eq!(sim.keyboard.active_layers(), 0b1111);
+        sim.release(LT1, 10);
+        // N1 stays down until A goes up, not A
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::N1])]);
+        sim.release(A, 10);
+        sim.release(B, 10);
+        assert_eq!(sim.sent(), vec![keys(&[])]);
+        assert_eq!(sim.keyboard.active_layers(), 1);
+        sim.press(A, 10);
+        sim.release(A, 10);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::A]), keys(&[])]);
+    }
+
+    #[test]
+    fn release_after_keymap_change() {
+        let mut sim = Sim::new(CONFIG);
+        sim.press(A, 10);
+        sim.press(LT1, 250);
+        sim.press(B, 10);
+        sim.keyboard.set_keymap([[Action::Nop; KEYS]; LAYERS.len()]);
+        sim.release(A, 10);
+        sim.release(B, 10);
+        sim.release(LT1, 10);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::A]), keys(&[])]);
+        assert_eq!(sim.keyboard.active_layers(), 1);
+
+        // the new keymap applies from the next press
+        sim.keyboard.set_keymap(keymap());
+        sim.keyboard.keymap_mut()[0][A] = Action::Key(KeyCode::C);
+        sim.press(A, 10);
+        sim.release(A, 10);
+        assert_eq!(sim.sent(), vec![keys(&[KeyCode::C]), keys(&[])]);
+    }
+
+    #[test]
+    fn report_queue_never_overwrites() {
+        let mut queue = ReportQueue::new();
+        let report = |i: usize| Report {
+            modifier: i as u8,
+            ..Report::new()
+        };
+        for i in 1..=REPORT_QUEUE_LEN {
+            assert!(queue.push(report(i)));
+        }
+        assert_eq!(queue.free(), 0);
+        assert!(!queue.push(report(0)));
+        for i in 1..=REPORT_QUEUE_LEN {
+            assert!(queue.front() == Some(&report(i)));
+            queue.pop();
+        }
+        assert!(queue.front().is_none());
+        // the refused report is still different from the last one queued
+        assert!(queue.push(report(0)));
+    }
+
+    #[test]
+    fn full_queue_defers_events() {
+        let mut sim = Sim::new(CONFIG);
+        // the host stops polling, A goes up and down once a ms
+        let mut taken = 0;
+        for _ in 0..REPORT_QUEUE_LEN {
+            let pressed = !sim.matrix.get_bit(A);
+            sim.matrix.set_bit(A, pressed);
+            sim.keyboard.tick(&sim.matrix);
+            if sim.keyboard.matrix_state().get_bit(A) != pressed {
+                // held back, so it's still there once the host polls
+                assert!(sim.keyboard.reports.free() < EVENT_REPORTS);
+                sim.matrix.set_bit(A, !pressed);
+                break;
+            }
+            taken += 1;
+        }
+        assert!(taken > 0 && taken < REPORT_QUEUE_LEN);
+        while sim.keyboard.report().is_some() {
+            sim.poll();
+        }
+        let expected: Vec<Sent> = (0..taken)
+            .map(|i| match i % 2 {
+                0 => keys(&[KeyCode::A]),
+                _ => keys(&[]),
+            })
+            .collect();
+        assert_eq!(sim.sent(), expected);
+
+        // a change left in the matrix goes through once there is room
+        sim.matrix.set_bit(A, taken % 2 == 0);
+        sim.run(5);
+        assert_eq!(sim.sent().len(), 1);
+    }
 }
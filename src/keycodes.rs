@@ -5,6 +5,7 @@
 
 // USB HID KeyCodes
 #[derive(PartialOrd, PartialEq, Copy, Clone, Debug, Default)]
+#[repr(u8)]
 pub enum KeyCode {
     #[default]
     No = 0x00,
@@ -185,7 +186,32 @@ pub enum KeyCode {
     RMeta, // 0xE7
 }
 
+// the keys `src/keys.json` names for keymap files, made into
+// `pub const KEYCODES: &[(KeyCode, u8)]` by build.rs
+include!(concat!(env!("OUT_DIR"), "/keys.rs"));
+
+// each with the HID usage of its variant, the test checks none is missing
+const _: () = {
+    let mut i = 0;
+    while i < KEYCODES.len() {
+        assert!(KEYCODES[i].0 as u8 == KEYCODES[i].1);
+        i += 1;
+    }
+};
+
 impl KeyCode {
+    /// The key with HID usage `code`, `None` for a gap in the enum.
+    pub fn from_u8(code: u8) -> Option<KeyCode> {
+        if code > KeyCode::ExSel as u8
+            && !(KeyCode::LCtrl as u8..=KeyCode::RMeta as u8).contains(&code)
+        {
+            return None;
+        }
+        // SAFETY: `KeyCode` is `repr(u8)` and has no gaps below `ExSel` nor
+        // between `LCtrl` and `RMeta`.
+        Some(unsafe { core::mem::transmute::<u8, KeyCode>(code) })
+    }
+
     pub fn is_modifier(self) -> bool {
         self >= KeyCode::LCtrl && self <= KeyCode::RMeta
     }
@@ -193,6 +219,30 @@ impl KeyCode {
     pub fn is_normal_key(self) -> bool {
         self >= KeyCode::A && self <= KeyCode::ExSel
     }
+
+    /// The modifier bit of a modifier key, empty for any other key.
+    pub fn as_modifier(self) -> Modifiers {
+        if self.is_modifier() {
+            Modifiers::from_bits_retain(1 << (self as u8 - KeyCode::LCtrl as u8))
+        } else {
+            Modifiers::empty()
+        }
+    }
+}
+
+bitflags::bitflags! {
+    /// HID report modifier byte, bit order follows `LCtrl` .. `RMeta`.
+    #[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+    pub struct Modifiers: u8 {
+        const LCTRL = 1 << 0;
+        const LSHIFT = 1 << 1;
+        const LALT = 1 << 2;
+        const LMETA = 1 << 3;
+        const RCTRL = 1 << 4;
+        const RSHIFT = 1 << 5;
+        const RALT = 1 << 6;
+        const RMETA = 1 << 7;
+    }
 }
 
 #[derive(PartialEq, Copy, Clone, Debug)]
@@ -205,3 +255,29 @@ pub enum MouseCode {
     BTN6,
     BTN7,
 }
+
+impl MouseCode {
+    pub fn from_u8(code: u8) -> Option<MouseCode> {
+        use MouseCode::*;
+        [BTN1, BTN2, BTN3, BTN4, BTN5, BTN6, BTN7]
+            .into_iter()
+            .find(|mouse| *mouse as u8 == code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keycodes_listed() {
+        let variants = (0..=u8::MAX).filter_map(KeyCode::from_u8);
+        for keycode in variants.clone() {
+            assert!(
+                KEYCODES.iter().any(|(listed, _)| *listed == keycode),
+                "{keycode:?}"
+            );
+        }
+        assert_eq!(variants.count(), KEYCODES.len());
+    }
+}
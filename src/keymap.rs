@@ -0,0 +1,263 @@
+//! Keymap and macros kept in their own flash sector, see `memory.x`, so
+//! they can be changed without a rebuild. The compiled-in `layout::LAYERS`
+//! and `layout::MACROS` are used whenever no valid copy is stored.
+//!
+//! Image: header, one 4 byte record per action, the macro buffer, CRC-32
+//! of all before it.
+//!
+//! | offset     | size | field                          |
+//! |------------|------|--------------------------------|
+//! | 0          | 4    | `MAGIC`                        |
+//! | 4          | 2    | `VERSION`, little endian       |
+//! | 6          | 1    | layer count                    |
+//! | 7          | 1    | keys per layer                 |
+//! | 8          | 4 n  | actions, layer by layer        |
+//! | 8 + 4n     | m    | [`MacroBuffer`]                |
+//! | 8 + 4n + m | 4    | CRC-32 (IEEE), little endian   |
+
+#![deny(unsafe_code)]
+
+use crate::{
+    action::Action,
+    keycodes::{KeyCode, Modifiers, MouseCode},
+    layout::{LayerNumber, Layout, LAYERS},
+    macros::{MacroBuffer, MACRO_BUFFER_LEN, MACRO_COUNT},
+};
+use tpkb50_protocol::image::crc32;
+
+pub type Keymap = [Layout; LAYERS.len()];
+
+/// Offset of the keymap sector from the start of flash.
+pub const KEYMAP_OFFSET: usize = 0x2_0000;
+/// Sector 5, the last 128K of the STM32F401's 256K.
+pub const KEYMAP_SECTOR: u8 = 5;
+
+pub const MAGIC: [u8; 4] = *b"TPKM";
+/// Bumped whenever the image or the action encoding changes.
+pub const VERSION: u16 = 2;
+
+const HEADER_LEN: usize = 8;
+const ACTION_LEN: usize = 4;
+const KEYS: usize = LAYERS[0].len();
+const MACROS_OFFSET: usize = HEADER_LEN + LAYERS.len() * KEYS * ACTION_LEN;
+/// Size of a complete image.
+pub const IMAGE_LEN: usize = MACROS_OFFSET + MACRO_BUFFER_LEN + 4;
+
+/// The keymap and macros stored in `data`, `None` if missing, corrupt or
+/// written for another version or keyboard.
+pub fn load(data: &[u8]) -> Option<(Keymap, MacroBuffer)> {
+    let image = data.get(..IMAGE_LEN)?;
+    let (body, crc) = image.split_at(IMAGE_LEN - 4);
+    if body[..4] != MAGIC
+        || u16::from_le_bytes([body[4], body[5]]) != VERSION
+        || body[6] as usize != LAYERS.len()
+        || body[7] as usize != KEYS
+        || crc32(body).to_le_bytes() != crc
+    {
+        return None;
+    }
+    let mut keymap = LAYERS;
+    let mut records = body[HEADER_LEN..MACROS_OFFSET].chunks_exact(ACTION_LEN);
+    for layer in keymap.iter_mut() {
+        for action in layer.iter_mut() {
+            let record = records.next()?;
+            *action = decode([record[0], record[1], record[2], record[3]])?;
+        }
+    }
+    let mut macros = [0; MACRO_BUFFER_LEN];
+    macros.copy_from_slice(&body[MACROS_OFFSET..]);
+    Some((keymap, macros))
+}
+
+/// Image of `keymap` and `macros` to be written at `KEYMAP_OFFSET`.
+pub fn store(keymap: &Keymap, macros: &MacroBuffer) -> [u8; IMAGE_LEN] {
+    let mut image = [0; IMAGE_LEN];
+    image[..4].copy_from_slice(&MAGIC);
+    image[4..6].copy_from_slice(&VERSION.to_le_bytes());
+    image[6] = LAYERS.len() as u8;
+    image[7] = KEYS as u8;
+    let records = image[HEADER_LEN..MACROS_OFFSET].chunks_exact_mut(ACTION_LEN);
+    for (record, action) in records.zip(keymap.iter().flatten()) {
+        record.copy_from_slice(&encode(*action));
+    }
+    image[MACROS_OFFSET..IMAGE_LEN - 4].copy_from_slice(macros);
+    let crc = crc32(&image[..IMAGE_LEN - 4]);
+    image[IMAGE_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+    image
+}
+
+/// Record of `action`: tag and up to three argument bytes.
+pub fn encode(action: Action) -> [u8; ACTION_LEN] {
+    match action {
+        Action::Nop => [0, 0, 0, 0],
+        Action::Transparent => [1, 0, 0, 0],
+        Action::Key(code) => [2, code as u8, 0, 0],
+        Action::ModifiedKey(mods, code) => [3, code as u8, mods.bits(), 0],
+        Action::LayerTapKey(layer, code) => [4, code as u8, layer as u8, 0],
+        Action::ModTap(mods, code) => [5, code as u8, mods.bits(), 0],
+        Action::OneShotMod(mods) => [6, 0, mods.bits(), 0],
+        Action::LayerMomentary(layer) => [7, 0, layer as u8, 0],
+        Action::LayerToggle(layer) => [8, 0, layer as u8, 0],
+        Action::LayerTo(layer) => [9, 0, layer as u8, 0],
+        Action::DefaultLayer(layer) => [10, 0, layer as u8, 0],
+        Action::OneShotLayer(layer) => [11, 0, layer as u8, 0],
+        Action::LayerMod(layer, mods) => [12, 0, layer as u8, mods.bits()],
+        Action::LayerLock => [13, 0, 0, 0],
+        Action::Mouse(code) => [14, code as u8, 0, 0],
+        Action::Leader => [15, 0, 0, 0],
+        Action::Macro(index) => {
+            let [low, high] = (index as u16).to_le_bytes();
+            [16, low, high, 0]
+        }
+        Action::DynamicMacroRecord => [17, 0, 0, 0],
+        Action::DynamicMacroStop => [18, 0, 0, 0],
+        Action::DynamicMacroPlay => [19, 0, 0, 0],
+        Action::CapsWord => [20, 0, 0, 0],
+        Action::Repeat => [21, 0, 0, 0],
+        Action::AltRepeat => [22, 0, 0, 0],
+        Action::Bootloader => [23, 0, 0, 0],
+    }
+}
+
+/// Action of a record, `None` for anything `encode` can't have written.
+pub fn decode(record: [u8; ACTION_LEN]) -> Option<Action> {
+    let [tag, code, arg, arg2] = record;
+    let key = || KeyCode::from_u8(code);
+    let layer = || LayerNumber::from_u8(arg);
+    let mods = Modifiers::from_bits_retain(arg);
+    let action = match tag {
+        0 => Action::Nop,
+        1 => Action::Transparent,
+        2 => Action::Key(key()?),
+        3 => Action::ModifiedKey(mods, key()?),
+        4 => Action::LayerTapKey(layer()?, key()?),
+        5 => Action::ModTap(mods, key()?),
+        6 => Action::OneShotMod(mods),
+        7 => Action::LayerMomentary(layer()?),
+        8 => Action::LayerToggle(layer()?),
+        9 => Action::LayerTo(layer()?),
+        10 => Action::DefaultLayer(layer()?),
+        11 => Action::OneShotLayer(layer()?),
+        12 => Action::LayerMod(layer()?, Modifiers::from_bits_retain(arg2)),
+        13 => Action::LayerLock,
+        14 => Action::Mouse(MouseCode::from_u8(code)?),
+        15 => Action::Leader,
+        16 => match u16::from_le_bytes([code, arg]) as usize {
+            index if index < MACRO_COUNT => Action::Macro(index),
+            _ => return None,
+        },
+        17 => Action::DynamicMacroRecord,
+        18 => Action::DynamicMacroStop,
+        19 => Action::DynamicMacroPlay,
+        20 => Action::CapsWord,
+        21 => Action::Repeat,
+        22 => Action::AltRepeat,
+        23 => Action::Bootloader,
+        _ => return None,
+    };
+    Some(action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycodes::KeyCode::*;
+
+    /// Every kind of action, with the highest layer, macro and modifiers.
+    const ACTIONS: [Action; 24] = [
+        Action::Nop,
+        Action::Transparent,
+        Action::Key(RMeta),
+        Action::ModifiedKey(Modifiers::all(), ExSel),
+        Action::LayerTapKey(LayerNumber::LN31, Space),
+        Action::ModTap(Modifiers::RSHIFT, A),
+        Action::OneShotMod(Modifiers::LCTRL),
+        Action::LayerMomentary(LayerNumber::LN1),
+        Action::LayerToggle(LayerNumber::LN2),
+        Action::LayerTo(LayerNumber::LN3),
+        Action::DefaultLayer(LayerNumber::LN0),
+        Action::OneShotLayer(LayerNumber::LN4),
+        Action::LayerMod(LayerNumber::LN5, Modifiers::LALT),
+        Action::LayerLock,
+        Action::Mouse(MouseCode::BTN7),
+        Action::Leader,
+        Action::Macro(MACRO_COUNT - 1),
+        Action::DynamicMacroRecord,
+        Action::DynamicMacroStop,
+        Action::DynamicMacroPlay,
+        Action::CapsWord,
+        Action::Repeat,
+        Action::AltRepeat,
+        Action::Bootloader,
+    ];
+
+    fn keymap() -> Keymap {
+        let mut keymap = LAYERS;
+        for (i, action) in keymap.iter_mut().flatten().enumerate() {
+            *action = ACTIONS[i % ACTIONS.len()];
+        }
+        keymap
+    }
+
+    /// `image` with `byte` changed and the CRC made to match again.
+    fn patched(mut image: [u8; IMAGE_LEN], byte: usize, value: u8) -> [u8; IMAGE_LEN] {
+        image[byte] = value;
+        let crc = crc32(&image[..IMAGE_LEN - 4]);
+        image[IMAGE_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn round_trip() {
+        for action in ACTIONS {
+            assert_eq!(decode(encode(action)), Some(action));
+        }
+        let mut macros = [0; MACRO_BUFFER_LEN];
+        macros[..4].copy_from_slice(b"ab\0c");
+        let image = store(&keymap(), &macros);
+        assert_eq!(load(&image), Some((keymap(), macros)));
+        // data past the image is left alone
+        let mut sector = [0xFF; IMAGE_LEN + 16];
+        sector[..IMAGE_LEN].copy_from_slice(&image);
+        assert_eq!(load(&sector), Some((keymap(), macros)));
+    }
+
+    #[test]
+    fn bad_images() {
+        let image = store(&keymap(), &[0; MACRO_BUFFER_LEN]);
+        assert!(load(&image[..IMAGE_LEN - 1]).is_none());
+        // a flipped bit anywhere, CRC included
+        for byte in [0, 7, HEADER_LEN, MACROS_OFFSET, IMAGE_LEN - 1] {
+            let mut bad = image;
+            bad[byte] ^= 1;
+            assert!(load(&bad).is_none(), "byte {byte}");
+        }
+        // with a matching CRC
+        assert!(load(&patched(image, 0, b'X')).is_none());
+        assert!(load(&patched(image, 4, VERSION as u8 + 1)).is_none());
+        assert!(load(&patched(image, 6, LAYERS.len() as u8 + 1)).is_none());
+        assert!(load(&patched(image, 7, KEYS as u8 - 1)).is_none());
+        // a record `encode` can't have written
+        assert!(load(&patched(image, HEADER_LEN, 24)).is_none());
+    }
+
+    #[test]
+    fn bad_records() {
+        let bad_key = RMeta as u8 + 1;
+        for record in [
+            [24, 0, 0, 0],
+            [u8::MAX, 0, 0, 0],
+            [2, bad_key, 0, 0],
+            [3, bad_key, 0, 0],
+            [4, A as u8, 32, 0],
+            [4, bad_key, 1, 0],
+            [7, 0, 32, 0],
+            [12, 0, u8::MAX, 0],
+            [14, 0, 0, 0],
+            [16, MACRO_COUNT as u8, 0, 0],
+            [16, 0, 1, 0],
+        ] {
+            assert_eq!(decode(record), None, "{record:?}");
+        }
+    }
+}
@@ -1,43 +1,79 @@
-//! Custom 4 rows, just let key matrix as simple as possible.
+//! Key matrix of the board, see [`crate::board`], as simple as possible.
 
 use bit_field::BitArray;
 use hal::gpio::{EPin, Input, Output};
 use stm32f4xx_hal as hal;
 
-pub const ROWS: usize = 4;
-pub const COLUMNS: usize = 13;
-type RowPins = [EPin<Input>; ROWS];
-type ColumnPins = [EPin<Output>; COLUMNS];
+use crate::board::{Diodes, DIODES, INPUTS, OUTPUTS};
+pub use crate::board::{COLUMNS, ROWS};
+
+type InputPins = [EPin<Input>; INPUTS];
+type OutputPins = [EPin<Output>; OUTPUTS];
 
 // State of the scan matrix
-pub const KEYBYTES: usize = 7; // ROWS * COLUMNS / 8 round up
+pub const KEYBYTES: usize = (ROWS * COLUMNS).div_ceil(8);
 pub type KeyState = [u8; KEYBYTES];
 
+/// Scans, i.e. ms, a changed matrix must stay the same before it is taken.
+pub const DEBOUNCE: u8 = 5;
+
+/// Index of the key at `row`, `column` in [`KeyState`] and the layouts.
+pub const fn key_index(row: usize, column: usize) -> usize {
+    row * COLUMNS + column
+}
+
 pub struct KeyMatrix {
-    // Stores the currently pressed down keys from last sample.
+    // Stores the currently pressed down keys, debounced.
     pub state: KeyState,
-    row_pins: RowPins,
-    column_pins: ColumnPins,
+    /// Last sample and the samples since that were the same.
+    raw: KeyState,
+    stable: u8,
+    debounce: u8,
+    output_pins: OutputPins,
+    input_pins: InputPins,
 }
 
 impl KeyMatrix {
-    pub fn new(row_pins: RowPins, column_pins: ColumnPins) -> Self {
+    pub fn new(output_pins: OutputPins, input_pins: InputPins) -> Self {
         Self {
             state: [0; KEYBYTES],
-            row_pins,
-            column_pins,
+            raw: [0; KEYBYTES],
+            stable: 0,
+            debounce: DEBOUNCE,
+            output_pins,
+            input_pins,
         }
     }
 
+    /// Override `DEBOUNCE`, e.g. from the stored settings.
+    pub fn set_debounce(&mut self, scans: u8) {
+        self.debounce = scans;
+    }
+
+    /// Sample the matrix, called every ms. A change shows once the same
+    /// sample came `debounce` more times in a row.
     pub fn current_state(&mut self) -> KeyState {
-        for column in 0..COLUMNS {
-            self.column_pins[column].set_high();
+        let mut raw = [0; KEYBYTES];
+        for output in 0..OUTPUTS {
+            self.output_pins[output].set_high();
             cortex_m::asm::delay(1000); // empirical time
-            for i in 0..ROWS {
-                self.state
-                    .set_bit(i * COLUMNS + column, self.row_pins[i].is_high());
+            for input in 0..INPUTS {
+                let (row, column) = match DIODES {
+                    Diodes::ColumnToRow => (input, output),
+                    Diodes::RowToColumn => (output, input),
+                };
+                raw.set_bit(key_index(row, column), self.input_pins[input].is_high());
             }
-            self.column_pins[column].set_low();
+            self.output_pins[output].set_low();
+        }
+        if raw != self.raw {
+            self.raw = raw;
+            self.stable = 0;
+        } else {
+            self.stable = self.stable.saturating_add(1);
+        }
+        if self.stable >= self.debounce {
+            self.state = raw;
         }
         self.state
     }
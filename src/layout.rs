@@ -1,25 +1,206 @@
 //! Included custom mouse and special char keys in L1, L2.
+//! L3 adjusts system keys, reached with L1 + L2.
 
 use crate::{
     action::Action,
-    keycodes::{KeyCode::*, MouseCode::*},
-    keymatrix::{COLUMNS, ROWS},
+    keyboard::{
+        AutoShiftConfig, Combo, ConditionalLayer, KeyOverride, LeaderSequence, TapHoldConfig,
+    },
+    keycodes::{KeyCode, KeyCode::*, Modifiers, MouseCode::*},
+    keymatrix::{key_index, COLUMNS, ROWS},
+    macros::{Macro, MacroStep::*},
 };
 
 pub type Layout = [Action; COLUMNS * ROWS];
 
 #[derive(Copy, Clone, PartialEq, Debug)]
+#[repr(u8)]
 pub enum LayerNumber {
+    LN0 = 0,
     LN1 = 1,
     LN2 = 2,
+    LN3,
+    LN4,
+    LN5,
+    LN6,
+    LN7,
+    LN8,
+    LN9,
+    LN10,
+    LN11,
+    LN12,
+    LN13,
+    LN14,
+    LN15,
+    LN16,
+    LN17,
+    LN18,
+    LN19,
+    LN20,
+    LN21,
+    LN22,
+    LN23,
+    LN24,
+    LN25,
+    LN26,
+    LN27,
+    LN28,
+    LN29,
+    LN30,
+    LN31,
 }
-pub const LAYERS: [Layout; 3] = [L0, L1, L2];
+
+impl LayerNumber {
+    const ALL: [LayerNumber; 32] = {
+        use LayerNumber::*;
+        [
+            LN0, LN1, LN2, LN3, LN4, LN5, LN6, LN7, LN8, LN9, LN10, LN11, LN12, LN13, LN14, LN15,
+            LN16, LN17, LN18, LN19, LN20, LN21, LN22, LN23, LN24, LN25, LN26, LN27, LN28, LN29,
+            LN30, LN31,
+        ]
+    };
+
+    pub fn from_u8(layer: u8) -> Option<LayerNumber> {
+        LayerNumber::ALL.get(layer as usize).copied()
+    }
+}
+
+// the layers themselves are in `keymap.json`, made into
+// `pub const LAYERS: [Layout; N]` by build.rs, which also checks that the
+// `Action` consts `src/keys.json` lists are all here
+include!(concat!(env!("OUT_DIR"), "/layers.rs"));
+
+// L3 comes on while both L1 and L2 are held, the thumb keys are TRNS in
+// L1/L2 so the other layer key stays reachable (its tap is still Space/Tab)
+pub const CONDITIONAL_LAYERS: &[ConditionalLayer] = &[ConditionalLayer {
+    if_all: &[LayerNumber::LN1, LayerNumber::LN2],
+    then: LayerNumber::LN3,
+}];
 
 // activate by indexing into LAYERS
 const LTKT: Action = Action::LayerTapKey(LayerNumber::LN1, Tab);
 const LTKS: Action = Action::LayerTapKey(LayerNumber::LN2, Space);
 
 const TRNS: Action = Action::Transparent;
+const LEAD: Action = Action::Leader;
+const CAPW: Action = Action::CapsWord;
+const REPT: Action = Action::Repeat;
+const AREP: Action = Action::AltRepeat;
+const BOOT: Action = Action::Bootloader;
+
+// record keys on the fly and play them back
+const DMRC: Action = Action::DynamicMacroRecord;
+const DMST: Action = Action::DynamicMacroStop;
+const DMPL: Action = Action::DynamicMacroPlay;
+
+// one-shot modifiers, held they work as usual
+const OSLS: Action = Action::OneShotMod(Modifiers::LSHIFT);
+const OSRS: Action = Action::OneShotMod(Modifiers::RSHIFT);
+const OSLM: Action = Action::OneShotMod(Modifiers::LMETA);
+const OSRM: Action = Action::OneShotMod(Modifiers::RMETA);
+const OSLA: Action = Action::OneShotMod(Modifiers::LALT);
+const OSRA: Action = Action::OneShotMod(Modifiers::RALT);
+
+// keys pressed together
+pub const COMBOS: &[Combo] = &[
+    // J + K
+    Combo {
+        keys: &[key_index(1, 8), key_index(1, 9)],
+        action: Action::Key(Escape),
+        timeout: 40,
+        in_order: false,
+    },
+];
+
+// shifted keys sending something else
+pub const KEY_OVERRIDES: &[KeyOverride] = &[
+    KeyOverride {
+        mods: Modifiers::LSHIFT,
+        key: BSpace,
+        replacement: Action::Key(Delete),
+    },
+    KeyOverride {
+        mods: Modifiers::LSHIFT,
+        key: Comma,
+        replacement: Action::Key(SColon),
+    },
+];
+
+// key taps after Leader, given up after LEADER_TIMEOUT ms without a tap
+pub const LEADER_SEQUENCES: &[LeaderSequence] = &[
+    LeaderSequence {
+        keys: &[C],
+        action: C.lctrl(),
+    },
+    LeaderSequence {
+        keys: &[V],
+        action: V.lctrl(),
+    },
+    LeaderSequence {
+        keys: &[X],
+        action: X.lctrl(),
+    },
+    LeaderSequence {
+        keys: &[D],
+        action: Action::Macro(0),
+    },
+];
+
+pub const MACROS: &[Macro] = &[
+    // duplicate the selection
+    &[
+        Press(LCtrl),
+        Tap(C),
+        Release(LCtrl),
+        Delay(50),
+        Press(LCtrl),
+        Tap(V),
+        Tap(V),
+        Release(LCtrl),
+    ],
+];
+pub const LEADER_TIMEOUT: u16 = 1000;
+
+// long press for shifted letters and digits, off by default
+pub const AUTO_SHIFT: AutoShiftConfig = AutoShiftConfig {
+    enabled: false,
+    timeout: 175,
+    excluded: &[],
+};
+
+// pairs for AltRepeat, either key gives the other one
+pub const ALT_REPEAT: &[(Action, Action)] = &[
+    (SKN9, SKN0),
+    (LBracket.to_action(), RBracket.to_action()),
+    (LBracket.lshift(), RBracket.lshift()),
+    (Comma.lshift(), Dot.lshift()),
+    (Left.to_action(), Right.to_action()),
+    (Up.to_action(), Down.to_action()),
+    (Home.to_action(), End.to_action()),
+    (PgUp.to_action(), PgDown.to_action()),
+];
+
+// keys that keep Caps Word on besides letters
+pub const CAPS_WORD_CONTINUE: &[KeyCode] = &[
+    Minus, BSpace, Delete, N1, N2, N3, N4, N5, N6, N7, N8, N9, N0,
+];
+
+// Caps Word turns off after this many ms without a key
+pub const CAPS_WORD_TIMEOUT: u16 = 5000;
+
+// tapped one-shot modifiers are dropped after this many ms
+pub const ONE_SHOT_TIMEOUT: u16 = 1000;
+
+// tap/hold decision of LayerTapKey and ModTap, times in ms
+pub const TAP_HOLD: TapHoldConfig = TapHoldConfig {
+    tapping_term: 200,
+    tapping_terms: &[],
+    quick_tap_term: 120,
+    require_prior_idle: 0,
+    // a long lone press of LTKT/LTKS still sends Tab/Space
+    retro_tapping: true,
+    permissive_hold: true,
+};
 
 // mouse key
 const MSB1: Action = Action::Mouse(BTN1);
@@ -31,34 +212,26 @@ const WHLT: Action = Action::Mouse(BTN6);
 const WHRT: Action = Action::Mouse(BTN7);
 
 // special chars
-const SKN0: Action = Action::ShiftKey(N0);
-const SKN1: Action = Action::ShiftKey(N1);
-const SKN2: Action = Action::ShiftKey(N2);
-const SKN3: Action = Action::ShiftKey(N3);
-const SKN4: Action = Action::ShiftKey(N4);
-const SKN5: Action = Action::ShiftKey(N5);
-const SKN6: Action = Action::ShiftKey(N6);
-const SKN7: Action = Action::ShiftKey(N7);
-const SKN8: Action = Action::ShiftKey(N8);
-const SKN9: Action = Action::ShiftKey(N9);
-
-pub const L0: Layout = layout![
-    Escape   Q        W        E        R        T        Y        U        I        O        P        LBracket RBracket
-    LCtrl    A        S        D        F        G        No       H        J        K        L        SColon   Enter
-    Minus    Quote    Z        X        C        V        B        N        M        Comma    Dot      Slash    Equal
-    LShift   Grave    LMeta    RMeta    LTKT     Quote    No       BSpace   LTKS     LAlt     RAlt     BSlash   RShift
-];
+const SKN0: Action = N0.lshift();
+const SKN1: Action = N1.lshift();
+const SKN2: Action = N2.lshift();
+const SKN3: Action = N3.lshift();
+const SKN4: Action = N4.lshift();
+const SKN5: Action = N5.lshift();
+const SKN6: Action = N6.lshift();
+const SKN7: Action = N7.lshift();
+const SKN8: Action = N8.lshift();
+const SKN9: Action = N9.lshift();
 
-pub const L1: Layout = layout![
-    TRNS     SKN2     SKN3     SKN4     SKN5     SKN6     SKN7     SKN8     SKN9     SKN0     SKN1     TRNS     TRNS
-    TRNS     N2       N3       N4       N5       N6       No       N7       N8       N9       N0       N1       TRNS
-    F1       F2       F3       F4       F5       F6       No       F7       F8       F9       F10      F11      F12
-    TRNS     VolDown  TRNS     TRNS     TRNS     TRNS     No       TRNS     Space    TRNS     TRNS     VolUp    TRNS
-];
+#[cfg(test)]
+mod tests {
+    use super::LayerNumber;
 
-pub const L2: Layout = layout![
-    TRNS     No       No       No       PgUp     WHRT     PScreen  WHUP     Up       MSB3     MSB2     MSB1     Delete
-    TRNS     No       Home     PgDown   End      WHLT     No       WHDN     Left     Down     Right    No       TRNS
-    F1       F2       F3       F4       F5       F6       No       F7       F8       F9       F10      F11      F12
-    TRNS     VolDown  TRNS     TRNS     Tab      TRNS     No       TRNS     TRNS     TRNS     TRNS     VolUp    TRNS
-];
+    #[test]
+    fn layer_number_from_u8() {
+        for layer in 0..=u8::MAX {
+            let number = LayerNumber::from_u8(layer).map(|number| number as u8);
+            assert_eq!(number, (layer < 32).then_some(layer));
+        }
+    }
+}
@@ -2,8 +2,20 @@
 
 #[macro_use]
 pub mod action;
+pub mod board;
+pub mod bootloader;
+pub mod config;
+#[cfg(feature = "console")]
+pub mod console;
+pub mod dfu;
+pub mod flash;
 pub mod keyboard;
 pub mod keycodes;
+pub mod keymap;
 pub mod keymatrix;
 pub mod layout;
+pub mod macros;
+pub mod settings;
 pub mod trackpoint;
+pub mod usb;
+pub mod via;
@@ -0,0 +1,445 @@
+//! Macros bound with `Action::Macro`, see `layout::MACROS`.
+//! Played a step at a time from `Keyboard::tick`, never blocking the scan.
+//!
+//! At run time macros live in a [`MacroBuffer`] in the format VIA edits
+//! them in, one after the other, each ended by a 0 byte:
+//! - `PREFIX`, `TAP`/`DOWN`/`UP`, HID keycode,
+//! - `PREFIX`, `TAP_EXT`/`DOWN_EXT`/`UP_EXT`, VIA keycode (u16 LE),
+//! - `PREFIX`, `DELAY`, ms in ASCII digits, `|`,
+//! - any other byte is an ASCII char typed with a US layout.
+//!
+//! `layout::MACROS` are encoded into the buffer at compile time.
+
+#![deny(unsafe_code)]
+
+use crate::{
+    action::Action,
+    keycodes::{KeyCode, KeyCode::*, Modifiers},
+    layout::MACROS,
+    via,
+};
+
+pub type Macro = &'static [MacroStep];
+
+pub enum MacroStep {
+    Press(KeyCode),
+    Release(KeyCode),
+    Tap(KeyCode),
+    /// Wait ms, counted once the reports so far have reached the host.
+    Delay(u16),
+    /// ASCII typed with a US layout, other chars are skipped.
+    Text(&'static str),
+}
+
+pub const MACRO_BUFFER_LEN: usize = 512;
+pub type MacroBuffer = [u8; MACRO_BUFFER_LEN];
+/// Macros offered for editing, `layout::MACROS` are the first ones.
+pub const MACRO_COUNT: usize = 16;
+
+const PREFIX: u8 = 1;
+const TAP: u8 = 1;
+const DOWN: u8 = 2;
+const UP: u8 = 3;
+const DELAY: u8 = 4;
+const TAP_EXT: u8 = 5;
+const DOWN_EXT: u8 = 6;
+const UP_EXT: u8 = 7;
+
+const _: () = assert!(MACROS.len() <= MACRO_COUNT);
+
+/// `MACROS` in the buffer format, the build fails if they don't fit.
+pub const DEFAULT_MACROS: MacroBuffer = encode(MACROS);
+
+const fn encode(macros: &[Macro]) -> MacroBuffer {
+    let mut buffer = [0; MACRO_BUFFER_LEN];
+    let mut pos = 0;
+    let mut m = 0;
+    while m < macros.len() {
+        let steps = macros[m];
+        let mut s = 0;
+        while s < steps.len() {
+            // longest is a delay: prefix, code, 5 digits, `|`
+            let mut bytes = [0; 8];
+            let mut len = 3;
+            let mut text: &[u8] = &[];
+            match steps[s] {
+                MacroStep::Press(code) => bytes = [PREFIX, DOWN, code as u8, 0, 0, 0, 0, 0],
+                MacroStep::Release(code) => bytes = [PREFIX, UP, code as u8, 0, 0, 0, 0, 0],
+                MacroStep::Tap(code) => bytes = [PREFIX, TAP, code as u8, 0, 0, 0, 0, 0],
+                MacroStep::Delay(ms) => {
+                    bytes[0] = PREFIX;
+                    bytes[1] = DELAY;
+                    len = 2;
+                    let mut divisor = 10000;
+                    while divisor > 0 {
+                        if ms >= divisor || divisor == 1 {
+                            bytes[len] = b'0' + (ms / divisor % 10) as u8;
+                            len += 1;
+                        }
+                        divisor /= 10;
+                    }
+                    bytes[len] = b'|';
+                    len += 1;
+                }
+                MacroStep::Text(chars) => {
+                    len = 0;
+                    text = chars.as_bytes();
+                }
+            }
+            let mut i = 0;
+            while i < len {
+                buffer[pos] = bytes[i];
+                pos += 1;
+                i += 1;
+            }
+            let mut i = 0;
+            while i < text.len() {
+                // control chars would read as codes
+                if text[i] > UP_EXT {
+                    buffer[pos] = text[i];
+                    pos += 1;
+                }
+                i += 1;
+            }
+            s += 1;
+        }
+        // end of the macro
+        pos += 1;
+        m += 1;
+    }
+    buffer
+}
+
+/// What the player asks `Keyboard` to do next.
+pub(crate) enum MacroEvent {
+    /// `pressed` changed.
+    Held,
+    Tap(Action),
+}
+
+/// Step of a macro in the buffer.
+enum Step {
+    Down(KeyCode),
+    Up(KeyCode),
+    Tap(Action),
+    Delay(u16),
+    /// Unknown code or char, skipped.
+    Skip,
+    End,
+}
+
+pub(crate) struct MacroPlayer {
+    pub buffer: MacroBuffer,
+    /// Next byte to play, `None` while no macro is playing.
+    pos: Option<usize>,
+    /// End of a running `Delay` step.
+    wait_until: Option<u32>,
+    /// Keys held by `Press` steps, `No` for a free slot.
+    pub pressed: [KeyCode; 6],
+}
+
+impl MacroPlayer {
+    pub const fn new() -> MacroPlayer {
+        MacroPlayer {
+            buffer: DEFAULT_MACROS,
+            pos: None,
+            wait_until: None,
+            pressed: [No; 6],
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.pos.is_some() || self.pressed.iter().any(|code| *code != No)
+    }
+
+    /// Start macro `index`, ignored while another macro is playing.
+    pub fn play(&mut self, index: usize) {
+        if self.is_playing() || index >= MACRO_COUNT {
+            return;
+        }
+        let mut start = 0;
+        for _ in 0..index {
+            match self.buffer[start..].iter().position(|b| *b == 0) {
+                Some(end) => start += end + 1,
+                None => return,
+            }
+        }
+        self.pos = Some(start);
+        self.wait_until = None;
+    }
+
+    /// Next event at `time`, `idle` tells whether all reports are sent.
+    pub fn next(&mut self, time: u32, idle: bool) -> Option<MacroEvent> {
+        while let Some(pos) = self.pos {
+            let (step, len) = self.step(pos);
+            let next = Some(pos + len);
+            match step {
+                Step::Down(code) => {
+                    self.pos = next;
+                    if let Some(slot) = self.pressed.iter_mut().find(|slot| **slot == No) {
+                        *slot = code;
+                    }
+                    return Some(MacroEvent::Held);
+                }
+                Step::Up(code) => {
+                    self.pos = next;
+                    self.release(code);
+                    return Some(MacroEvent::Held);
+                }
+                Step::Tap(action) => {
+                    self.pos = next;
+                    return Some(MacroEvent::Tap(action));
+                }
+                Step::Delay(ms) => match self.wait_until {
+                    None if idle => self.wait_until = Some(time.wrapping_add(ms as u32)),
+                    // `until` reached, in wrapping time
+                    Some(until) if time.wrapping_sub(until) < u32::MAX / 2 => {
+                        self.wait_until = None;
+                        self.pos = next;
+                    }
+                    _ => return None,
+                },
+                Step::Skip => self.pos = next,
+                Step::End => self.pos = None,
+            }
+        }
+        // let go of anything the macro left pressed
+        let code = self.pressed.iter().copied().find(|code| *code != No)?;
+        self.release(code);
+        Some(MacroEvent::Held)
+    }
+
+    fn step(&self, pos: usize) -> (Step, usize) {
+        let bytes = self.buffer.get(pos..).unwrap_or(&[]);
+        let key = |code: u16| match via::from_keycode(code) {
+            Some(Action::Key(code) | Action::ModifiedKey(_, code)) => Some(code),
+            _ => None,
+        };
+        let ext = |low: u8, high: u8| {
+            // VIA writes 0xFF for 0, a 0 would end the macro
+            let byte = |b: u8| if b == 0xFF { 0 } else { b as u16 };
+            byte(low) | byte(high) << 8
+        };
+        match *bytes {
+            [] | [0, ..] => (Step::End, 0),
+            [PREFIX, code @ (TAP | DOWN | UP), kc, ..] => {
+                let step = match (code, KeyCode::from_u8(kc)) {
+                    (_, None) => Step::Skip,
+                    (TAP, Some(kc)) => Step::Tap(kc.to_action()),
+                    (DOWN, Some(kc)) => Step::Down(kc),
+                    (_, Some(kc)) => Step::Up(kc),
+                };
+                (step, 3)
+            }
+            [PREFIX, code @ (TAP_EXT | DOWN_EXT | UP_EXT), low, high, ..] => {
+                let keycode = ext(low, high);
+                let step = match code {
+                    TAP_EXT => via::from_keycode(keycode).map_or(Step::Skip, Step::Tap),
+                    DOWN_EXT => key(keycode).map_or(Step::Skip, Step::Down),
+                    _ => key(keycode).map_or(Step::Skip, Step::Up),
+                };
+                (step, 4)
+            }
+            [PREFIX, DELAY, ..] => {
+                let digits = &bytes[2..];
+                let len = digits.iter().position(|b| *b == b'|' || *b == 0);
+                let len = len.unwrap_or(digits.len());
+                let ms = digits[..len]
+                    .iter()
+                    .filter(|b| b.is_ascii_digit())
+                    .fold(0u16, |ms, b| {
+                        ms.saturating_mul(10).saturating_add((b - b'0') as u16)
+                    });
+                // skip the `|` but never a 0
+                let end = if digits.get(len) == Some(&b'|') {
+                    len + 1
+                } else {
+                    len
+                };
+                (Step::Delay(ms), 2 + end)
+            }
+            [PREFIX, ..] => (Step::Skip, 2),
+            [c, ..] => (ascii_action(c).map_or(Step::Skip, Step::Tap), 1),
+        }
+    }
+
+    fn release(&mut self, code: KeyCode) {
+        if let Some(slot) = self.pressed.iter_mut().find(|slot| **slot == code) {
+            *slot = No;
+        }
+    }
+}
+
+const LETTERS: [KeyCode; 26] = [
+    A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+];
+const DIGITS: [KeyCode; 10] = [N0, N1, N2, N3, N4, N5, N6, N7, N8, N9];
+// shifted digits, by digit
+const DIGIT_SYMBOLS: &[u8; 10] = b")!@#$%^&*(";
+// (plain, shifted, key)
+const PUNCTUATION: [(u8, u8, KeyCode); 11] = [
+    (b'-', b'_', Minus),
+    (b'=', b'+', Equal),
+    (b'[', b'{', LBracket),
+    (b']', b'}', RBracket),
+    (b'\\', b'|', BSlash),
+    (b';', b':', SColon),
+    (b'\'', b'"', Quote),
+    (b'`', b'~', Grave),
+    (b',', b'<', Comma),
+    (b'.', b'>', Dot),
+    (b'/', b'?', Slash),
+];
+
+/// Key and shift typing `c` on a US layout.
+pub fn ascii_action(c: u8) -> Option<Action> {
+    let shifted = |code: KeyCode| code.with(Modifiers::LSHIFT);
+    let action = match c {
+        b'a'..=b'z' => LETTERS[(c - b'a') as usize].to_action(),
+        b'A'..=b'Z' => shifted(LETTERS[(c - b'A') as usize]),
+        b'0'..=b'9' => DIGITS[(c - b'0') as usize].to_action(),
+        b' ' => Space.to_action(),
+        b'\n' => Enter.to_action(),
+        b'\t' => Tab.to_action(),
+        _ => {
+            if let Some(digit) = DIGIT_SYMBOLS.iter().position(|s| *s == c) {
+                shifted(DIGITS[digit])
+            } else {
+                let (plain, _, code) = PUNCTUATION
+                    .iter()
+                    .find(|(plain, shift, _)| *plain == c || *shift == c)?;
+                if *plain == c {
+                    code.to_action()
+                } else {
+                    shifted(*code)
+                }
+            }
+        }
+    };
+    Some(action)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use super::*;
+
+    #[derive(PartialEq, Debug)]
+    enum Played {
+        Tap(Action),
+        /// Keys held by the macro after a `Held` event.
+        Held(Vec<KeyCode>),
+    }
+
+    /// Events until the player waits or is done.
+    fn play(player: &mut MacroPlayer, time: u32) -> Vec<Played> {
+        let mut played = Vec::new();
+        while let Some(event) = player.next(time, true) {
+            played.push(match event {
+                MacroEvent::Tap(action) => Played::Tap(action),
+                MacroEvent::Held => {
+                    let pressed = player.pressed.iter().filter(|code| **code != No);
+                    Played::Held(pressed.copied().collect())
+                }
+            });
+        }
+        played
+    }
+
+    fn player(macros: &[u8]) -> MacroPlayer {
+        let mut player = MacroPlayer::new();
+        player.buffer = [0; MACRO_BUFFER_LEN];
+        player.buffer[..macros.len()].copy_from_slice(macros);
+        player
+    }
+
+    #[test]
+    fn via_format() {
+        let [low, high] = via::to_keycode(C.lctrl()).to_le_bytes();
+        let mut player = player(&[
+            PREFIX,
+            TAP,
+            A as u8,
+            PREFIX,
+            DOWN,
+            LShift as u8,
+            b'x',
+            PREFIX,
+            UP,
+            LShift as u8,
+            PREFIX,
+            DELAY,
+            b'1',
+            b'2',
+            b'|',
+            PREFIX,
+            TAP_EXT,
+            low,
+            high,
+            b'!',
+            0,
+        ]);
+        player.play(0);
+        assert_eq!(
+            play(&mut player, 100),
+            vec![
+                Played::Tap(A.to_action()),
+                Played::Held(vec![LShift]),
+                Played::Tap(X.to_action()),
+                Played::Held(vec![]),
+            ]
+        );
+        assert_eq!(play(&mut player, 111), vec![]);
+        assert_eq!(
+            play(&mut player, 112),
+            vec![Played::Tap(C.lctrl()), Played::Tap(N1.lshift())]
+        );
+        assert!(!player.is_playing());
+    }
+
+    #[test]
+    fn delay_waits_for_the_reports() {
+        let mut player = player(&[PREFIX, DELAY, b'5', b'|', b'a', 0]);
+        player.play(0);
+        assert!(player.next(0, false).is_none());
+        assert!(player.next(3, false).is_none());
+        assert_eq!(play(&mut player, 4), vec![]);
+        assert_eq!(play(&mut player, 8), vec![]);
+        assert_eq!(play(&mut player, 9), vec![Played::Tap(A.to_action())]);
+    }
+
+    #[test]
+    fn released_at_the_end() {
+        let mut player = player(&[PREFIX, DOWN, LCtrl as u8, PREFIX, DOWN, C as u8, 0]);
+        player.play(0);
+        assert_eq!(
+            play(&mut player, 0),
+            vec![
+                Played::Held(vec![LCtrl]),
+                Played::Held(vec![LCtrl, C]),
+                Played::Held(vec![C]),
+                Played::Held(vec![]),
+            ]
+        );
+        assert!(!player.is_playing());
+    }
+
+    #[test]
+    fn macro_index() {
+        let mut player = player(&[b'a', 0, b'b', 0]);
+        // past the macros offered, nothing plays
+        player.play(MACRO_COUNT);
+        assert!(!player.is_playing());
+        player.play(1);
+        // one at a time
+        player.play(0);
+        assert_eq!(play(&mut player, 0), vec![Played::Tap(B.to_action())]);
+        // an empty one ends at once
+        player.play(5);
+        assert_eq!(play(&mut player, 0), vec![]);
+        assert!(!player.is_playing());
+    }
+}
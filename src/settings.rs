@@ -0,0 +1,528 @@
+//! Small key/value store for settings, logged over two flash sectors.
+//!
+//! Values are appended to the active sector, so a setting written again
+//! lands on fresh flash instead of wearing out one place. When the sector
+//! is full the live values are compacted into the other sector, which then
+//! becomes active. Either sector is erased only at the start of such a
+//! compaction, so wear is spread over both.
+//!
+//! Sector: `MAGIC`, sequence number (u32 LE), then records.
+//!
+//! Record, padded with 0xFF to 4 bytes: CRC (low half of the CRC-32 of the
+//! rest, u16 LE), key, value length, value. Length 0 removes the key.
+//!
+//! Power loss never leaves a half written value behind:
+//! - a torn record fails its CRC and is skipped, the older value stays,
+//! - a compacted sector gets its sequence number and then `MAGIC` last,
+//!   until that the previous sector is still the valid one.
+
+#![deny(unsafe_code)]
+
+use tpkb50_protocol::image::crc32;
+
+/// Keys, the store holds at most `MAX_KEYS`.
+pub mod key {
+    /// `TrackPoint::set_sensitivity_factor`, one byte.
+    pub const TP_SENSITIVITY: u8 = 0;
+    /// `TapHoldConfig::tapping_term` in ms, u16 LE.
+    pub const TAPPING_TERM: u8 = 1;
+    /// `KeyMatrix::set_debounce` in ms, one byte.
+    pub const DEBOUNCE: u8 = 2;
+}
+
+pub const MAX_KEYS: usize = 64;
+pub const MAX_VALUE_LEN: usize = 255;
+
+const MAGIC: [u8; 4] = *b"TPKS";
+const SECTOR_HEADER_LEN: usize = 8;
+const RECORD_HEADER_LEN: usize = 4;
+const ERASED: u8 = 0xFF;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Error {
+    /// Erase or write failed, or wrote something else than asked.
+    Flash,
+    /// Key not below `MAX_KEYS`.
+    InvalidKey,
+    /// Empty or longer than `MAX_VALUE_LEN`.
+    InvalidValue,
+    /// The live values don't fit in one sector.
+    Full,
+}
+
+/// Two equally sized, separately erasable sectors, erased to 0xFF.
+pub trait Flash {
+    /// Bytes per sector, at most 64K.
+    fn sector_size(&self) -> usize;
+    fn read(&self, sector: usize, offset: usize, buf: &mut [u8]);
+    /// Program erased bytes only.
+    fn write(&mut self, sector: usize, offset: usize, data: &[u8]) -> Result<(), Error>;
+    fn erase(&mut self, sector: usize) -> Result<(), Error>;
+}
+
+pub struct Settings<F: Flash> {
+    flash: F,
+    /// Sector the records are read from and appended to.
+    active: usize,
+    sequence: u32,
+    /// Offset of the free space in the active sector.
+    end: usize,
+    /// Offset of the latest record of each key, 0 for none.
+    index: [u16; MAX_KEYS],
+}
+
+impl<F: Flash> Settings<F> {
+    /// Mount the store on `flash`, formatting it if neither sector is valid.
+    /// On failure `flash` is handed back, its other regions still usable.
+    pub fn new(flash: F) -> Result<Settings<F>, (F, Error)> {
+        let mut settings = Settings {
+            flash,
+            active: 0,
+            sequence: 0,
+            end: SECTOR_HEADER_LEN,
+            index: [0; MAX_KEYS],
+        };
+        match settings.mount() {
+            Ok(()) => Ok(settings),
+            Err(error) => Err((settings.flash, error)),
+        }
+    }
+
+    fn mount(&mut self) -> Result<(), Error> {
+        let newest = match (self.sector_sequence(0), self.sector_sequence(1)) {
+            (Some(a), Some(b)) if is_newer(b, a) => Some((b, 1)),
+            (Some(a), _) => Some((a, 0)),
+            (None, Some(b)) => Some((b, 1)),
+            (None, None) => None,
+        };
+        match newest {
+            Some((sequence, sector)) => {
+                self.active = sector;
+                self.sequence = sequence;
+                self.scan();
+            }
+            None => {
+                self.flash.erase(0)?;
+                self.commit_sector(0, 1)?;
+                self.sequence = 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// The flash underneath, for its regions outside the two sectors.
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    /// Copy the value of `key` into `buf`, returning its length.
+    pub fn get(&self, key: u8, buf: &mut [u8]) -> Option<usize> {
+        let offset = *self.index.get(key as usize)? as usize;
+        if offset == 0 {
+            return None;
+        }
+        let mut header = [0; RECORD_HEADER_LEN];
+        self.flash.read(self.active, offset, &mut header);
+        let len = header[3] as usize;
+        let value = buf.get_mut(..len)?;
+        self.flash
+            .read(self.active, offset + RECORD_HEADER_LEN, value);
+        Some(len)
+    }
+
+    pub fn get_u8(&self, key: u8) -> Option<u8> {
+        let mut buf = [0; 1];
+        self.get(key, &mut buf)?;
+        Some(buf[0])
+    }
+
+    pub fn get_u16(&self, key: u8) -> Option<u16> {
+        let mut buf = [0; 2];
+        self.get(key, &mut buf)?;
+        Some(u16::from_le_bytes(buf))
+    }
+
+    pub fn set(&mut self, key: u8, value: &[u8]) -> Result<(), Error> {
+        if value.is_empty() || value.len() > MAX_VALUE_LEN {
+            return Err(Error::InvalidValue);
+        }
+        self.put(key, value)
+    }
+
+    pub fn remove(&mut self, key: u8) -> Result<(), Error> {
+        if self.index.get(key as usize) == Some(&0) {
+            return Ok(());
+        }
+        self.put(key, &[])
+    }
+
+    fn put(&mut self, key: u8, value: &[u8]) -> Result<(), Error> {
+        if key as usize >= MAX_KEYS {
+            return Err(Error::InvalidKey);
+        }
+        let mut record = [ERASED; RECORD_HEADER_LEN + MAX_VALUE_LEN + 3];
+        let len = encode_record(key, value, &mut record);
+        if self.end + len <= self.flash.sector_size() {
+            let offset = self.end;
+            // even a failed write may have programmed some bytes
+            self.end += len;
+            if self
+                .write_verified(self.active, offset, &record[..len])
+                .is_ok()
+            {
+                self.index[key as usize] = if value.is_empty() { 0 } else { offset as u16 };
+                return Ok(());
+            }
+        }
+        self.compact(key, &record[..len])
+    }
+
+    /// Move the live values and `record` to the other sector.
+    fn compact(&mut self, key: u8, record: &[u8]) -> Result<(), Error> {
+        let target = 1 - self.active;
+        let size = self.flash.sector_size();
+        self.flash.erase(target)?;
+        let mut index = [0; MAX_KEYS];
+        let mut end = SECTOR_HEADER_LEN;
+        let mut buf = [0; RECORD_HEADER_LEN + MAX_VALUE_LEN + 3];
+        for (k, offset) in self.index.into_iter().enumerate() {
+            if offset == 0 || k == key as usize {
+                continue;
+            }
+            self.flash
+                .read(self.active, offset as usize, &mut buf[..RECORD_HEADER_LEN]);
+            let len = record_len(buf[3]);
+            self.flash
+                .read(self.active, offset as usize, &mut buf[..len]);
+            if end + len > size {
+                return Err(Error::Full);
+            }
+            self.write_verified(target, end, &buf[..len])?;
+            index[k] = end as u16;
+            end += len;
+        }
+        // a removal is done by leaving the key out
+        if record[3] != 0 {
+            if end + record.len() > size {
+                return Err(Error::Full);
+            }
+            self.write_verified(target, end, record)?;
+            index[key as usize] = end as u16;
+            end += record.len();
+        }
+        self.commit_sector(target, self.sequence.wrapping_add(1))?;
+        self.active = target;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.index = index;
+        self.end = end;
+        Ok(())
+    }
+
+    /// Make `sector` valid, sequence first so `MAGIC` only ever marks a
+    /// complete header.
+    fn commit_sector(&mut self, sector: usize, sequence: u32) -> Result<(), Error> {
+        self.write_verified(sector, MAGIC.len(), &sequence.to_le_bytes())?;
+        self.write_verified(sector, 0, &MAGIC)
+    }
+
+    fn sector_sequence(&self, sector: usize) -> Option<u32> {
+        let mut header = [0; SECTOR_HEADER_LEN];
+        self.flash.read(sector, 0, &mut header);
+        if header[..4] != MAGIC {
+            return None;
+        }
+        Some(u32::from_le_bytes([
+            header[4], header[5], header[6], header[7],
+        ]))
+    }
+
+    /// Index the records of the active sector and find its free space.
+    fn scan(&mut self) {
+        let size = self.flash.sector_size();
+        let mut offset = SECTOR_HEADER_LEN;
+        let mut buf = [0; RECORD_HEADER_LEN + MAX_VALUE_LEN + 3];
+        while offset + RECORD_HEADER_LEN <= size {
+            self.flash
+                .read(self.active, offset, &mut buf[..RECORD_HEADER_LEN]);
+            if buf[..RECORD_HEADER_LEN].iter().all(|b| *b == ERASED) {
+                break;
+            }
+            let len = record_len(buf[3]);
+            if offset + len > size {
+                // garbage length, nothing after it can be trusted
+                offset = size;
+                break;
+            }
+            self.flash.read(self.active, offset, &mut buf[..len]);
+            let key = buf[2] as usize;
+            if key < MAX_KEYS && record_crc(&buf[..len]) == [buf[0], buf[1]] {
+                self.index[key] = if buf[3] == 0 { 0 } else { offset as u16 };
+            }
+            offset += len;
+        }
+        self.end = offset;
+    }
+
+    fn write_verified(&mut self, sector: usize, offset: usize, data: &[u8]) -> Result<(), Error> {
+        self.flash.write(sector, offset, data)?;
+        let mut byte = [0];
+        for (i, expected) in data.iter().enumerate() {
+            self.flash.read(sector, offset + i, &mut byte);
+            if byte[0] != *expected {
+                return Err(Error::Flash);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether sequence number `a` came after `b`, also across the wrap from
+/// `u32::MAX` to 0.
+fn is_newer(a: u32, b: u32) -> bool {
+    a.wrapping_sub(b) as i32 > 0
+}
+
+/// Record size of a value `len` bytes long.
+fn record_len(len: u8) -> usize {
+    (RECORD_HEADER_LEN + len as usize + 3) & !3
+}
+
+/// Write the record of `key` and `value` to the start of `buf`, returning
+/// its size.
+fn encode_record(key: u8, value: &[u8], buf: &mut [u8]) -> usize {
+    let len = record_len(value.len() as u8);
+    buf[2] = key;
+    buf[3] = value.len() as u8;
+    buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + value.len()].copy_from_slice(value);
+    let crc = record_crc(&buf[..len]);
+    buf[..2].copy_from_slice(&crc);
+    len
+}
+
+/// CRC of key, length and value of a record, the padding left out.
+fn record_crc(record: &[u8]) -> [u8; 2] {
+    let len = record[3] as usize;
+    (crc32(&record[2..RECORD_HEADER_LEN + len]) as u16).to_le_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use super::*;
+
+    const SECTOR_SIZE: usize = 256;
+
+    /// Flash in RAM that loses power after `budget` more bytes programmed
+    /// or erased. The byte being programmed then only gets some of its
+    /// bits, and nothing after it is touched.
+    #[derive(Clone)]
+    struct Ram {
+        sectors: [Vec<u8>; 2],
+        budget: usize,
+        /// Bytes programmed or erased so far.
+        used: usize,
+        cut: bool,
+    }
+
+    impl Ram {
+        fn new() -> Ram {
+            Ram {
+                sectors: [vec![ERASED; SECTOR_SIZE], vec![ERASED; SECTOR_SIZE]],
+                budget: usize::MAX,
+                used: 0,
+                cut: false,
+            }
+        }
+
+        fn with_budget(&self, budget: usize) -> Ram {
+            Ram {
+                budget,
+                ..self.reboot()
+            }
+        }
+
+        /// The same flash after power comes back.
+        fn reboot(&self) -> Ram {
+            Ram {
+                sectors: self.sectors.clone(),
+                ..Ram::new()
+            }
+        }
+
+        /// Take one byte from the budget, false once the power is gone.
+        fn spend(&mut self) -> bool {
+            if self.cut || self.budget == 0 {
+                return false;
+            }
+            self.budget -= 1;
+            self.used += 1;
+            true
+        }
+    }
+
+    impl Flash for Ram {
+        fn sector_size(&self) -> usize {
+            SECTOR_SIZE
+        }
+
+        fn read(&self, sector: usize, offset: usize, buf: &mut [u8]) {
+            buf.copy_from_slice(&self.sectors[sector][offset..offset + buf.len()]);
+        }
+
+        fn write(&mut self, sector: usize, offset: usize, data: &[u8]) -> Result<(), Error> {
+            for (i, byte) in data.iter().enumerate() {
+                let powered = self.spend();
+                let cell = &mut self.sectors[sector][offset + i];
+                if !powered {
+                    if !self.cut {
+                        *cell &= byte | 0xF0;
+                        self.cut = true;
+                    }
+                    return Err(Error::Flash);
+                }
+                *cell &= byte;
+            }
+            Ok(())
+        }
+
+        fn erase(&mut self, sector: usize) -> Result<(), Error> {
+            for i in 0..SECTOR_SIZE {
+                if !self.spend() {
+                    self.cut = true;
+                    return Err(Error::Flash);
+                }
+                self.sectors[sector][i] = ERASED;
+            }
+            Ok(())
+        }
+    }
+
+    const KEYS: u8 = 4;
+
+    fn mount(ram: Ram) -> Settings<Ram> {
+        Settings::new(ram).ok().expect("mount")
+    }
+
+    fn values(settings: &Settings<Ram>) -> Vec<Option<Vec<u8>>> {
+        (0..KEYS)
+            .map(|key| {
+                let mut buf = [0; MAX_VALUE_LEN];
+                let len = settings.get(key, &mut buf)?;
+                Some(buf[..len].to_vec())
+            })
+            .collect()
+    }
+
+    /// Step `step` of a run of writes over all keys, with removals and
+    /// values of many sizes so the log compacts now and then.
+    fn step(settings: &mut Settings<Ram>, step: u8) -> Result<(), Error> {
+        let key = step % KEYS;
+        if step % 7 == 6 {
+            settings.remove(key)
+        } else {
+            settings.set(key, &[step; 40][..1 + step as usize % 40])
+        }
+    }
+
+    #[test]
+    fn recovery_after_every_cut() {
+        let mut settings = mount(Ram::new());
+        let mut compactions = 0;
+        for i in 0..60 {
+            let before = values(&settings);
+            let flash = settings.flash().reboot();
+            let sequence = settings.sequence;
+            settings.flash().used = 0;
+            step(&mut settings, i).unwrap();
+            let after = values(&settings);
+            compactions += settings.sequence.wrapping_sub(sequence);
+            for budget in 0..settings.flash().used {
+                let mut torn = mount(flash.with_budget(budget));
+                assert!(step(&mut torn, i).is_err());
+
+                let mut recovered = mount(torn.flash().reboot());
+                let got = values(&recovered);
+                for key in 0..KEYS as usize {
+                    let old = got[key] == before[key];
+                    let new = got[key] == after[key];
+                    assert!(old || new, "step {i}, cut at {budget}, key {key}");
+                    if key != (i % KEYS) as usize {
+                        assert!(old, "step {i}, cut at {budget}, key {key}");
+                    }
+                }
+                // and the store goes on working
+                step(&mut recovered, i).unwrap();
+                assert_eq!(values(&mount(recovered.flash().reboot())), after);
+            }
+        }
+        assert!(compactions >= 3);
+    }
+
+    #[test]
+    fn compaction_swaps_sectors() {
+        let mut settings = mount(Ram::new());
+        settings.set(key::TP_SENSITIVITY, &[0x80]).unwrap();
+        let mut active = settings.active;
+        let mut swaps = 0;
+        for term in 0..200u16 {
+            settings
+                .set(key::TAPPING_TERM, &term.to_le_bytes())
+                .unwrap();
+            if settings.active != active {
+                active = settings.active;
+                swaps += 1;
+            }
+        }
+        assert!(swaps >= 2);
+        assert_eq!(settings.sequence, 1 + swaps);
+
+        let settings = mount(settings.flash().reboot());
+        assert_eq!(settings.active, active);
+        assert_eq!(settings.sequence, 1 + swaps);
+        assert_eq!(settings.get_u8(key::TP_SENSITIVITY), Some(0x80));
+        assert_eq!(settings.get_u16(key::TAPPING_TERM), Some(199));
+    }
+
+    #[test]
+    fn full_log() {
+        // three of these fill a sector
+        let value = |key: u8| [key; 60];
+        let mut settings = mount(Ram::new());
+        for key in 0..3 {
+            settings.set(key, &value(key)).unwrap();
+        }
+        assert_eq!(settings.set(3, &value(3)), Err(Error::Full));
+
+        let expected: Vec<_> = (0..KEYS)
+            .map(|key| (key < 3).then(|| value(key).to_vec()))
+            .collect();
+        assert_eq!(values(&settings), expected);
+        assert_eq!(values(&mount(settings.flash().reboot())), expected);
+
+        // a removal makes room again
+        settings.remove(0).unwrap();
+        settings.set(3, &value(3)).unwrap();
+        let settings = mount(settings.flash().reboot());
+        assert_eq!(settings.get(0, &mut [0; MAX_VALUE_LEN]), None);
+        assert_eq!(values(&settings)[3], Some(value(3).to_vec()));
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        let sector = |sequence: u32| {
+            let mut sector = vec![ERASED; SECTOR_SIZE];
+            sector[..4].copy_from_slice(&MAGIC);
+            sector[4..8].copy_from_slice(&sequence.to_le_bytes());
+            sector
+        };
+        for (a, b, newest) in [(1, 2, 1), (2, 1, 0), (u32::MAX, 0, 1), (0, u32::MAX, 0)] {
+            let mut ram = Ram::new();
+            ram.sectors = [sector(a), sector(b)];
+            let settings = mount(ram);
+            assert_eq!(settings.active, newest, "{a} {b}");
+        }
+    }
+}
@@ -22,9 +22,11 @@ pub struct DataReport {
 const CC_READ_DATA: u8 = 0xEB;
 const CC_SNSTVTY: u8 = 0x4A;
 const CC_RAM: u8 = 0xE2;
+const CC_GET: u8 = 0x80;
 const CC_SET: u8 = 0x81;
 const CC_ENABLE: u8 = 0xF4;
 const CC_STREAM_MODE: u8 = 0xEA;
+const ACK: u8 = 0xFA;
 
 pub const SFACTOR_HIGH: u8 = 0xCC;
 pub type RST = EPin<Output<PushPull>>;
@@ -37,6 +39,9 @@ pub struct TrackPoint {
     incoming: u8,
     counter: u8,
     pub data_available: bool,
+    /// Data reports queried, and those of them not acknowledged.
+    pub packets: u32,
+    pub errors: u32,
 
     pub scl: SCL,
     sda: SDA,
@@ -56,6 +61,8 @@ impl TrackPoint {
             incoming: 0,
             counter: 0,
             data_available: false,
+            packets: 0,
+            errors: 0,
             scl,
             sda,
             rst,
@@ -65,7 +72,10 @@ impl TrackPoint {
 
     pub fn query_data_report(&mut self) -> DataReport {
         self.write(CC_READ_DATA);
-        self.read();
+        self.packets = self.packets.wrapping_add(1);
+        if self.read() != ACK {
+            self.errors = self.errors.wrapping_add(1);
+        }
         DataReport {
             state: self.read(),
             x: self.read() as i8,
@@ -129,6 +139,19 @@ impl TrackPoint {
         self.read();
     }
 
+    pub fn read_from_ram_location(&mut self, location: u8) -> u8 {
+        self.write(CC_RAM);
+        self.read();
+
+        self.write(CC_GET);
+        self.read();
+
+        self.write(location);
+        self.read();
+
+        self.read()
+    }
+
     pub fn set_stream_mode(&mut self) {
         self.write(CC_STREAM_MODE);
         self.read();
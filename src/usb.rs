@@ -0,0 +1,338 @@
+//! The USB device and its interfaces.
+//!
+//! The OTG_FS peripheral has three IN endpoints besides the control one.
+//! Normally they go to the keyboard, the mouse and the raw HID interface.
+//! With the `console` feature a CDC-ACM serial port needs two of them, so
+//! keyboard and mouse share one HID interface, told apart by report ids,
+//! and there is no raw HID interface.
+//!
+//! The DFU runtime interface needs no endpoints and is always there.
+
+use hal::otg_fs::UsbBusType;
+use stm32f4xx_hal as hal;
+use usb_device::{bus::UsbBusAllocator, prelude::*};
+use usbd_hid::{
+    descriptor::{KeyboardReport, MouseReport},
+    hid_class::HIDClass,
+};
+
+#[cfg(feature = "console")]
+use crate::console::{Console, Target};
+use crate::{
+    board::USB,
+    config::Pending,
+    dfu::DfuRuntime,
+    flash::InternalFlash,
+    keyboard::Keyboard,
+    settings::Settings,
+    trackpoint::{DataReport, TrackPoint},
+    via,
+};
+
+type HidDev = HIDClass<'static, UsbBusType>;
+
+fn device(bus: &'static UsbBusAllocator<UsbBusType>) -> UsbDeviceBuilder<'static, UsbBusType> {
+    UsbDeviceBuilder::new(bus, UsbVidPid(USB.vendor_id, USB.product_id))
+        .manufacturer(USB.manufacturer)
+        .product(USB.product)
+        .serial_number(USB.serial_number)
+}
+
+#[cfg(not(feature = "console"))]
+pub struct Usb {
+    device: UsbDevice<'static, UsbBusType>,
+    keyboard: HidDev,
+    mouse: HidDev,
+    raw: HidDev,
+    dfu: DfuRuntime,
+}
+
+#[cfg(not(feature = "console"))]
+impl Usb {
+    pub fn new(bus: &'static UsbBusAllocator<UsbBusType>) -> Usb {
+        use usbd_hid::descriptor::generator_prelude::SerializedDescriptor;
+
+        let keyboard = HIDClass::new(bus, KeyboardReport::desc(), 10);
+        let mouse = HIDClass::new(bus, MouseReport::desc(), 10);
+        let raw = HIDClass::new(bus, via::REPORT_DESCRIPTOR, 10);
+        let dfu = DfuRuntime::new(bus);
+        Usb {
+            device: device(bus).device_class(0).build(),
+            keyboard,
+            mouse,
+            raw,
+            dfu,
+        }
+    }
+
+    pub fn poll(&mut self) {
+        self.device.poll(&mut [
+            &mut self.keyboard,
+            &mut self.mouse,
+            &mut self.raw,
+            &mut self.dfu,
+        ]);
+    }
+
+    /// `false` if the report couldn't be queued and must be sent again.
+    pub fn push_keyboard(&mut self, report: &KeyboardReport) -> bool {
+        self.keyboard.push_input(report).is_ok()
+    }
+
+    pub fn push_mouse(&mut self, report: &MouseReport) {
+        self.mouse.push_input(report).ok();
+    }
+
+    /// Take a report from the raw HID interface, `false` if none came.
+    pub fn pull_raw(&mut self, report: &mut [u8; via::REPORT_LEN]) -> bool {
+        self.raw.pull_raw_output(report).is_ok()
+    }
+
+    pub fn push_raw(&mut self, report: &[u8; via::REPORT_LEN]) {
+        self.raw.push_raw_input(report).ok();
+    }
+
+    /// Whether a DFU host asked to switch to the bootloader.
+    pub fn take_detach_request(&mut self) -> bool {
+        self.dfu.take_detach_request()
+    }
+
+    /// Serve the serial console, there is none in this build.
+    pub fn console(
+        &mut self,
+        _keyboard: &Keyboard,
+        _trackpoint: &TrackPoint,
+        _tp_data: &DataReport,
+        _settings: Option<&mut Settings<InternalFlash>>,
+    ) -> Pending {
+        Pending::None
+    }
+}
+
+#[cfg(feature = "console")]
+pub struct Usb {
+    device: UsbDevice<'static, UsbBusType>,
+    hid: HidDev,
+    serial: usbd_serial::SerialPort<'static, UsbBusType>,
+    console: Console,
+    dfu: DfuRuntime,
+}
+
+#[cfg(feature = "console")]
+impl Usb {
+    pub fn new(bus: &'static UsbBusAllocator<UsbBusType>) -> Usb {
+        let hid = HIDClass::new(bus, COMBINED_REPORT_DESCRIPTOR, 10);
+        let serial = usbd_serial::SerialPort::new(bus);
+        let dfu = DfuRuntime::new(bus);
+        Usb {
+            // CDC-ACM takes two interfaces, grouped by an association descriptor
+            device: device(bus).composite_with_iads().build(),
+            hid,
+            serial,
+            console: Console::new(),
+            dfu,
+        }
+    }
+
+    pub fn poll(&mut self) {
+        self.device
+            .poll(&mut [&mut self.hid, &mut self.serial, &mut self.dfu]);
+    }
+
+    /// `false` if the report couldn't be queued and must be sent again.
+    pub fn push_keyboard(&mut self, report: &KeyboardReport) -> bool {
+        let mut data = [KEYBOARD_REPORT_ID; 9];
+        data[1..3].copy_from_slice(&[report.modifier, report.reserved]);
+        data[3..].copy_from_slice(&report.keycodes);
+        self.hid.push_raw_input(&data).is_ok()
+    }
+
+    pub fn push_mouse(&mut self, report: &MouseReport) {
+        let data = [
+            MOUSE_REPORT_ID,
+            report.buttons,
+            report.x as u8,
+            report.y as u8,
+            report.wheel as u8,
+            report.pan as u8,
+        ];
+        self.hid.push_raw_input(&data).ok();
+    }
+
+    /// Take a report from the raw HID interface, there is none in this
+    /// build.
+    pub fn pull_raw(&mut self, _report: &mut [u8; via::REPORT_LEN]) -> bool {
+        false
+    }
+
+    pub fn push_raw(&mut self, _report: &[u8; via::REPORT_LEN]) {}
+
+    /// Whether a DFU host asked to switch to the bootloader.
+    pub fn take_detach_request(&mut self) -> bool {
+        self.dfu.take_detach_request()
+    }
+
+    /// Serve the serial console, returning what its commands ask for.
+    pub fn console(
+        &mut self,
+        keyboard: &Keyboard,
+        trackpoint: &TrackPoint,
+        tp_data: &DataReport,
+        settings: Option<&mut Settings<InternalFlash>>,
+    ) -> Pending {
+        let mut target = Target {
+            keyboard,
+            trackpoint,
+            tp_data,
+            settings,
+        };
+        let mut input = [0; 64];
+        if let Ok(len) = self.serial.read(&mut input) {
+            self.console.input(&input[..len], &mut target);
+        }
+        self.console.watch(&target);
+        if let Ok(len) = self.serial.write(self.console.output()) {
+            self.console.consume(len);
+        }
+        self.console.take_pending()
+    }
+}
+
+#[cfg(feature = "console")]
+const KEYBOARD_REPORT_ID: u8 = 1;
+#[cfg(feature = "console")]
+const MOUSE_REPORT_ID: u8 = 2;
+
+/// The boot keyboard and the usbd-hid mouse with report ids.
+#[cfg(feature = "console")]
+const COMBINED_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05,
+    0x01, // Usage Page (Generic Desktop)
+    0x09,
+    0x06, // Usage (Keyboard)
+    0xA1,
+    0x01, // Collection (Application)
+    0x85,
+    KEYBOARD_REPORT_ID, // Report ID
+    0x05,
+    0x07, //   Usage Page (Keyboard)
+    0x19,
+    0xE0, //   Usage Minimum (Left Control)
+    0x29,
+    0xE7, //   Usage Maximum (Right GUI)
+    0x15,
+    0x00, //   Logical Minimum (0)
+    0x25,
+    0x01, //   Logical Maximum (1)
+    0x75,
+    0x01, //   Report Size (1)
+    0x95,
+    0x08, //   Report Count (8)
+    0x81,
+    0x02, //   Input (Data, Variable, Absolute), modifiers
+    0x75,
+    0x08, //   Report Size (8)
+    0x95,
+    0x01, //   Report Count (1)
+    0x81,
+    0x01, //   Input (Constant), reserved
+    0x05,
+    0x08, //   Usage Page (LEDs)
+    0x19,
+    0x01, //   Usage Minimum (Num Lock)
+    0x29,
+    0x05, //   Usage Maximum (Kana)
+    0x75,
+    0x01, //   Report Size (1)
+    0x95,
+    0x05, //   Report Count (5)
+    0x91,
+    0x02, //   Output (Data, Variable, Absolute), LEDs
+    0x75,
+    0x03, //   Report Size (3)
+    0x95,
+    0x01, //   Report Count (1)
+    0x91,
+    0x01, //   Output (Constant), padding
+    0x05,
+    0x07, //   Usage Page (Keyboard)
+    0x19,
+    0x00, //   Usage Minimum (0)
+    0x29,
+    0xFF, //   Usage Maximum (255)
+    0x15,
+    0x00, //   Logical Minimum (0)
+    0x26,
+    0xFF,
+    0x00, // Logical Maximum (255)
+    0x75,
+    0x08, //   Report Size (8)
+    0x95,
+    0x06, //   Report Count (6)
+    0x81,
+    0x00, //   Input (Data, Array), keycodes
+    0xC0, // End Collection
+    0x05,
+    0x01, // Usage Page (Generic Desktop)
+    0x09,
+    0x02, // Usage (Mouse)
+    0xA1,
+    0x01, // Collection (Application)
+    0x85,
+    MOUSE_REPORT_ID, // Report ID
+    0x09,
+    0x01, //   Usage (Pointer)
+    0xA1,
+    0x00, //   Collection (Physical)
+    0x05,
+    0x09, //     Usage Page (Button)
+    0x19,
+    0x01, //     Usage Minimum (1)
+    0x29,
+    0x08, //     Usage Maximum (8)
+    0x15,
+    0x00, //     Logical Minimum (0)
+    0x25,
+    0x01, //     Logical Maximum (1)
+    0x75,
+    0x01, //     Report Size (1)
+    0x95,
+    0x08, //     Report Count (8)
+    0x81,
+    0x02, //     Input (Data, Variable, Absolute), buttons
+    0x05,
+    0x01, //     Usage Page (Generic Desktop)
+    0x09,
+    0x30, //     Usage (X)
+    0x09,
+    0x31, //     Usage (Y)
+    0x09,
+    0x38, //     Usage (Wheel)
+    0x15,
+    0x81, //     Logical Minimum (-127)
+    0x25,
+    0x7F, //     Logical Maximum (127)
+    0x75,
+    0x08, //     Report Size (8)
+    0x95,
+    0x03, //     Report Count (3)
+    0x81,
+    0x06, //     Input (Data, Variable, Relative)
+    0x05,
+    0x0C, //     Usage Page (Consumer)
+    0x0A,
+    0x38,
+    0x02, // Usage (AC Pan)
+    0x15,
+    0x81, //     Logical Minimum (-127)
+    0x25,
+    0x7F, //     Logical Maximum (127)
+    0x75,
+    0x08, //     Report Size (8)
+    0x95,
+    0x01, //     Report Count (1)
+    0x81,
+    0x06, //     Input (Data, Variable, Relative)
+    0xC0, //   End Collection
+    0xC0, // End Collection
+];
@@ -0,0 +1,441 @@
+//! VIA configuration protocol over a raw HID interface, so keys and macros
+//! can be remapped from the VIA app. Lighting and encoders are not
+//! supported, their commands are answered as unhandled.
+//!
+//! Every command is a 32 byte report, answered with the same report with
+//! the requested values filled in.
+
+#![deny(unsafe_code)]
+
+use bit_field::BitArray;
+
+use crate::{
+    action::Action,
+    keyboard::Keyboard,
+    keycodes::{KeyCode, Modifiers, MouseCode},
+    keymatrix::{key_index, COLUMNS, ROWS},
+    layout::{LayerNumber, LAYERS},
+    macros::{DEFAULT_MACROS, MACRO_BUFFER_LEN, MACRO_COUNT},
+};
+
+pub const REPORT_LEN: usize = 32;
+/// VIA protocol 12, which uses the QMK keycodes of 2023.
+pub const PROTOCOL_VERSION: u16 = 0x000C;
+
+/// Vendor usage page 0xFF60, usage 0x61, as VIA looks for.
+pub const REPORT_DESCRIPTOR: &[u8] = &[
+    0x06,
+    0x60,
+    0xFF, // Usage Page (0xFF60)
+    0x09,
+    0x61, // Usage (0x61)
+    0xA1,
+    0x01, // Collection (Application)
+    0x09,
+    0x62, // Usage (0x62)
+    0x15,
+    0x00, // Logical Minimum (0)
+    0x26,
+    0xFF,
+    0x00, // Logical Maximum (255)
+    0x95,
+    REPORT_LEN as u8, // Report Count
+    0x75,
+    0x08, // Report Size (8)
+    0x81,
+    0x02, // Input (Data, Variable, Absolute)
+    0x09,
+    0x63, // Usage (0x63)
+    0x15,
+    0x00, // Logical Minimum (0)
+    0x26,
+    0xFF,
+    0x00, // Logical Maximum (255)
+    0x95,
+    REPORT_LEN as u8, // Report Count
+    0x75,
+    0x08, // Report Size (8)
+    0x91,
+    0x02, // Output (Data, Variable, Absolute)
+    0xC0, // End Collection
+];
+
+// command ids
+const GET_PROTOCOL_VERSION: u8 = 0x01;
+const GET_KEYBOARD_VALUE: u8 = 0x02;
+const SET_KEYBOARD_VALUE: u8 = 0x03;
+const KEYMAP_GET_KEYCODE: u8 = 0x04;
+const KEYMAP_SET_KEYCODE: u8 = 0x05;
+const KEYMAP_RESET: u8 = 0x06;
+const EEPROM_RESET: u8 = 0x0A;
+const MACRO_GET_COUNT: u8 = 0x0C;
+const MACRO_GET_BUFFER_SIZE: u8 = 0x0D;
+const MACRO_GET_BUFFER: u8 = 0x0E;
+const MACRO_SET_BUFFER: u8 = 0x0F;
+const MACRO_RESET: u8 = 0x10;
+const KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+const KEYMAP_GET_BUFFER: u8 = 0x12;
+const KEYMAP_SET_BUFFER: u8 = 0x13;
+pub const UNHANDLED: u8 = 0xFF;
+/// Highest id VIA protocol 12 sends, `dynamic_keymap_set_encoder`. Ids
+/// above it are free for other protocols on this interface.
+pub const LAST_COMMAND: u8 = 0x15;
+
+// `GET_KEYBOARD_VALUE` ids
+const UPTIME: u8 = 0x01;
+const LAYOUT_OPTIONS: u8 = 0x02;
+const SWITCH_MATRIX_STATE: u8 = 0x03;
+
+/// Bytes of a command after the id and a u16 offset and a size.
+const BUFFER_DATA_LEN: usize = REPORT_LEN - 4;
+
+/// Answer the command in `data` in place, true if it changed the keymap or
+/// macros, which should then be saved.
+pub fn handle(keyboard: &mut Keyboard, data: &mut [u8; REPORT_LEN]) -> bool {
+    let offset = u16::from_be_bytes([data[1], data[2]]) as usize;
+    let size = (data[3] as usize).min(BUFFER_DATA_LEN);
+    match data[0] {
+        GET_PROTOCOL_VERSION => data[1..3].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes()),
+        GET_KEYBOARD_VALUE => match data[1] {
+            UPTIME => data[2..6].copy_from_slice(&keyboard.uptime().to_be_bytes()),
+            LAYOUT_OPTIONS => data[2..6].fill(0),
+            SWITCH_MATRIX_STATE => {
+                // one big endian row of columns after the other
+                let state = keyboard.matrix_state();
+                for (row, bytes) in data[2..].chunks_exact_mut(2).take(ROWS).enumerate() {
+                    let columns = (0..COLUMNS).fold(0u16, |bits, column| {
+                        bits | (state.get_bit(key_index(row, column)) as u16) << column
+                    });
+                    bytes.copy_from_slice(&columns.to_be_bytes());
+                }
+            }
+            _ => data[0] = UNHANDLED,
+        },
+        // only layout options, there are none
+        SET_KEYBOARD_VALUE if data[1] == LAYOUT_OPTIONS => {}
+        KEYMAP_GET_KEYCODE => {
+            let keycode = key_position(data[1], data[2], data[3])
+                .map_or(0, |(layer, key)| to_keycode(keyboard.keymap()[layer][key]));
+            data[4..6].copy_from_slice(&keycode.to_be_bytes());
+        }
+        KEYMAP_SET_KEYCODE => {
+            let keycode = u16::from_be_bytes([data[4], data[5]]);
+            if let Some((layer, key)) = key_position(data[1], data[2], data[3]) {
+                keyboard.keymap_mut()[layer][key] = from_keycode(keycode).unwrap_or(Action::Nop);
+                return true;
+            }
+        }
+        KEYMAP_RESET => {
+            keyboard.set_keymap(LAYERS);
+            return true;
+        }
+        EEPROM_RESET => {
+            keyboard.set_keymap(LAYERS);
+            *keyboard.macros_mut() = DEFAULT_MACROS;
+            return true;
+        }
+        MACRO_GET_COUNT => data[1] = MACRO_COUNT as u8,
+        MACRO_GET_BUFFER_SIZE => {
+            data[1..3].copy_from_slice(&(MACRO_BUFFER_LEN as u16).to_be_bytes())
+        }
+        MACRO_GET_BUFFER => {
+            let macros = keyboard.macros();
+            let end = (offset + size).min(MACRO_BUFFER_LEN);
+            let bytes = macros.get(offset..end).unwrap_or(&[]);
+            data[4..4 + bytes.len()].copy_from_slice(bytes);
+        }
+        MACRO_SET_BUFFER => {
+            let macros = keyboard.macros_mut();
+            let end = (offset + size).min(MACRO_BUFFER_LEN);
+            if let Some(bytes) = macros.get_mut(offset..end) {
+                bytes.copy_from_slice(&data[4..4 + bytes.len()]);
+                return true;
+            }
+        }
+        MACRO_RESET => {
+            *keyboard.macros_mut() = [0; MACRO_BUFFER_LEN];
+            return true;
+        }
+        KEYMAP_GET_LAYER_COUNT => data[1] = LAYERS.len() as u8,
+        // keycodes as u16 big endian, layer by layer, row by row
+        KEYMAP_GET_BUFFER => {
+            let keymap = keyboard.keymap();
+            for (i, bytes) in data[4..4 + size].chunks_exact_mut(2).enumerate() {
+                let keycode = buffer_key(offset / 2 + i)
+                    .map_or(0, |(layer, key)| to_keycode(keymap[layer][key]));
+                bytes.copy_from_slice(&keycode.to_be_bytes());
+            }
+        }
+        KEYMAP_SET_BUFFER => {
+            let keymap = keyboard.keymap_mut();
+            let mut written = false;
+            for (i, bytes) in data[4..4 + size].chunks_exact(2).enumerate() {
+                if let Some((layer, key)) = buffer_key(offset / 2 + i) {
+                    let keycode = u16::from_be_bytes([bytes[0], bytes[1]]);
+                    keymap[layer][key] = from_keycode(keycode).unwrap_or(Action::Nop);
+                    written = true;
+                }
+            }
+            return written;
+        }
+        _ => data[0] = UNHANDLED,
+    }
+    false
+}
+
+fn key_position(layer: u8, row: u8, column: u8) -> Option<(usize, usize)> {
+    let (layer, row, column) = (layer as usize, row as usize, column as usize);
+    if layer < LAYERS.len() && row < ROWS && column < COLUMNS {
+        Some((layer, key_index(row, column)))
+    } else {
+        None
+    }
+}
+
+/// Layer and key of the `index`th keycode in the keymap buffer.
+fn buffer_key(index: usize) -> Option<(usize, usize)> {
+    let keys = ROWS * COLUMNS;
+    if index < LAYERS.len() * keys {
+        Some((index / keys, index % keys))
+    } else {
+        None
+    }
+}
+
+// QMK keycode ranges
+const TRANSPARENT: u16 = 0x0001;
+const MOD_TAP: u16 = 0x2000;
+const LAYER_TAP: u16 = 0x4000;
+const LAYER_MOD: u16 = 0x5000;
+const TO: u16 = 0x5200;
+const MOMENTARY: u16 = 0x5220;
+const DEF_LAYER: u16 = 0x5240;
+const TOGGLE_LAYER: u16 = 0x5260;
+const ONE_SHOT_LAYER: u16 = 0x5280;
+const ONE_SHOT_MOD: u16 = 0x52A0;
+const MACRO: u16 = 0x7700;
+const BOOTLOADER: u16 = 0x7C00;
+const DYNAMIC_MACRO_RECORD_START_1: u16 = 0x7C53;
+const DYNAMIC_MACRO_RECORD_START_2: u16 = 0x7C54;
+const DYNAMIC_MACRO_RECORD_STOP: u16 = 0x7C55;
+const DYNAMIC_MACRO_PLAY_1: u16 = 0x7C56;
+const DYNAMIC_MACRO_PLAY_2: u16 = 0x7C57;
+const LEADER: u16 = 0x7C58;
+const CAPS_WORD_TOGGLE: u16 = 0x7C73;
+const REPEAT_KEY: u16 = 0x7C79;
+const ALT_REPEAT_KEY: u16 = 0x7C7A;
+const LAYER_LOCK: u16 = 0x7C7B;
+
+// `MouseCode`s by QMK mouse keycode, buttons and then wheel
+const MOUSE: [(u16, MouseCode); 7] = [
+    (0xD1, MouseCode::BTN1),
+    (0xD2, MouseCode::BTN2),
+    (0xD3, MouseCode::BTN3),
+    (0xD9, MouseCode::BTN4),
+    (0xDA, MouseCode::BTN5),
+    (0xDB, MouseCode::BTN6),
+    (0xDC, MouseCode::BTN7),
+];
+
+/// QMK's 5 bit modifiers: ctrl, shift, alt, gui, and a bit for the right
+/// hand ones. Mixed hands fall back to the left.
+fn to_qmk_mods(mods: Modifiers) -> u16 {
+    let (left, right) = (mods.bits() & 0x0F, mods.bits() >> 4);
+    if left == 0 && right != 0 {
+        0x10 | right as u16
+    } else {
+        (left | right) as u16
+    }
+}
+
+fn from_qmk_mods(mods: u16) -> Modifiers {
+    let bits = (mods & 0x0F) as u8;
+    if mods & 0x10 != 0 {
+        Modifiers::from_bits_retain(bits << 4)
+    } else {
+        Modifiers::from_bits_retain(bits)
+    }
+}
+
+/// VIA keycode of `action`, `KC_NO` for those VIA has no keycode for.
+pub fn to_keycode(action: Action) -> u16 {
+    let layer = |layer: LayerNumber| layer as u16;
+    match action {
+        Action::Nop => 0,
+        Action::Transparent => TRANSPARENT,
+        Action::Key(code) => code as u16,
+        Action::ModifiedKey(mods, code) => to_qmk_mods(mods) << 8 | code as u16,
+        Action::ModTap(mods, code) => MOD_TAP | to_qmk_mods(mods) << 8 | code as u16,
+        Action::LayerTapKey(l, code) if layer(l) < 16 => LAYER_TAP | layer(l) << 8 | code as u16,
+        Action::LayerMod(l, mods) if layer(l) < 16 => LAYER_MOD | layer(l) << 5 | to_qmk_mods(mods),
+        Action::LayerTo(l) => TO | layer(l),
+        Action::LayerMomentary(l) => MOMENTARY | layer(l),
+        Action::DefaultLayer(l) => DEF_LAYER | layer(l),
+        Action::LayerToggle(l) => TOGGLE_LAYER | layer(l),
+        Action::OneShotLayer(l) => ONE_SHOT_LAYER | layer(l),
+        Action::OneShotMod(mods) => ONE_SHOT_MOD | to_qmk_mods(mods),
+        Action::LayerLock => LAYER_LOCK,
+        Action::Mouse(code) => MOUSE
+            .iter()
+            .find(|(_, m)| *m == code)
+            .map_or(0, |(k, _)| *k),
+        Action::Leader => LEADER,
+        Action::Macro(index) if index < 0x80 => MACRO | index as u16,
+        Action::DynamicMacroRecord => DYNAMIC_MACRO_RECORD_START_1,
+        Action::DynamicMacroStop => DYNAMIC_MACRO_RECORD_STOP,
+        Action::DynamicMacroPlay => DYNAMIC_MACRO_PLAY_1,
+        Action::CapsWord => CAPS_WORD_TOGGLE,
+        Action::Repeat => REPEAT_KEY,
+        Action::AltRepeat => ALT_REPEAT_KEY,
+        Action::Bootloader => BOOTLOADER,
+        Action::LayerTapKey(..) | Action::LayerMod(..) | Action::Macro(_) => 0,
+    }
+}
+
+/// Action of a VIA keycode, `None` for keycodes without one.
+pub fn from_keycode(keycode: u16) -> Option<Action> {
+    let key = || KeyCode::from_u8(keycode as u8);
+    let layer = |layer: u16| LayerNumber::from_u8(layer as u8);
+    let mods = |shift: u16| from_qmk_mods(keycode >> shift & 0x1F);
+    let action = match keycode {
+        0 => Action::Nop,
+        TRANSPARENT => Action::Transparent,
+        0x00D1..=0x00DC => Action::Mouse(MOUSE.iter().find(|(k, _)| *k == keycode)?.1),
+        0x0002..=0x00FF => Action::Key(key()?),
+        0x0100..=0x1FFF => Action::ModifiedKey(mods(8), key()?),
+        0x2000..=0x3FFF => Action::ModTap(mods(8), key()?),
+        0x4000..=0x4FFF => Action::LayerTapKey(layer(keycode >> 8 & 0x0F)?, key()?),
+        0x5000..=0x51FF => Action::LayerMod(layer(keycode >> 5 & 0x0F)?, mods(0)),
+        0x5200..=0x521F => Action::LayerTo(layer(keycode & 0x1F)?),
+        0x5220..=0x523F => Action::LayerMomentary(layer(keycode & 0x1F)?),
+        0x5240..=0x525F => Action::DefaultLayer(layer(keycode & 0x1F)?),
+        0x5260..=0x527F => Action::LayerToggle(layer(keycode & 0x1F)?),
+        0x5280..=0x529F => Action::OneShotLayer(layer(keycode & 0x1F)?),
+        0x52A0..=0x52BF => Action::OneShotMod(mods(0)),
+        0x7700..=0x777F if ((keycode & 0x7F) as usize) < MACRO_COUNT => {
+            Action::Macro((keycode & 0x7F) as usize)
+        }
+        DYNAMIC_MACRO_RECORD_START_1 | DYNAMIC_MACRO_RECORD_START_2 => Action::DynamicMacroRecord,
+        DYNAMIC_MACRO_RECORD_STOP => Action::DynamicMacroStop,
+        DYNAMIC_MACRO_PLAY_1 | DYNAMIC_MACRO_PLAY_2 => Action::DynamicMacroPlay,
+        LEADER => Action::Leader,
+        CAPS_WORD_TOGGLE => Action::CapsWord,
+        REPEAT_KEY => Action::Repeat,
+        ALT_REPEAT_KEY => Action::AltRepeat,
+        BOOTLOADER => Action::Bootloader,
+        LAYER_LOCK => Action::LayerLock,
+        _ => return None,
+    };
+    Some(action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycodes::KeyCode::*;
+
+    /// Every kind of action VIA has a keycode for, with modifiers of one
+    /// hand as QMK can't mix them.
+    const ACTIONS: [Action; 24] = [
+        Action::Nop,
+        Action::Transparent,
+        Action::Key(RMeta),
+        Action::ModifiedKey(Modifiers::RCTRL.union(Modifiers::RSHIFT), ExSel),
+        Action::ModTap(Modifiers::LALT, A),
+        Action::LayerTapKey(LayerNumber::LN15, Space),
+        Action::LayerMod(LayerNumber::LN15, Modifiers::LMETA),
+        Action::LayerTo(LayerNumber::LN31),
+        Action::LayerMomentary(LayerNumber::LN1),
+        Action::DefaultLayer(LayerNumber::LN0),
+        Action::LayerToggle(LayerNumber::LN2),
+        Action::OneShotLayer(LayerNumber::LN3),
+        Action::OneShotMod(Modifiers::RSHIFT),
+        Action::LayerLock,
+        Action::Mouse(MouseCode::BTN7),
+        Action::Leader,
+        Action::Macro(MACRO_COUNT - 1),
+        Action::DynamicMacroRecord,
+        Action::DynamicMacroStop,
+        Action::DynamicMacroPlay,
+        Action::CapsWord,
+        Action::Repeat,
+        Action::AltRepeat,
+        Action::Bootloader,
+    ];
+
+    #[test]
+    fn keycode_round_trip() {
+        for action in ACTIONS {
+            assert_eq!(from_keycode(to_keycode(action)), Some(action), "{action:?}");
+        }
+    }
+
+    #[test]
+    fn keycode_boundaries() {
+        let keycodes = [
+            (0x0000, Some(Action::Nop)),
+            (0x0001, Some(Action::Transparent)),
+            (0x0004, Some(Action::Key(A))),
+            (0x0100, Some(Action::ModifiedKey(Modifiers::LCTRL, No))),
+            (
+                0x1FE7,
+                Some(Action::ModifiedKey(
+                    Modifiers::from_bits_retain(0xF0),
+                    RMeta,
+                )),
+            ),
+            (0x2000, Some(Action::ModTap(Modifiers::empty(), No))),
+            (0x4000, Some(Action::LayerTapKey(LayerNumber::LN0, No))),
+            (0x4F2C, Some(Action::LayerTapKey(LayerNumber::LN15, Space))),
+            (
+                0x5000,
+                Some(Action::LayerMod(LayerNumber::LN0, Modifiers::empty())),
+            ),
+            (0x5220, Some(Action::LayerMomentary(LayerNumber::LN0))),
+            (0x523F, Some(Action::LayerMomentary(LayerNumber::LN31))),
+            (0x7700, Some(Action::Macro(0))),
+            // no such key, macro or keycode
+            (0x00FF, None),
+            (0x3FFF, None),
+            (0x4FFF, None),
+            (0x7700 | MACRO_COUNT as u16, None),
+            (0x52C0, None),
+            (0xFFFF, None),
+        ];
+        for (keycode, action) in keycodes {
+            assert_eq!(from_keycode(keycode), action, "{keycode:04x}");
+        }
+    }
+
+    #[test]
+    fn no_keycode() {
+        // too high for QMK's 4 bit layer fields, or its 7 bit macro index
+        for action in [
+            Action::LayerTapKey(LayerNumber::LN16, A),
+            Action::LayerMod(LayerNumber::LN31, Modifiers::LCTRL),
+            Action::Macro(0x80),
+        ] {
+            assert_eq!(to_keycode(action), 0, "{action:?}");
+        }
+    }
+
+    #[test]
+    fn set_buffer() {
+        let mut keyboard = Keyboard::new();
+        let keys = LAYERS.len() * ROWS * COLUMNS;
+        let mut data = [0; REPORT_LEN];
+        data[0] = KEYMAP_SET_BUFFER;
+        data[3] = 4;
+        data[4..8].copy_from_slice(&[0x00, 0x04, 0x00, 0x05]);
+        // the last key is written, the one after it is not there
+        data[1..3].copy_from_slice(&(2 * (keys - 1) as u16).to_be_bytes());
+        assert!(handle(&mut keyboard, &mut data.clone()));
+        assert_eq!(
+            keyboard.keymap()[LAYERS.len() - 1][ROWS * COLUMNS - 1],
+            Action::Key(A)
+        );
+        // past the end nothing is, so there is nothing to save
+        data[1..3].copy_from_slice(&(2 * keys as u16).to_be_bytes());
+        assert!(!handle(&mut keyboard, &mut data));
+    }
+}
@@ -0,0 +1,31 @@
+//! Reads the key names and boards from the firmware's data files next door
+//! into `firmware.rs`, so the keymap conversions follow `src/keys.json` and
+//! `src/board/` without copies to keep in step.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[path = "../build/source.rs"]
+mod source;
+
+fn main() {
+    let root = Path::new("..");
+    println!("cargo:rerun-if-changed=../build/source.rs");
+    for source in source::sources(root) {
+        println!("cargo:rerun-if-changed={}", source.display());
+    }
+
+    let mut tables = String::from("// generated by build.rs from the firmware data files\n");
+    tables += "pub const KEY_NAMES: &[&str] = &[\n";
+    for name in source::key_names(root) {
+        tables += &format!("    {name:?},\n");
+    }
+    tables += "];\n\npub const BOARDS: &[Board] = &[\n";
+    for (name, rows, columns) in source::boards(root) {
+        tables += &format!("    Board {{ name: {name:?}, rows: {rows}, columns: {columns} }},\n");
+    }
+    tables += "];\n";
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out.join("firmware.rs"), tables).unwrap();
+}
@@ -0,0 +1,24 @@
+//! What the tool knows of the firmware, read from its data files by build.rs:
+//! `KEY_NAMES`, the names `keymap.json` may use, and the `BOARDS`.
+
+/// A board of the firmware's `src/board/`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Board {
+    pub name: &'static str,
+    pub rows: usize,
+    pub columns: usize,
+}
+
+/// The board in `pcb/`, the firmware's default.
+pub const DEFAULT_BOARD: &str = "tpkb50";
+
+include!(concat!(env!("OUT_DIR"), "/firmware.rs"));
+
+pub fn board(name: &str) -> Option<Board> {
+    BOARDS.iter().find(|board| board.name == name).copied()
+}
+
+/// Whether `name` is a key `keymap.json` may use.
+pub fn is_key(name: &str) -> bool {
+    KEY_NAMES.contains(&name)
+}
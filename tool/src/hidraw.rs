@@ -0,0 +1,91 @@
+//! The keyboard's raw HID interface through Linux hidraw.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use tpkb50_protocol::{Report, REPORT_LEN, USAGE, USAGE_PAGE};
+
+use crate::Transport;
+
+const HID_ID: &str = "HID_ID=0003:00002023:00000610";
+/// How long `receive` waits for a report, ms.
+const TIMEOUT: i32 = 1000;
+
+pub struct HidRaw {
+    file: File,
+}
+
+impl HidRaw {
+    pub fn open(path: &Path) -> io::Result<HidRaw> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(HidRaw { file })
+    }
+
+    /// The hidraw node of the keyboard's raw HID interface.
+    pub fn find() -> io::Result<PathBuf> {
+        for entry in fs::read_dir("/sys/class/hidraw")? {
+            let entry = entry?;
+            let device = entry.path().join("device");
+            let uevent = fs::read_to_string(device.join("uevent")).unwrap_or_default();
+            if !uevent.lines().any(|line| line == HID_ID) {
+                continue;
+            }
+            let descriptor = fs::read(device.join("report_descriptor")).unwrap_or_default();
+            if is_raw_hid(&descriptor) {
+                return Ok(Path::new("/dev").join(entry.file_name()));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no tpkb50 found, is it plugged in?",
+        ))
+    }
+}
+
+/// Whether `descriptor` starts with the usage page and usage of the raw
+/// HID interface.
+fn is_raw_hid(descriptor: &[u8]) -> bool {
+    let [page_low, page_high] = USAGE_PAGE.to_le_bytes();
+    descriptor.starts_with(&[0x06, page_low, page_high, 0x09, USAGE as u8])
+}
+
+impl Transport for HidRaw {
+    fn send(&mut self, request: &Report) -> io::Result<()> {
+        // the interface has no report ids, hidraw still wants a zero first
+        let mut buf = [0; REPORT_LEN + 1];
+        buf[1..].copy_from_slice(request);
+        self.file.write_all(&buf)
+    }
+
+    fn receive(&mut self) -> io::Result<Report> {
+        let mut fd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `fd` is a single valid pollfd for the duration of the call.
+        match unsafe { libc::poll(&mut fd, 1, TIMEOUT) } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no response from the keyboard",
+                ))
+            }
+            _ => {}
+        }
+        let mut report = [0; REPORT_LEN];
+        let len = self.file.read(&mut report)?;
+        if len != REPORT_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("short report of {len} bytes"),
+            ));
+        }
+        Ok(report)
+    }
+}
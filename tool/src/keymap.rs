@@ -0,0 +1,311 @@
+//! Keymap files: the firmware's `keymap.json`, QMK's `keymap.json` and
+//! keyboard-layout-editor.com layouts. All of them list the keys of each
+//! layer row by row, they only name the keys differently.
+
+use std::fs;
+
+use serde_json::Value;
+
+use crate::{
+    firmware::{self, Board, DEFAULT_BOARD},
+    qmk, render,
+};
+
+/// Key names of a layer, row by row.
+pub type Layer = Vec<String>;
+
+/// Run `keymap [--board NAME] CONVERSION INPUT [OUTPUT]`, writing to
+/// stdout without an output file.
+pub fn command(args: &[String]) -> Result<(), String> {
+    let (board, args) = match args {
+        [flag, name, args @ ..] if flag == "--board" => (name.as_str(), args),
+        args => (DEFAULT_BOARD, args),
+    };
+    let board = firmware::board(board).ok_or_else(|| {
+        let boards: Vec<&str> = firmware::BOARDS.iter().map(|board| board.name).collect();
+        format!("no board {board}, there are {}", boards.join(", "))
+    })?;
+    let (conversion, input, output) = match args {
+        [conversion, input] => (conversion, input, None),
+        [conversion, input, output] => (conversion, input, Some(output)),
+        _ => return Err(crate::USAGE.to_string()),
+    };
+    let text = fs::read_to_string(input).map_err(|e| format!("{input}: {e}"))?;
+    let keyboard = ("keyboard", board.name);
+    let converted = match conversion.as_str() {
+        "from-qmk" => format_keymap(&[keyboard], &from_qmk(&text, board)?, board),
+        "to-qmk" => format_keymap(
+            &[keyboard, ("keymap", "default"), ("layout", "LAYOUT")],
+            &to_qmk(&read(&text, board)?, board)?,
+            board,
+        ),
+        "from-kle" => format_keymap(&[keyboard], &from_kle(&text, board)?, board),
+        "to-kle" => to_kle(&read(&text, board)?, board),
+        "ascii" => render::ascii(&read(&text, board)?, board),
+        "svg" => render::svg(&read(&text, board)?, board),
+        _ => return Err(crate::USAGE.to_string()),
+    };
+    match output {
+        Some(output) => fs::write(output, converted).map_err(|e| format!("{output}: {e}")),
+        None => {
+            print!("{converted}");
+            Ok(())
+        }
+    }
+}
+
+/// Layers of the firmware's `keymap.json`.
+fn read(text: &str, board: Board) -> Result<Vec<Layer>, String> {
+    let layers = layers(text, board)?;
+    map(&layers, board, |name| {
+        firmware::is_key(name).then(|| name.to_string())
+    })?;
+    Ok(layers)
+}
+
+/// The `layers` of a `keymap.json`, as they are.
+fn layers(text: &str, board: Board) -> Result<Vec<Layer>, String> {
+    let Board { rows, columns, .. } = board;
+    let keymap: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let layers = keymap["layers"].as_array().ok_or("no `layers` list")?;
+    layers
+        .iter()
+        .enumerate()
+        .map(|(layer, keys)| {
+            let keys = keys
+                .as_array()
+                .filter(|keys| keys.len() == rows * columns)
+                .ok_or(format!(
+                    "layer {layer}: expected a list of {rows}x{columns} keys"
+                ))?;
+            keys.iter()
+                .map(|key| match key {
+                    Value::String(key) => Ok(key.clone()),
+                    key => Err(format!("layer {layer}: {key} is not a key")),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Rename every key, or name all the keys `rename` has no name for.
+fn map(
+    layers: &[Layer],
+    board: Board,
+    rename: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<Layer>, String> {
+    let mut errors = Vec::new();
+    let layers = layers
+        .iter()
+        .enumerate()
+        .map(|(layer, keys)| {
+            keys.iter()
+                .enumerate()
+                .map(|(i, key)| {
+                    rename(key).unwrap_or_else(|| {
+                        let (row, column) = (i / board.columns, i % board.columns);
+                        errors.push(format!("layer {layer}, row {row}, column {column}: {key}"));
+                        String::new()
+                    })
+                })
+                .collect()
+        })
+        .collect();
+    if errors.is_empty() {
+        Ok(layers)
+    } else {
+        Err(format!("no key for\n{}", errors.join("\n")))
+    }
+}
+
+fn from_qmk(text: &str, board: Board) -> Result<Vec<Layer>, String> {
+    map(&layers(text, board)?, board, |keycode| {
+        qmk::from_qmk(keycode).map(String::from)
+    })
+}
+
+fn to_qmk(layers: &[Layer], board: Board) -> Result<Vec<Layer>, String> {
+    map(layers, board, |name| qmk::to_qmk(name).map(String::from))
+}
+
+/// A `keymap.json` with `fields` before the layers, a row per line.
+fn format_keymap(fields: &[(&str, &str)], layers: &[Layer], board: Board) -> String {
+    let Board { rows, columns, .. } = board;
+    let mut json = String::from("{\n");
+    for (field, value) in fields {
+        json += &format!("  {}: {},\n", quote(field), quote(value));
+    }
+    json += "  \"layers\": [\n";
+    for (layer, keys) in layers.iter().enumerate() {
+        json += "    [\n";
+        for (row, keys) in keys.chunks(columns).enumerate() {
+            let last_row = row == rows - 1;
+            let keys: Vec<String> = keys
+                .iter()
+                .enumerate()
+                .map(|(column, key)| {
+                    let comma = if last_row && column == columns - 1 {
+                        ""
+                    } else {
+                        ","
+                    };
+                    format!("{:11}", quote(key) + comma)
+                })
+                .collect();
+            json += &format!("      {}\n", keys.join(" ").trim_end());
+        }
+        json += if layer == layers.len() - 1 {
+            "    ]\n"
+        } else {
+            "    ],\n"
+        };
+    }
+    json + "  ]\n}\n"
+}
+
+fn quote(text: &str) -> String {
+    Value::from(text).to_string()
+}
+
+/// Keyboard-layout-editor.com raw data with the layers one below the
+/// other, named as in `keymap.json` so `from-kle` reads them back.
+fn to_kle(layers: &[Layer], board: Board) -> String {
+    let mut rows = vec![format!("{{\"name\": {}}}", quote(board.name))];
+    for (layer, keys) in layers.iter().enumerate() {
+        for (row, keys) in keys.chunks(board.columns).enumerate() {
+            let mut labels: Vec<String> = keys.iter().map(|key| quote(key)).collect();
+            if layer > 0 && row == 0 {
+                labels.insert(0, r#"{"y": 0.5}"#.to_string());
+            }
+            rows.push(format!("[{}]", labels.join(", ")));
+        }
+    }
+    format!("[\n  {}\n]\n", rows.join(",\n  "))
+}
+
+/// Layers of keyboard-layout-editor.com raw data, a layer every `rows`
+/// rows with the key name as the first line of the legend.
+fn from_kle(text: &str, board: Board) -> Result<Vec<Layer>, String> {
+    let Board { rows, columns, .. } = board;
+    let kle: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let kle_rows: Vec<Layer> = kle
+        .as_array()
+        .ok_or("not a KLE layout")?
+        .iter()
+        .filter_map(Value::as_array)
+        .map(|row| {
+            row.iter()
+                .filter_map(Value::as_str)
+                .map(|legend| legend.lines().next().unwrap_or("").to_string())
+                .collect()
+        })
+        .collect();
+    if kle_rows.is_empty() || !kle_rows.len().is_multiple_of(rows) {
+        return Err(format!(
+            "{} rows, not layers of {rows} rows",
+            kle_rows.len()
+        ));
+    }
+    if let Some(row) = kle_rows.iter().position(|keys| keys.len() != columns) {
+        return Err(format!("row {row}: expected {columns} keys"));
+    }
+    let layers: Vec<Layer> = kle_rows.chunks(rows).map(|rows| rows.concat()).collect();
+    map(&layers, board, |name| {
+        firmware::is_key(name).then(|| name.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYMAP: &str = include_str!("../../keymap.json");
+
+    fn tpkb50() -> Board {
+        firmware::board(DEFAULT_BOARD).unwrap()
+    }
+
+    const SMALL: Board = Board {
+        name: "small",
+        rows: 2,
+        columns: 3,
+    };
+
+    const SMALL_KEYMAP: &str = r#"{
+  "keyboard": "small",
+  "layers": [
+    [
+      "Escape",   "A",        "LTKS",
+      "TRNS",     "SKN1",     "No"
+    ],
+    [
+      "TRNS",     "F1",       "LEAD",
+      "OSLS",     "Up",       "BOOT"
+    ]
+  ]
+}
+"#;
+
+    fn qmk_round_trip(text: &str, board: Board) -> String {
+        let qmk = format_keymap(
+            &[("keyboard", board.name)],
+            &to_qmk(&read(text, board).unwrap(), board).unwrap(),
+            board,
+        );
+        let layers = from_qmk(&qmk, board).unwrap();
+        format_keymap(&[("keyboard", board.name)], &layers, board)
+    }
+
+    fn kle_round_trip(text: &str, board: Board) -> String {
+        let kle = to_kle(&read(text, board).unwrap(), board);
+        let layers = from_kle(&kle, board).unwrap();
+        format_keymap(&[("keyboard", board.name)], &layers, board)
+    }
+
+    #[test]
+    fn qmk_round_trip_keeps_the_keymap() {
+        assert_eq!(qmk_round_trip(KEYMAP, tpkb50()), KEYMAP);
+        assert_eq!(qmk_round_trip(SMALL_KEYMAP, SMALL), SMALL_KEYMAP);
+    }
+
+    #[test]
+    fn kle_round_trip_keeps_the_keymap() {
+        assert_eq!(kle_round_trip(KEYMAP, tpkb50()), KEYMAP);
+        assert_eq!(kle_round_trip(SMALL_KEYMAP, SMALL), SMALL_KEYMAP);
+    }
+
+    #[test]
+    fn from_qmk_names() {
+        let qmk = r#"{"layers": [["KC_ESC", "LT(2, KC_SPC)", "_______",
+                                  "S(KC_1)", "KC_NO", "QK_BOOT"]]}"#;
+        assert_eq!(
+            from_qmk(qmk, SMALL).unwrap(),
+            vec![["Escape", "LTKS", "TRNS", "SKN1", "No", "BOOT"].map(String::from)]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            read(r#"{"layers": [["A", "B"]]}"#, SMALL),
+            Err("layer 0: expected a list of 2x3 keys".to_string())
+        );
+        assert_eq!(
+            read(r#"{"layers": [["A", "B", "C", "D", "E", "Foo"]]}"#, SMALL),
+            Err("no key for\nlayer 0, row 1, column 2: Foo".to_string())
+        );
+        assert_eq!(
+            to_qmk(
+                &[["A", "B", "C", "D", "E", "RollOver"]
+                    .map(String::from)
+                    .to_vec()],
+                SMALL
+            ),
+            Err("no key for\nlayer 0, row 1, column 2: RollOver".to_string())
+        );
+        assert_eq!(
+            from_kle(r#"[["A", "B", "C"]]"#, SMALL),
+            Err("1 rows, not layers of 2 rows".to_string())
+        );
+    }
+}
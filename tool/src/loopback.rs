@@ -0,0 +1,284 @@
+//! A keyboard in memory answering through `protocol::serve`, the same code
+//! the firmware runs, to try the tool and the protocol without hardware.
+
+use std::{collections::VecDeque, io};
+
+use tpkb50_protocol::{self as protocol, Device, Info, KeyPosition, Report, Status};
+
+use crate::Transport;
+
+const LAYERS: u8 = 4;
+const ROWS: u8 = 4;
+const COLUMNS: u8 = 13;
+/// `tpkb50::keymap::encode(Action::Transparent)`.
+const TRANSPARENT: [u8; 4] = [1, 0, 0, 0];
+
+struct FakeKeyboard {
+    keymap: Vec<[u8; 4]>,
+    trackpoint_ram: [u8; 256],
+    /// Sensitivity factor, tapping term.
+    settings: [Vec<u8>; 2],
+}
+
+impl FakeKeyboard {
+    fn index(&self, position: KeyPosition) -> Result<usize, Status> {
+        if position.layer < LAYERS && position.row < ROWS && position.column < COLUMNS {
+            let (layer, row, column) = (
+                position.layer as usize,
+                position.row as usize,
+                position.column as usize,
+            );
+            Ok((layer * ROWS as usize + row) * COLUMNS as usize + column)
+        } else {
+            Err(Status::InvalidArgument)
+        }
+    }
+}
+
+impl Device for FakeKeyboard {
+    fn info(&self) -> Info {
+        Info {
+            layers: LAYERS,
+            rows: ROWS,
+            columns: COLUMNS,
+            firmware: [0, 1, 0],
+        }
+    }
+
+    fn get_key(&self, position: KeyPosition) -> Result<[u8; 4], Status> {
+        Ok(self.keymap[self.index(position)?])
+    }
+
+    fn set_key(&mut self, position: KeyPosition, record: [u8; 4]) -> Result<(), Status> {
+        let index = self.index(position)?;
+        self.keymap[index] = record;
+        Ok(())
+    }
+
+    fn read_trackpoint_ram(&mut self, location: u8) -> Result<u8, Status> {
+        Ok(self.trackpoint_ram[location as usize])
+    }
+
+    fn write_trackpoint_ram(&mut self, location: u8, value: u8) -> Result<(), Status> {
+        self.trackpoint_ram[location as usize] = value;
+        Ok(())
+    }
+
+    fn get_setting(&self, key: u8, buf: &mut [u8]) -> Result<usize, Status> {
+        let value = self
+            .settings
+            .get(key as usize)
+            .ok_or(Status::InvalidArgument)?;
+        buf[..value.len()].copy_from_slice(value);
+        Ok(value.len())
+    }
+
+    fn reboot(&mut self) -> Result<(), Status> {
+        Ok(())
+    }
+
+    fn bootloader(&mut self) -> Result<(), Status> {
+        Ok(())
+    }
+}
+
+pub struct Loopback {
+    keyboard: FakeKeyboard,
+    responses: VecDeque<Report>,
+}
+
+impl Loopback {
+    pub fn new() -> Loopback {
+        let mut trackpoint_ram = [0; 256];
+        // sensitivity factor
+        trackpoint_ram[0x4A] = 0xCC;
+        Loopback {
+            keyboard: FakeKeyboard {
+                keymap: vec![TRANSPARENT; (LAYERS * ROWS * COLUMNS) as usize],
+                trackpoint_ram,
+                settings: [vec![0xCC], 200u16.to_le_bytes().to_vec()],
+            },
+            responses: VecDeque::new(),
+        }
+    }
+}
+
+impl Transport for Loopback {
+    fn send(&mut self, request: &Report) -> io::Result<()> {
+        let response = protocol::serve(&mut self.keyboard, request);
+        self.responses.push_back(response);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> io::Result<Report> {
+        self.responses
+            .pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no response"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tpkb50_protocol::{Request, Response, Setting, REPORT_LEN, VERSION};
+
+    use super::*;
+    use crate::transfer;
+
+    fn position(layer: u8, row: u8, column: u8) -> KeyPosition {
+        KeyPosition { layer, row, column }
+    }
+
+    fn request(loopback: &mut Loopback, request: Request) -> Result<Response, Status> {
+        transfer(loopback, &request, 7).unwrap()
+    }
+
+    #[test]
+    fn every_request() {
+        let mut loopback = Loopback::new();
+        let info = Info {
+            layers: LAYERS,
+            rows: ROWS,
+            columns: COLUMNS,
+            firmware: [0, 1, 0],
+        };
+        assert_eq!(
+            request(&mut loopback, Request::GetInfo),
+            Ok(Response::Info(info))
+        );
+
+        let key = position(3, 3, 12);
+        assert_eq!(
+            request(&mut loopback, Request::GetKey(key)),
+            Ok(Response::Key(TRANSPARENT))
+        );
+        let record = [2, 0x04, 0, 0];
+        assert_eq!(
+            request(&mut loopback, Request::SetKey(key, record)),
+            Ok(Response::Done)
+        );
+        assert_eq!(
+            request(&mut loopback, Request::GetKey(key)),
+            Ok(Response::Key(record))
+        );
+        assert_eq!(
+            request(&mut loopback, Request::GetKey(position(0, 0, 0))),
+            Ok(Response::Key(TRANSPARENT))
+        );
+
+        assert_eq!(
+            request(&mut loopback, Request::ReadTrackPointRam(0x4A)),
+            Ok(Response::TrackPointRam(0xCC))
+        );
+        assert_eq!(
+            request(&mut loopback, Request::WriteTrackPointRam(0x4A, 0x80)),
+            Ok(Response::Done)
+        );
+        assert_eq!(
+            request(&mut loopback, Request::ReadTrackPointRam(0x4A)),
+            Ok(Response::TrackPointRam(0x80))
+        );
+
+        let setting = |value: &[u8]| Ok(Response::Setting(Setting::new(value).unwrap()));
+        assert_eq!(
+            request(&mut loopback, Request::GetSetting(0)),
+            setting(&[0xCC])
+        );
+        assert_eq!(
+            request(&mut loopback, Request::GetSetting(1)),
+            setting(&200u16.to_le_bytes())
+        );
+
+        assert_eq!(request(&mut loopback, Request::Reboot), Ok(Response::Done));
+        assert_eq!(
+            request(&mut loopback, Request::Bootloader),
+            Ok(Response::Done)
+        );
+    }
+
+    #[test]
+    fn bad_position() {
+        let mut loopback = Loopback::new();
+        for key in [
+            position(LAYERS, 0, 0),
+            position(0, ROWS, 0),
+            position(0, 0, COLUMNS),
+        ] {
+            assert_eq!(
+                request(&mut loopback, Request::GetKey(key)),
+                Err(Status::InvalidArgument)
+            );
+            assert_eq!(
+                request(&mut loopback, Request::SetKey(key, [0; 4])),
+                Err(Status::InvalidArgument)
+            );
+        }
+        assert_eq!(
+            request(&mut loopback, Request::GetSetting(2)),
+            Err(Status::InvalidArgument)
+        );
+    }
+
+    /// Hands out `responses` whatever is sent.
+    struct Script {
+        responses: VecDeque<Report>,
+    }
+
+    impl Transport for Script {
+        fn send(&mut self, _: &Report) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn receive(&mut self) -> io::Result<Report> {
+            self.responses
+                .pop_front()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no response"))
+        }
+    }
+
+    #[test]
+    fn version_mismatch() {
+        // a keyboard with another version answers in its own
+        let mut response = Response::encode(&Request::GetInfo.encode(7), Err(Status::Failed));
+        response[1] = VERSION + 1;
+        let mut script = Script {
+            responses: VecDeque::from([response]),
+        };
+        let error = transfer(&mut script, &Request::GetInfo, 7).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // and refuses requests in ours
+        let mut loopback = Loopback::new();
+        let mut report = Request::GetInfo.encode(7);
+        report[1] = VERSION + 1;
+        let response = protocol::serve(&mut loopback.keyboard, &report);
+        assert_eq!(
+            Response::decode(&response),
+            Ok((7, Err(Status::VersionMismatch)))
+        );
+    }
+
+    #[test]
+    fn stale_responses_skipped() {
+        let current = Request::ReadTrackPointRam(0x4A).encode(7);
+        let answer =
+            |request: &Report, value| Response::encode(request, Ok(Response::TrackPointRam(value)));
+        // VIA's get protocol version
+        let mut via = [0; REPORT_LEN];
+        via[0] = 0x01;
+        let mut script = Script {
+            responses: VecDeque::from([
+                // to an earlier request that timed out
+                answer(&Request::ReadTrackPointRam(0x4A).encode(6), 1),
+                via,
+                // same sequence, other command
+                Response::encode(&Request::GetInfo.encode(7), Err(Status::Failed)),
+                answer(&current, 2),
+            ]),
+        };
+        assert_eq!(
+            transfer(&mut script, &Request::ReadTrackPointRam(0x4A), 7).unwrap(),
+            Ok(Response::TrackPointRam(2))
+        );
+        assert!(script.responses.is_empty());
+    }
+}
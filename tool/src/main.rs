@@ -0,0 +1,201 @@
+//! Command line tool for the tpkb50 configuration protocol.
+
+use std::{env, fs, io, path::PathBuf, process};
+
+use tpkb50_protocol::{image, KeyPosition, Report, Request, Response, Status};
+
+mod firmware;
+mod hidraw;
+mod keymap;
+mod loopback;
+mod qmk;
+mod render;
+
+const USAGE: &str = "\
+usage: tpkb50-tool [--device /dev/hidrawN | --loopback] COMMAND [, COMMAND]...
+       tpkb50-tool pack FIRMWARE.bin IMAGE
+       tpkb50-tool keymap [--board NAME] CONVERSION INPUT [OUTPUT]
+
+commands:
+  info                          layers, matrix size and firmware version
+  get-key LAYER ROW COL         action record of a key
+  set-key LAYER ROW COL B0 B1 B2 B3
+                                set the action record of a key
+  tp-read LOCATION              read a TrackPoint RAM location
+  tp-write LOCATION VALUE       write a TrackPoint RAM location
+  setting KEY                   read a stored setting
+  reboot                        restart the keyboard
+  bootloader                    restart into the bootloader
+
+Numbers may be given as 0x.. hex. Commands separated by `,` run in order
+on the same connection, which makes --loopback keep its state between them.
+
+`pack` makes an image for the custom bootloader from a firmware binary
+built with the custom-bootloader feature, no keyboard needed.
+
+`keymap` converts keymap files, the firmware's keymap.json being INPUT or
+OUTPUT, and writes to stdout without OUTPUT:
+  from-qmk, to-qmk              QMK keymap.json
+  from-kle, to-kle              keyboard-layout-editor.com raw data
+  ascii, svg                    picture of each layer
+--board takes the matrix size of a board in the firmware's src/board/,
+tpkb50 by default.";
+
+/// Reports to and from the keyboard.
+pub trait Transport {
+    fn send(&mut self, request: &Report) -> io::Result<()>;
+    fn receive(&mut self) -> io::Result<Report>;
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    match args.split_first() {
+        Some((command, [firmware, output])) if command == "pack" => {
+            return pack(firmware, output).unwrap_or_else(|e| fail(&e));
+        }
+        Some((command, args)) if command == "keymap" => {
+            return keymap::command(args).unwrap_or_else(|e| fail(&e));
+        }
+        _ => {}
+    }
+    let mut device = None;
+    let mut loopback = false;
+    while let Some(flag) = args.first().filter(|arg| arg.starts_with("--")) {
+        match flag.as_str() {
+            "--device" if args.len() > 1 => {
+                device = Some(PathBuf::from(args.remove(1)));
+            }
+            "--loopback" => loopback = true,
+            _ => fail(USAGE),
+        }
+        args.remove(0);
+    }
+    let commands: Vec<Request> = args
+        .split(|arg| arg == ",")
+        .map(|command| parse(command).unwrap_or_else(|| fail(USAGE)))
+        .collect();
+    if commands.is_empty() {
+        fail(USAGE);
+    }
+
+    let mut transport: Box<dyn Transport> = if loopback {
+        Box::new(loopback::Loopback::new())
+    } else {
+        let path = match device {
+            Some(path) => path,
+            None => hidraw::HidRaw::find().unwrap_or_else(|e| fail(&e.to_string())),
+        };
+        let hidraw = hidraw::HidRaw::open(&path)
+            .unwrap_or_else(|e| fail(&format!("{}: {e}", path.display())));
+        Box::new(hidraw)
+    };
+
+    for (sequence, request) in commands.iter().enumerate() {
+        match transfer(transport.as_mut(), request, sequence as u8) {
+            Ok(Ok(response)) => print(&response),
+            Ok(Err(status)) => fail(&format!("keyboard refused: {status:?}")),
+            Err(e) => fail(&e.to_string()),
+        }
+    }
+}
+
+/// Send `request` and wait for its response, skipping VIA reports and
+/// stale responses.
+fn transfer(
+    transport: &mut dyn Transport,
+    request: &Request,
+    sequence: u8,
+) -> io::Result<Result<Response, Status>> {
+    let report = request.encode(sequence);
+    transport.send(&report)?;
+    loop {
+        let response = transport.receive()?;
+        match Response::decode(&response) {
+            Ok((seq, result)) if seq == sequence && response[2] == report[2] => return Ok(result),
+            Ok(_) => continue,
+            Err(Status::VersionMismatch) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("keyboard speaks protocol version {}", response[1]),
+                ))
+            }
+            Err(_) => continue,
+        }
+    }
+}
+
+fn pack(firmware: &str, output: &str) -> Result<(), String> {
+    let firmware = fs::read(firmware).map_err(|e| format!("{firmware}: {e}"))?;
+    let header = image::Header::new(&firmware).ok_or_else(|| {
+        format!(
+            "firmware is {} bytes, it must be 1 to {} bytes",
+            firmware.len(),
+            image::MAX_APP_LEN
+        )
+    })?;
+    let mut packed = header.encode().to_vec();
+    packed.extend_from_slice(&firmware);
+    fs::write(output, packed).map_err(|e| format!("{output}: {e}"))?;
+    println!("{} bytes, crc32 0x{:08x}", header.len, header.crc);
+    Ok(())
+}
+
+fn parse(command: &[String]) -> Option<Request> {
+    let (name, args) = command.split_first()?;
+    let args = args
+        .iter()
+        .map(|arg| number(arg))
+        .collect::<Option<Vec<u8>>>()?;
+    let position = |args: &[u8]| KeyPosition {
+        layer: args[0],
+        row: args[1],
+        column: args[2],
+    };
+    let request = match (name.as_str(), args.as_slice()) {
+        ("info", []) => Request::GetInfo,
+        ("get-key", [_, _, _]) => Request::GetKey(position(&args)),
+        ("set-key", [_, _, _, b0, b1, b2, b3]) => {
+            Request::SetKey(position(&args), [*b0, *b1, *b2, *b3])
+        }
+        ("tp-read", [location]) => Request::ReadTrackPointRam(*location),
+        ("tp-write", [location, value]) => Request::WriteTrackPointRam(*location, *value),
+        ("setting", [key]) => Request::GetSetting(*key),
+        ("reboot", []) => Request::Reboot,
+        ("bootloader", []) => Request::Bootloader,
+        _ => return None,
+    };
+    Some(request)
+}
+
+fn number(arg: &str) -> Option<u8> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+fn print(response: &Response) {
+    match response {
+        Response::Info(info) => {
+            let [major, minor, patch] = info.firmware;
+            println!(
+                "layers {}, matrix {}x{}, firmware {major}.{minor}.{patch}",
+                info.layers, info.rows, info.columns
+            );
+        }
+        Response::Key(record) => println!("{}", hex(record)),
+        Response::TrackPointRam(value) => println!("0x{value:02x}"),
+        Response::Setting(setting) => println!("{}", hex(setting.value())),
+        Response::Done => println!("ok"),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("0x{b:02x}")).collect();
+    bytes.join(" ")
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    process::exit(1)
+}
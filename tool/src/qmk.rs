@@ -0,0 +1,323 @@
+//! QMK keycodes of the keys in `keymap.json`.
+
+/// Keys of `keymap.json`, `KeyCode` variants then `Action` consts of
+/// `src/keys.json`, with the QMK keycode doing the same. Keys QMK has no
+/// keycode for are left out.
+const KEYS: &[(&str, &str)] = &[
+    ("No", "KC_NO"),
+    ("A", "KC_A"),
+    ("B", "KC_B"),
+    ("C", "KC_C"),
+    ("D", "KC_D"),
+    ("E", "KC_E"),
+    ("F", "KC_F"),
+    ("G", "KC_G"),
+    ("H", "KC_H"),
+    ("I", "KC_I"),
+    ("J", "KC_J"),
+    ("K", "KC_K"),
+    ("L", "KC_L"),
+    ("M", "KC_M"),
+    ("N", "KC_N"),
+    ("O", "KC_O"),
+    ("P", "KC_P"),
+    ("Q", "KC_Q"),
+    ("R", "KC_R"),
+    ("S", "KC_S"),
+    ("T", "KC_T"),
+    ("U", "KC_U"),
+    ("V", "KC_V"),
+    ("W", "KC_W"),
+    ("X", "KC_X"),
+    ("Y", "KC_Y"),
+    ("Z", "KC_Z"),
+    ("N1", "KC_1"),
+    ("N2", "KC_2"),
+    ("N3", "KC_3"),
+    ("N4", "KC_4"),
+    ("N5", "KC_5"),
+    ("N6", "KC_6"),
+    ("N7", "KC_7"),
+    ("N8", "KC_8"),
+    ("N9", "KC_9"),
+    ("N0", "KC_0"),
+    ("Enter", "KC_ENT"),
+    ("Escape", "KC_ESC"),
+    ("BSpace", "KC_BSPC"),
+    ("Tab", "KC_TAB"),
+    ("Space", "KC_SPC"),
+    ("Minus", "KC_MINS"),
+    ("Equal", "KC_EQL"),
+    ("LBracket", "KC_LBRC"),
+    ("RBracket", "KC_RBRC"),
+    ("BSlash", "KC_BSLS"),
+    ("NonUSHash", "KC_NUHS"),
+    ("SColon", "KC_SCLN"),
+    ("Quote", "KC_QUOT"),
+    ("Grave", "KC_GRV"),
+    ("Comma", "KC_COMM"),
+    ("Dot", "KC_DOT"),
+    ("Slash", "KC_SLSH"),
+    ("Capslock", "KC_CAPS"),
+    ("F1", "KC_F1"),
+    ("F2", "KC_F2"),
+    ("F3", "KC_F3"),
+    ("F4", "KC_F4"),
+    ("F5", "KC_F5"),
+    ("F6", "KC_F6"),
+    ("F7", "KC_F7"),
+    ("F8", "KC_F8"),
+    ("F9", "KC_F9"),
+    ("F10", "KC_F10"),
+    ("F11", "KC_F11"),
+    ("F12", "KC_F12"),
+    ("PScreen", "KC_PSCR"),
+    ("Scrolllock", "KC_SCRL"),
+    ("Pause", "KC_PAUS"),
+    ("Insert", "KC_INS"),
+    ("Home", "KC_HOME"),
+    ("PgUp", "KC_PGUP"),
+    ("Delete", "KC_DEL"),
+    ("End", "KC_END"),
+    ("PgDown", "KC_PGDN"),
+    ("Right", "KC_RGHT"),
+    ("Left", "KC_LEFT"),
+    ("Down", "KC_DOWN"),
+    ("Up", "KC_UP"),
+    ("Numlock", "KC_NUM"),
+    ("KpSlash", "KC_PSLS"),
+    ("KpAsterisk", "KC_PAST"),
+    ("KpMinus", "KC_PMNS"),
+    ("KpPlus", "KC_PPLS"),
+    ("KpEnter", "KC_PENT"),
+    ("Kp1", "KC_P1"),
+    ("Kp2", "KC_P2"),
+    ("Kp3", "KC_P3"),
+    ("Kp4", "KC_P4"),
+    ("Kp5", "KC_P5"),
+    ("Kp6", "KC_P6"),
+    ("Kp7", "KC_P7"),
+    ("Kp8", "KC_P8"),
+    ("Kp9", "KC_P9"),
+    ("Kp0", "KC_P0"),
+    ("KpDot", "KC_PDOT"),
+    ("NonUSBackslash", "KC_NUBS"),
+    ("Application", "KC_APP"),
+    ("Power", "KC_KB_POWER"),
+    ("KpEqual", "KC_PEQL"),
+    ("F13", "KC_F13"),
+    ("F14", "KC_F14"),
+    ("F15", "KC_F15"),
+    ("F16", "KC_F16"),
+    ("F17", "KC_F17"),
+    ("F18", "KC_F18"),
+    ("F19", "KC_F19"),
+    ("F20", "KC_F20"),
+    ("F21", "KC_F21"),
+    ("F22", "KC_F22"),
+    ("F23", "KC_F23"),
+    ("F24", "KC_F24"),
+    ("Execute", "KC_EXEC"),
+    ("Help", "KC_HELP"),
+    ("Menu", "KC_MENU"),
+    ("Select", "KC_SLCT"),
+    ("Stop", "KC_STOP"),
+    ("Again", "KC_AGIN"),
+    ("Undo", "KC_UNDO"),
+    ("Cut", "KC_CUT"),
+    ("Copy", "KC_COPY"),
+    ("Paste", "KC_PSTE"),
+    ("Find", "KC_FIND"),
+    // the keyboard page ones, QMK's KC_MUTE etc. are consumer keys
+    ("Mute", "KC_KB_MUTE"),
+    ("VolUp", "KC_KB_VOLUME_UP"),
+    ("VolDown", "KC_KB_VOLUME_DOWN"),
+    ("LockingCapsLock", "KC_LCAP"),
+    ("LockingNumLock", "KC_LNUM"),
+    ("LockingScrollLock", "KC_LSCR"),
+    ("KpComma", "KC_PCMM"),
+    ("KpEqualSign", "KC_KP_EQUAL_AS400"),
+    ("Intl1", "KC_INT1"),
+    ("Intl2", "KC_INT2"),
+    ("Intl3", "KC_INT3"),
+    ("Intl4", "KC_INT4"),
+    ("Intl5", "KC_INT5"),
+    ("Intl6", "KC_INT6"),
+    ("Intl7", "KC_INT7"),
+    ("Intl8", "KC_INT8"),
+    ("Intl9", "KC_INT9"),
+    ("Lang1", "KC_LNG1"),
+    ("Lang2", "KC_LNG2"),
+    ("Lang3", "KC_LNG3"),
+    ("Lang4", "KC_LNG4"),
+    ("Lang5", "KC_LNG5"),
+    ("Lang6", "KC_LNG6"),
+    ("Lang7", "KC_LNG7"),
+    ("Lang8", "KC_LNG8"),
+    ("Lang9", "KC_LNG9"),
+    ("AltErase", "KC_ERAS"),
+    ("SysReq", "KC_SYRQ"),
+    ("Cancel", "KC_CNCL"),
+    ("Clear", "KC_CLR"),
+    ("Prior", "KC_PRIR"),
+    ("Return", "KC_RETN"),
+    ("Separator", "KC_SEPR"),
+    ("Out", "KC_OUT"),
+    ("Oper", "KC_OPER"),
+    ("ClearAgain", "KC_CLAG"),
+    ("CrSel", "KC_CRSL"),
+    ("ExSel", "KC_EXSL"),
+    ("LCtrl", "KC_LCTL"),
+    ("LShift", "KC_LSFT"),
+    ("LAlt", "KC_LALT"),
+    ("LMeta", "KC_LGUI"),
+    ("RCtrl", "KC_RCTL"),
+    ("RShift", "KC_RSFT"),
+    ("RAlt", "KC_RALT"),
+    ("RMeta", "KC_RGUI"),
+    // layout.rs
+    ("TRNS", "KC_TRNS"),
+    ("LTKT", "LT(1,KC_TAB)"),
+    ("LTKS", "LT(2,KC_SPC)"),
+    ("LEAD", "QK_LEAD"),
+    ("CAPW", "CW_TOGG"),
+    ("REPT", "QK_REP"),
+    ("AREP", "QK_AREP"),
+    ("BOOT", "QK_BOOT"),
+    ("DMRC", "DM_REC1"),
+    ("DMST", "DM_RSTP"),
+    ("DMPL", "DM_PLY1"),
+    ("OSLS", "OSM(MOD_LSFT)"),
+    ("OSRS", "OSM(MOD_RSFT)"),
+    ("OSLM", "OSM(MOD_LGUI)"),
+    ("OSRM", "OSM(MOD_RGUI)"),
+    ("OSLA", "OSM(MOD_LALT)"),
+    ("OSRA", "OSM(MOD_RALT)"),
+    ("MSB1", "KC_BTN1"),
+    ("MSB2", "KC_BTN2"),
+    ("MSB3", "KC_BTN3"),
+    ("WHUP", "KC_WH_U"),
+    ("WHDN", "KC_WH_D"),
+    ("WHLT", "KC_WH_L"),
+    ("WHRT", "KC_WH_R"),
+    ("SKN0", "LSFT(KC_0)"),
+    ("SKN1", "LSFT(KC_1)"),
+    ("SKN2", "LSFT(KC_2)"),
+    ("SKN3", "LSFT(KC_3)"),
+    ("SKN4", "LSFT(KC_4)"),
+    ("SKN5", "LSFT(KC_5)"),
+    ("SKN6", "LSFT(KC_6)"),
+    ("SKN7", "LSFT(KC_7)"),
+    ("SKN8", "LSFT(KC_8)"),
+    ("SKN9", "LSFT(KC_9)"),
+];
+
+/// Other QMK spellings of the keycodes above, or ones close enough.
+const QMK_ALIASES: &[(&str, &str)] = &[
+    ("XXXXXXX", "No"),
+    ("_______", "TRNS"),
+    ("KC_TRANSPARENT", "TRNS"),
+    ("KC_ENTER", "Enter"),
+    ("KC_ESCAPE", "Escape"),
+    ("KC_BACKSPACE", "BSpace"),
+    ("KC_SPACE", "Space"),
+    ("KC_DELETE", "Delete"),
+    ("KC_PRINT_SCREEN", "PScreen"),
+    ("KC_PAUSE", "Pause"),
+    ("KC_INSERT", "Insert"),
+    ("KC_LCTRL", "LCtrl"),
+    ("KC_RCTRL", "RCtrl"),
+    ("KC_LSHIFT", "LShift"),
+    ("KC_RSHIFT", "RShift"),
+    ("KC_LCMD", "LMeta"),
+    ("KC_RCMD", "RMeta"),
+    ("KC_LWIN", "LMeta"),
+    ("KC_RWIN", "RMeta"),
+    ("KC_LOPT", "LAlt"),
+    ("KC_ROPT", "RAlt"),
+    ("KC_ALGR", "RAlt"),
+    // consumer keys in QMK, the nearest keyboard keys here
+    ("KC_MUTE", "Mute"),
+    ("KC_VOLU", "VolUp"),
+    ("KC_VOLD", "VolDown"),
+    ("MS_BTN1", "MSB1"),
+    ("MS_BTN2", "MSB2"),
+    ("MS_BTN3", "MSB3"),
+    ("MS_WHLU", "WHUP"),
+    ("MS_WHLD", "WHDN"),
+    ("MS_WHLL", "WHLT"),
+    ("MS_WHLR", "WHRT"),
+    ("QK_BOOTLOADER", "BOOT"),
+    ("QK_LEADER", "LEAD"),
+    ("QK_REPEAT_KEY", "REPT"),
+    ("QK_ALT_REPEAT_KEY", "AREP"),
+    ("CW_TOGGLE", "CAPW"),
+    ("S(KC_0)", "SKN0"),
+    ("S(KC_1)", "SKN1"),
+    ("S(KC_2)", "SKN2"),
+    ("S(KC_3)", "SKN3"),
+    ("S(KC_4)", "SKN4"),
+    ("S(KC_5)", "SKN5"),
+    ("S(KC_6)", "SKN6"),
+    ("S(KC_7)", "SKN7"),
+    ("S(KC_8)", "SKN8"),
+    ("S(KC_9)", "SKN9"),
+    ("KC_RPRN", "SKN0"),
+    ("KC_EXLM", "SKN1"),
+    ("KC_AT", "SKN2"),
+    ("KC_HASH", "SKN3"),
+    ("KC_DLR", "SKN4"),
+    ("KC_PERC", "SKN5"),
+    ("KC_CIRC", "SKN6"),
+    ("KC_AMPR", "SKN7"),
+    ("KC_ASTR", "SKN8"),
+    ("KC_LPRN", "SKN9"),
+];
+
+/// QMK keycode of the key `name`.
+pub fn to_qmk(name: &str) -> Option<&'static str> {
+    KEYS.iter()
+        .find(|(key, _)| *key == name)
+        .map(|(_, qmk)| *qmk)
+}
+
+/// Key name of a QMK keycode, spaces in it don't matter.
+pub fn from_qmk(keycode: &str) -> Option<&'static str> {
+    let keycode: String = keycode.chars().filter(|c| !c.is_whitespace()).collect();
+    KEYS.iter()
+        .find(|(_, qmk)| *qmk == keycode)
+        .map(|(key, _)| *key)
+        .or_else(|| {
+            QMK_ALIASES
+                .iter()
+                .find(|(qmk, _)| *qmk == keycode)
+                .map(|(_, key)| *key)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firmware::is_key;
+
+    #[test]
+    fn keys_are_firmware_keys() {
+        for (key, _) in KEYS {
+            assert!(is_key(key), "{key}");
+        }
+        for (_, key) in QMK_ALIASES {
+            assert!(is_key(key), "{key}");
+        }
+    }
+
+    #[test]
+    fn keycodes_round_trip() {
+        for (key, qmk) in KEYS {
+            assert_eq!(from_qmk(qmk), Some(*key));
+            assert_eq!(to_qmk(key), Some(*qmk));
+        }
+        assert_eq!(from_qmk("LT(1, KC_TAB)"), Some("LTKT"));
+        assert_eq!(from_qmk("_______"), Some("TRNS"));
+        assert_eq!(to_qmk("RollOver"), None);
+    }
+}
@@ -0,0 +1,77 @@
+//! Pictures of the layers, to review a keymap without reading JSON.
+
+use crate::{firmware::Board, keymap::Layer};
+
+/// Layers as text tables.
+pub fn ascii(layers: &[Layer], board: Board) -> String {
+    let columns = board.columns;
+    let width = layers.iter().flatten().map(String::len).max().unwrap_or(0);
+    let line = format!(
+        "+{}\n",
+        format!("{}+", "-".repeat(width + 2)).repeat(columns)
+    );
+    let mut text = String::new();
+    for (layer, keys) in layers.iter().enumerate() {
+        text += &format!("layer {layer}\n{line}");
+        for keys in keys.chunks(columns) {
+            text += "|";
+            for key in keys {
+                text += &format!(" {key:width$} |");
+            }
+            text += "\n";
+            text += &line;
+        }
+        text += "\n";
+    }
+    text
+}
+
+const KEY: usize = 64;
+const GAP: usize = 4;
+const TITLE: usize = 24;
+
+/// Layers as an SVG image, one grid of keys below the other.
+pub fn svg(layers: &[Layer], board: Board) -> String {
+    let columns = board.columns;
+    let layer_height = |keys: &Layer| TITLE + keys.len() / columns * (KEY + GAP) + GAP;
+    let width = columns * (KEY + GAP) + GAP;
+    let height: usize = layers.iter().map(layer_height).sum();
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+         font-family=\"sans-serif\" font-size=\"11\" text-anchor=\"middle\">\n"
+    );
+    let mut top = 0;
+    for (layer, keys) in layers.iter().enumerate() {
+        svg += &format!(
+            "  <text x=\"{GAP}\" y=\"{}\" font-size=\"14\" text-anchor=\"start\">layer {layer}</text>\n",
+            top + TITLE - 8
+        );
+        for (i, key) in keys.iter().enumerate() {
+            let x = GAP + i % columns * (KEY + GAP);
+            let y = top + TITLE + i / columns * (KEY + GAP);
+            let (fill, color) = match key.as_str() {
+                "TRNS" => ("#f4f4f4", "#999999"),
+                "No" => ("#f4f4f4", "#cccccc"),
+                _ => ("#ffffff", "#000000"),
+            };
+            svg += &format!(
+                "  <rect x=\"{x}\" y=\"{y}\" width=\"{KEY}\" height=\"{KEY}\" rx=\"6\" \
+                 fill=\"{fill}\" stroke=\"#666666\"/>\n"
+            );
+            svg += &format!(
+                "  <text x=\"{}\" y=\"{}\" fill=\"{color}\">{}</text>\n",
+                x + KEY / 2,
+                y + KEY / 2 + 4,
+                escape(key)
+            );
+        }
+        top += layer_height(keys);
+    }
+    svg + "</svg>\n"
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}